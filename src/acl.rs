@@ -0,0 +1,154 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! Client access control lists
+
+use super::*;
+
+use details::synthesize_reply;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Client identifier which knows the client's IP address.
+/// [`Network::client_ip`] of networks with such `ClientId` can just forward to it.
+pub trait ClientIp {
+    /// IP address of the client, if it has one. IPv4-mapped IPv6 addresses, as seen by
+    /// sockets listening on `[::]`, are given as IPv4 so that IPv4 rules apply to them.
+    fn client_ip(&self) -> Option<IpAddr>;
+}
+
+impl ClientIp for IpAddr {
+    fn client_ip(&self) -> Option<IpAddr> {
+        Some(self.to_canonical())
+    }
+}
+
+impl ClientIp for SocketAddr {
+    fn client_ip(&self) -> Option<IpAddr> {
+        Some(self.ip().to_canonical())
+    }
+}
+
+/// Ordered allow and deny rules by client network. The first matching rule decides.
+/// Empty list allows everybody, otherwise clients not matched by any rule are denied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    /// Allow (true) or deny (false), network, prefix length
+    pub rules: Vec<(bool, IpAddr, u8)>,
+}
+
+impl Acl {
+    /// Whether the client address is allowed
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.rules.is_empty() {
+            return true;
+        }
+        match self.rules.iter().find(|&&(_, net, prefix)| in_network(ip, net, prefix)) {
+            Some(&(allow, _, _)) => allow,
+            None => false,
+        }
+    }
+}
+
+impl FromStr for Acl {
+    type Err = String;
+    /// Comma-separated rules like `allow 10.0.0.0/8, deny 10.9.0.0/16, allow all`.
+    /// `all` means both `0.0.0.0/0` and `::/0`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for item in s.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let mut words = item.split_whitespace();
+            let allow = match words.next() {
+                Some("allow") => true,
+                Some("deny") => false,
+                _ => return Err(format!("expected allow or deny in {}", item)),
+            };
+            match (words.next(), words.next()) {
+                (Some("all"), None) | (Some("any"), None) => {
+                    rules.push((allow, IpAddr::from([0u8; 4]), 0));
+                    rules.push((allow, IpAddr::from([0u8; 16]), 0));
+                }
+                (Some(net), None) => {
+                    let (net, prefix) = parse_network(net).ok_or_else(|| format!("bad network in {}", item))?;
+                    rules.push((allow, net, prefix));
+                }
+                _ => return Err(format!("expected one network in {}", item)),
+            }
+        }
+        Ok(Acl { rules })
+    }
+}
+
+/// What denied clients get
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenyAction {
+    /// REFUSED reply
+    Refuse,
+    /// No reply at all
+    Drop,
+}
+
+impl FromStr for DenyAction {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "refuse" | "refused" => Ok(DenyAction::Refuse),
+            "drop" => Ok(DenyAction::Drop),
+            _ => Err(format!("unknown deny action {}, expected refuse or drop", s)),
+        }
+    }
+}
+
+/// Separate client ACLs for the ways a query can be handled.
+/// Clients whose [`Network::client_ip`] is unknown are always allowed.
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    /// Who may get answers made without asking upstream: from the cache, local records,
+    /// blocklists, response policy zones and the reverse index
+    pub cached: Acl,
+    /// Who may cause queries to upstream for A and AAAA questions, both for missing
+    /// and for stale entries. Stale answers are still given to clients allowed by `cached`.
+    pub upstream: Acl,
+    /// Who may get other queries (MX, TXT, ...) forwarded to upstream as is
+    pub direct: Acl,
+    /// How to treat denied queries
+    pub deny_action: DenyAction,
+}
+
+impl Default for AccessControl {
+    fn default() -> Self {
+        AccessControl {
+            cached: Default::default(),
+            upstream: Default::default(),
+            direct: Default::default(),
+            deny_action: DenyAction::Refuse,
+        }
+    }
+}
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Whether the client is allowed by the ACL selected from options
+    pub(crate) fn acl_allows(&self, src: N::ClientId, acl: fn(&AccessControl) -> &Acl) -> bool {
+        match self.net.client_ip(src) {
+            Some(ip) => acl(&self.opts.acl).allows(ip),
+            None => true,
+        }
+    }
+
+    /// Refuse or drop the query of a client denied by an ACL
+    pub(crate) fn reply_denied(&mut self, src: N::ClientId, received: SystemTime, query: &[u8]) -> BoxResult<()> {
+        Metrics::inc(&self.metrics.denied);
+        match self.opts.acl.deny_action {
+            DenyAction::Refuse => {
+                info!("  denied, refused");
+                let reply = synthesize_reply(query, 5, &[])?;
+                self.net.send_to_client(&reply, src)?;
+                tap::tap_reply(&mut self.taps, src, received, &reply, Outcome::Denied);
+            }
+            DenyAction::Drop => {
+                info!("  denied, dropped");
+                self.tap_unanswered(src, received, query, Outcome::Denied);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! `list`, `show`, `delete`, `purge` and `stats` subcommands
+
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use dnscache::{Database, CacheEntry, CacheEntry2, BoxResult, BatchOp, Options, Time};
+use dnscache::{is_subdomain, parse_ttl};
+
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    parse_ttl(s).ok_or_else(|| format!("bad duration {}, expected like 3600, 12h or 30d", s))
+}
+
+fn now() -> BoxResult<Time> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Like `1d 2h` or `35s`: two most significant units
+fn format_age(secs: u64) -> String {
+    const UNITS: [(u64, &str); 5] = [(7 * 86400, "w"), (86400, "d"), (3600, "h"), (60, "m"), (1, "s")];
+    let mut parts = Vec::with_capacity(2);
+    let mut rest = secs;
+    for &(size, name) in &UNITS {
+        if rest >= size || (size == 1 && parts.is_empty()) {
+            parts.push(format!("{}{}", rest / size, name));
+            rest %= size;
+        }
+        if parts.len() == 2 || (!parts.is_empty() && rest == 0) {
+            break;
+        }
+    }
+    parts.join(" ")
+}
+
+fn summary(ce2: &Option<CacheEntry2>, now: Time, opts: &Options) -> String {
+    let ce2 = match *ce2 {
+        None => return "-".to_string(),
+        Some(ref x) => x,
+    };
+    let (adj, expired) = ce2.adjusted(now, opts);
+    let age = format_age(now.saturating_sub(ce2.t));
+    let stale = if expired { ", stale" } else { "" };
+    if ce2.is_negative() {
+        return format!("negative, age {}{}", age, stale);
+    }
+    let ips: Vec<String> = ce2.a.iter().zip(adj.iter()).map(|(x, a)| {
+        match x.ip_addr() {
+            Some(ip) => format!("{} ttl {}", ip, a.ttl),
+            None => "(malformed)".to_string(),
+        }
+    }).collect();
+    format!("{}, age {}{}", ips.join(", "), age, stale)
+}
+
+pub fn list(db: &mut dyn Database, opts: &Options, suffix: Option<&str>, out: &mut dyn Write) -> BoxResult<()> {
+    let now = now()?;
+    let mut result = Ok(());
+    db.scan("", &mut |dom, ce| {
+        if let Some(s) = suffix {
+            if !is_subdomain(dom, s) {
+                return true;
+            }
+        }
+        result = writeln!(
+            out,
+            "{}\tA: {}\tAAAA: {}",
+            dom,
+            summary(&ce.a4, now, opts),
+            summary(&ce.a6, now, opts),
+        ).map_err(From::from);
+        result.is_ok()
+    })?;
+    result
+}
+
+fn show_family(
+    name: &str,
+    ce2: &Option<CacheEntry2>,
+    now: Time,
+    opts: &Options,
+    out: &mut dyn Write,
+) -> BoxResult<()> {
+    let ce2 = match *ce2 {
+        None => {
+            writeln!(out, "  {}: not queried", name)?;
+            return Ok(());
+        }
+        Some(ref x) => x,
+    };
+    let (adj, expired) = ce2.adjusted(now, opts);
+    writeln!(
+        out,
+        "  {}: answered {} ago (at {}){}",
+        name,
+        format_age(now.saturating_sub(ce2.t)),
+        ce2.t,
+        if expired { ", stale" } else { "" },
+    )?;
+    if ce2.is_negative() {
+        let left = opts.neg_ttl.saturating_sub(now.saturating_sub(ce2.t));
+        writeln!(out, "    no addresses, negative TTL left {}", left)?;
+    }
+    for (x, a) in ce2.a.iter().zip(adj.iter()) {
+        match x.ip_addr() {
+            Some(ip) => writeln!(out, "    {:<40} ttl {}, effective {}", ip, x.ttl, a.ttl)?,
+            None => writeln!(out, "    malformed address of {} bytes", x.ip.len())?,
+        }
+    }
+    Ok(())
+}
+
+pub fn show(db: &mut dyn Database, opts: &Options, dom: &str, out: &mut dyn Write) -> BoxResult<()> {
+    let ce = match db.get(dom)? {
+        None => Err(format!("{} is not in the database", dom))?,
+        Some(x) => x,
+    };
+    let now = now()?;
+    writeln!(out, "{}", dom)?;
+    show_family("A", &ce.a4, now, opts, out)?;
+    show_family("AAAA", &ce.a6, now, opts, out)?;
+    if let Some(u) = ce.u {
+        writeln!(out, "  last used {} ago", format_age(now.saturating_sub(u)))?;
+    }
+    writeln!(out, "  approximate size {} bytes", ce.approx_size())?;
+    Ok(())
+}
+
+fn apply(db: &mut dyn Database, batch: Vec<BatchOp>) -> BoxResult<()> {
+    if !batch.is_empty() {
+        db.write_batch(batch)?;
+        db.flush()?;
+    }
+    Ok(())
+}
+
+/// Returns number of deleted entries
+pub fn delete(db: &mut dyn Database, names: &[String], suffixes: &[String]) -> BoxResult<usize> {
+    let mut batch = Vec::new();
+    for name in names {
+        if db.get(name)?.is_some() {
+            batch.push(BatchOp::Delete(name.clone()));
+        }
+    }
+    if !suffixes.is_empty() {
+        db.scan("", &mut |dom, _| {
+            if suffixes.iter().any(|s| is_subdomain(dom, s)) && !names.iter().any(|n| n == dom) {
+                batch.push(BatchOp::Delete(dom.to_string()));
+            }
+            true
+        })?;
+    }
+    let n = batch.len();
+    apply(db, batch)?;
+    Ok(n)
+}
+
+/// Negative A or AAAA results are forgotten, entries without anything left are deleted.
+/// Entries not refreshed nor used for `older_than` seconds are deleted.
+/// Returns numbers of changed and deleted entries.
+pub fn purge(db: &mut dyn Database, negative: bool, older_than: Option<u64>) -> BoxResult<(usize, usize)> {
+    let now = now()?;
+    let mut batch = Vec::new();
+    let mut changed = 0;
+    let mut deleted = 0;
+    db.scan("", &mut |dom, mut ce| {
+        if let Some(age) = older_than {
+            if now.saturating_sub(ce.last_used()) >= age {
+                batch.push(BatchOp::Delete(dom.to_string()));
+                deleted += 1;
+                return true;
+            }
+        }
+        if negative {
+            let a4neg = ce.a4.as_ref().is_some_and(CacheEntry2::is_negative);
+            let a6neg = ce.a6.as_ref().is_some_and(CacheEntry2::is_negative);
+            if !a4neg && !a6neg {
+                return true;
+            }
+            if a4neg {
+                ce.a4 = None;
+            }
+            if a6neg {
+                ce.a6 = None;
+            }
+            if ce.a4.is_none() && ce.a6.is_none() {
+                batch.push(BatchOp::Delete(dom.to_string()));
+                deleted += 1;
+            } else {
+                batch.push(BatchOp::Put(dom.to_string(), ce));
+                changed += 1;
+            }
+        }
+        true
+    })?;
+    apply(db, batch)?;
+    Ok((changed, deleted))
+}
+
+#[derive(Default)]
+struct FamilyStats {
+    present: usize,
+    negative: usize,
+    stale: usize,
+    addresses: usize,
+}
+
+impl FamilyStats {
+    fn add(&mut self, ce2: &Option<CacheEntry2>, now: Time, opts: &Options) {
+        if let Some(ref x) = *ce2 {
+            self.present += 1;
+            self.addresses += x.a.len();
+            if x.is_negative() {
+                self.negative += 1;
+            }
+            if x.adjusted(now, opts).1 {
+                self.stale += 1;
+            }
+        }
+    }
+
+    fn print(&self, name: &str, out: &mut dyn Write) -> BoxResult<()> {
+        writeln!(
+            out,
+            "{:<6}{} answers ({} negative, {} stale), {} addresses",
+            name, self.present, self.negative, self.stale, self.addresses,
+        )?;
+        Ok(())
+    }
+}
+
+pub fn stats(db: &mut dyn Database, opts: &Options, out: &mut dyn Write) -> BoxResult<()> {
+    let now = now()?;
+    let mut entries = 0;
+    let mut size = 0;
+    let mut a4 = FamilyStats::default();
+    let mut a6 = FamilyStats::default();
+    let mut oldest: Option<Time> = None;
+    let mut newest: Option<Time> = None;
+    db.scan("", &mut |_, ce: CacheEntry| {
+        entries += 1;
+        size += ce.approx_size();
+        a4.add(&ce.a4, now, opts);
+        a6.add(&ce.a6, now, opts);
+        let t = ce.last_used();
+        oldest = Some(oldest.map_or(t, |x| x.min(t)));
+        newest = Some(newest.map_or(t, |x| x.max(t)));
+        true
+    })?;
+    writeln!(out, "entries {}", entries)?;
+    writeln!(out, "approximate size {} bytes", size)?;
+    a4.print("A", out)?;
+    a6.print("AAAA", out)?;
+    if let (Some(o), Some(n)) = (oldest, newest) {
+        writeln!(
+            out,
+            "last refreshed or used: oldest {} ago, newest {} ago",
+            format_age(now.saturating_sub(o)),
+            format_age(now.saturating_sub(n)),
+        )?;
+    }
+    Ok(())
+}
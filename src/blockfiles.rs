@@ -0,0 +1,81 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! Blocklists and response policy zones from files given on command line,
+//! reloaded when the files change.
+
+use std::fs;
+use std::path::PathBuf;
+use dnscache::{BlockAction, Blocklist, BoxResult, Rpz};
+use watch::WatchedFiles;
+use super::Cache;
+
+pub struct BlockFiles {
+    files: Vec<PathBuf>,
+    action: BlockAction,
+    rpz_files: Vec<PathBuf>,
+    /// For RPZ local data without TTL
+    ttl: u32,
+    watch: WatchedFiles,
+}
+
+impl BlockFiles {
+    pub fn new(files: &[PathBuf], action: BlockAction, rpz_files: &[PathBuf], ttl: u32) -> Self {
+        BlockFiles {
+            files: files.to_vec(),
+            action,
+            rpz_files: rpz_files.to_vec(),
+            ttl,
+            watch: WatchedFiles::new(files.iter().chain(rpz_files)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watch.is_empty()
+    }
+
+    /// Read all the files and apply them
+    pub fn load_into(&self, cache: &mut Cache) -> BoxResult<()> {
+        let lists = self.load_blocklists()?;
+        let zones = self.load_rpz()?;
+        cache.set_blocklists(lists);
+        cache.set_rpz(zones);
+        Ok(())
+    }
+
+    /// One list per file, named after it
+    fn load_blocklists(&self) -> BoxResult<Vec<Blocklist>> {
+        let mut lists = Vec::with_capacity(self.files.len());
+        for path in &self.files {
+            let name = path.display().to_string();
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", name, e))?;
+            let mut list = Blocklist::new(&name, self.action);
+            list.add_text(&text);
+            info!("Blocklist {}: {} domains", name, list.len());
+            lists.push(list);
+        }
+        Ok(lists)
+    }
+
+    /// One zone per file, named after it
+    fn load_rpz(&self) -> BoxResult<Vec<Rpz>> {
+        let mut zones = Vec::with_capacity(self.rpz_files.len());
+        for path in &self.rpz_files {
+            let name = path.display().to_string();
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", name, e))?;
+            let zone = Rpz::parse(&name, &text, self.ttl).map_err(|e| format!("{}: {}", name, e))?;
+            info!("RPZ {}: {} triggers", name, zone.len());
+            zones.push(zone);
+        }
+        Ok(zones)
+    }
+
+    /// Reload everything if some file has changed. On error previous lists and zones stay in use.
+    pub fn reload_if_changed(&mut self, cache: &mut Cache) {
+        if !self.watch.changed() {
+            return;
+        }
+        if let Err(e) = self.load_into(cache) {
+            error!("Keeping previous blocklists and RPZ: {}", e);
+        }
+    }
+}
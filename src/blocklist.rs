@@ -0,0 +1,194 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+use super::*;
+
+use details::{raw_questions, synthesize_reply};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::str::FromStr;
+use tap::tap_reply;
+
+/// TTL of 0.0.0.0 and :: answers to blocked names
+const BLOCK_TTL: Ttl = 60;
+
+/// Names that hosts-style blocklists map to themselves and that must not be blocked
+const HOSTS_BOILERPLATE: [&str; 8] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-allnodes",
+];
+
+/// How to answer queries for blocked names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockAction {
+    /// Name does not exist
+    NxDomain,
+    /// A queries get `0.0.0.0`, AAAA queries get `::`, other types get no records
+    Zero,
+    /// Refuse to answer
+    Refused,
+}
+
+impl FromStr for BlockAction {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "nxdomain" => Ok(BlockAction::NxDomain),
+            "zero" | "0.0.0.0" => Ok(BlockAction::Zero),
+            "refused" => Ok(BlockAction::Refused),
+            _ => Err(format!("unknown block action {}, expected nxdomain, zero or refused", s)),
+        }
+    }
+}
+
+/// Named set of blocked domains. Subdomains of listed domains are blocked as well.
+#[derive(Debug, Clone)]
+pub struct Blocklist {
+    name: String,
+    action: BlockAction,
+    domains: HashSet<String>,
+}
+
+impl Blocklist {
+    /// Empty list. `name` identifies it in logs and metrics.
+    pub fn new(name: &str, action: BlockAction) -> Self {
+        Blocklist {
+            name: name.to_string(),
+            action,
+            domains: HashSet::new(),
+        }
+    }
+
+    /// Name given on creation
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How blocked names are answered
+    pub fn action(&self) -> BlockAction {
+        self.action
+    }
+
+    /// Number of listed domains
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    /// No domains are listed
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    /// Block the domain and its subdomains. `*.` or `.` prefix is accepted and ignored.
+    pub fn add(&mut self, dom: &str) {
+        let dom = dom.trim_start_matches("*.").trim_matches('.');
+        if !dom.is_empty() {
+            self.domains.insert(dom.to_ascii_lowercase());
+        }
+    }
+
+    /// Add domains from text with one domain per line or from hosts-style text
+    /// (`0.0.0.0 ads.example`). `#` starts a comment.
+    pub fn add_text(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap();
+            let mut words = line.split_whitespace().peekable();
+            if let Some(first) = words.peek() {
+                if first.parse::<IpAddr>().is_ok() {
+                    words.next();
+                }
+            }
+            for w in words {
+                if HOSTS_BOILERPLATE.contains(&w) || w.parse::<IpAddr>().is_ok() {
+                    continue;
+                }
+                self.add(w);
+            }
+        }
+    }
+
+    /// Whether the domain or any of its parent domains is listed, ignoring letter case
+    pub fn matches(&self, dom: &str) -> bool {
+        let dom = dom.to_ascii_lowercase();
+        let mut d = dom.trim_end_matches('.');
+        loop {
+            if self.domains.contains(d) {
+                return true;
+            }
+            match d.find('.') {
+                Some(i) => d = &d[i + 1..],
+                None => return false,
+            }
+        }
+    }
+}
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Replace blocklists, e.g. after files they came from have changed.
+    /// The first list matching a queried name decides the answer.
+    pub fn set_blocklists(&mut self, lists: Vec<Blocklist>) {
+        self.blocklists = lists;
+    }
+
+    /// Index of the first blocklist matching any of the questions
+    pub(crate) fn find_blocklist(&self, qs: &[SimplifiedQuestion]) -> Option<usize> {
+        self.blocklists.iter().position(|b| qs.iter().any(|q| b.matches(&q.dom)))
+    }
+
+    /// Answer query according to the action of the blocklist, without asking upstream
+    pub(crate) fn reply_blocked(
+        &mut self,
+        src: N::ClientId,
+        received: SystemTime,
+        query: &[u8],
+        list: usize,
+    ) -> BoxResult<()> {
+        let (name, action) = {
+            let b = &self.blocklists[list];
+            (b.name.clone(), b.action)
+        };
+        info!("  blocked by {}", name);
+        Metrics::inc(&self.metrics.blocked);
+        self.metrics.inc_blocklist(&name);
+
+        let mut answers = Vec::new();
+        let rcode = match action {
+            BlockAction::NxDomain => 3,
+            BlockAction::Refused => 5,
+            BlockAction::Zero => {
+                for q in raw_questions(query)?.0 {
+                    match q.qtype {
+                        0x0001 => answers.push((q.offset, q.qtype, BLOCK_TTL, vec![0; 4])),
+                        0x001C => answers.push((q.offset, q.qtype, BLOCK_TTL, vec![0; 16])),
+                        _ => {}
+                    }
+                }
+                0
+            }
+        };
+        let reply = synthesize_reply(query, rcode, &answers)?;
+        self.net.send_to_client(&reply, src)?;
+        tap_reply(&mut self.taps, src, received, &reply, Outcome::Blocked);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_ignoring_case() {
+        let mut b = Blocklist::new("test", BlockAction::NxDomain);
+        b.add_text("0.0.0.0 Tracker.Example.NET\nads.example.com\n");
+        assert!(b.matches("ADS.Example.com"));
+        assert!(b.matches("x.Ads.EXAMPLE.com."));
+        assert!(b.matches("tracker.example.net"));
+        assert!(!b.matches("example.com"));
+    }
+}
@@ -0,0 +1,234 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+use super::*;
+
+use bytes::{BufMut, BigEndian as BE};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Counters of in-memory state of [`DnsCache`]
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Requests waiting for upstream to answer A or AAAA questions (including refreshes)
+    pub unreplied_requests: usize,
+    /// Domains these requests wait for
+    pub awaited_domains: usize,
+    /// Requests forwarded as is, waiting for upstream reply
+    pub direct_requests: usize,
+}
+
+/// In-flight request, as reported by [`DnsCache::unreplied_requests`]
+#[derive(Debug, Clone)]
+pub struct PendingRequest<C> {
+    /// DNS ID used for upstream
+    pub id: u16,
+    /// Who is waiting for the reply. None for refreshes initiated by [`DnsCache::refresh`]
+    pub client: Option<C>,
+    /// Domain names with flags whether A and AAAA are requested
+    pub questions: Vec<(String, bool, bool)>,
+    /// Client already got a reply from cache; this only updates the cache
+    pub refresh_only: bool,
+    /// Where the query was sent
+    pub upstream: UpstreamId,
+    /// Client asked with DNSSEC OK bit, so it is only answered with signatures or by upstream as is
+    pub dnssec_ok: bool,
+}
+
+/// `a.example` is a subdomain of `a.example`, `A.Example` and `example`, but not of `xa.example`.
+/// Empty or `.` suffix matches everything. Letter case is ignored.
+pub fn is_subdomain(dom: &str, suffix: &str) -> bool {
+    let suffix = suffix.trim_matches('.');
+    if dom.eq_ignore_ascii_case(suffix) || suffix.is_empty() {
+        return true;
+    }
+    let dom = dom.as_bytes();
+    dom.len() > suffix.len()
+        && dom[dom.len() - suffix.len()..].eq_ignore_ascii_case(suffix.as_bytes())
+        && dom[dom.len() - suffix.len() - 1] == b'.'
+}
+
+/// Whether `ip` is in network `net`/`prefix`. Addresses of different families never match.
+pub fn in_network(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Address bytes (4 or 16), as in [`AddrTtl::ip`]
+pub fn ip_octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(x) => x.octets().to_vec(),
+        IpAddr::V6(x) => x.octets().to_vec(),
+    }
+}
+
+/// Not cryptographically random, just hard to guess from outside
+fn query_id(salt: u16) -> u16 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.subsec_nanos()).unwrap_or(0);
+    ((nanos >> 16) as u16) ^ (nanos as u16) ^ salt.rotate_left(7)
+}
+
+pub(crate) fn make_query(id: u16, dom: &str, qtype: u16) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(dom.len() + 18);
+    buf.put_u16::<BE>(id);
+    buf.put_u16::<BE>(0x0100); // query, recursion desired
+    buf.put_u16::<BE>(1); // q-s
+    buf.put_u16::<BE>(0); // a-s
+    buf.put_u16::<BE>(0); // auth-s
+    buf.put_u16::<BE>(0); // addit
+    for l in dom.split('.') {
+        buf.put_u8(l.len() as u8);
+        buf.put(l);
+    }
+    buf.put_u8(0x00);
+    buf.put_u16::<BE>(qtype);
+    buf.put_u16::<BE>(0x0001); // IN
+    buf
+}
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Current options
+    pub fn options(&self) -> &Options {
+        &self.opts
+    }
+
+    /// Change options while running. New TTL limits apply to subsequent answers.
+    pub fn options_mut(&mut self) -> &mut Options {
+        &mut self.opts
+    }
+
+    /// The network, e.g. for adjusting socket options
+    pub fn network(&self) -> &N {
+        &self.net
+    }
+
+    /// Direct access to the database, e.g. for inspection
+    pub fn database(&mut self) -> &mut DB {
+        &mut self.db
+    }
+
+    /// Counters of in-memory state
+    pub fn stats(&self) -> Stats {
+        Stats {
+            unreplied_requests: self.unreplied_requests.len_slow(),
+            awaited_domains: self.dom_update_subscriptions.len(),
+            direct_requests: self.r2a.len(),
+        }
+    }
+
+    /// Requests waiting for upstream
+    pub fn unreplied_requests(&self) -> Vec<PendingRequest<N::ClientId>> {
+        self.unreplied_requests
+            .iter()
+            .map(|(_, r)| PendingRequest {
+                id: r.id,
+                client: r.clientid,
+                questions: r.q.iter().map(|q| (q.dom.clone(), q.a4, q.a6)).collect(),
+                refresh_only: r.inhibit_send,
+                upstream: r.upstream,
+                dnssec_ok: r.dnssec_ok,
+            })
+            .collect()
+    }
+
+    /// Forget the domain and all its subdomains. Returns number of removed entries.
+    pub fn flush_suffix(&mut self, suffix: &str) -> BoxResult<usize> {
+        let mut batch = Vec::new();
+        let reverse = &mut self.reverse;
+        // Keys are not sorted by suffix, so everything has to be visited
+        self.db.scan("", &mut |dom, ce| {
+            if is_subdomain(dom, suffix) {
+                for ce2 in ce.a4.iter().chain(ce.a6.iter()) {
+                    reverse.remove(dom, ce2);
+                }
+                batch.push(BatchOp::Delete(dom.to_string()));
+            }
+            true
+        })?;
+        let n = batch.len();
+        if n > 0 {
+            self.db.write_batch(batch)?;
+            self.db.flush()?;
+        }
+        Ok(n)
+    }
+
+    /// Forget one domain. Returns whether it was present.
+    pub fn flush_domain(&mut self, dom: &str) -> BoxResult<bool> {
+        let present = reverse::delete_entry(&mut self.db, &mut self.reverse, dom)?;
+        if present {
+            self.db.flush()?;
+        }
+        Ok(present)
+    }
+
+    /// Ask upstream for A and AAAA records of the domain now, regardless of TTL.
+    /// Families disabled by [`Options::family`] are skipped.
+    /// The database gets updated when the replies arrive.
+    pub fn refresh(&mut self, dom: &str) -> BoxResult<()> {
+        let dom = dom.trim_end_matches('.');
+        if dom.is_empty() || dom.split('.').any(|l| l.is_empty() || l.len() > 63) {
+            Err(format!("bad domain name {}", dom))?;
+        }
+        if !self.opts.family.disables(false) {
+            self.send_own_query(dom, 0x0001)?;
+        }
+        if !self.opts.family.disables(true) {
+            self.send_own_query(dom, 0x001C)?;
+        }
+        info!("refresh {}", dom);
+        Ok(())
+    }
+
+    /// Ask upstream for A (`qtype` 1) or AAAA (28) records of the domain on our own behalf.
+    /// Nobody gets the reply, it only updates the database.
+    pub(crate) fn send_own_query(&mut self, dom: &str, qtype: u16) -> BoxResult<()> {
+        let upstream = self.upstream_for(dom);
+        let id = query_id(self.dom_update_subscriptions.len() as u16 ^ qtype);
+        let r = SimplifiedRequest {
+            id,
+            clientid: None,
+            q: vec![SimplifiedQuestion {
+                dom: dom.to_string(),
+                a4: qtype == 0x0001,
+                a6: qtype == 0x001C,
+            }],
+            inhibit_send: true,
+            received: SystemTime::now(),
+            upstream,
+            want_ad: false,
+            dnssec_ok: false,
+            checking_disabled: false,
+            query: vec![],
+        };
+        let ask_dnssec_ok = self.cached_with_dnssec_ok(&r.q);
+        let rid = self.unreplied_requests.insert(r);
+        self.dom_update_subscriptions.insert(dom.to_string(), rid);
+        let query = make_query(id, dom, qtype);
+        let query = self.upstream_query(&query, ask_dnssec_ok)?.into_owned();
+        self.send_upstream(id, &query, upstream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subdomains() {
+        assert!(is_subdomain("host.corp.example", "corp.example"));
+        assert!(is_subdomain("Host.CORP.example", "corp.example"));
+        assert!(is_subdomain("corp.example", "Corp.Example."));
+        assert!(is_subdomain("anything", ""));
+        assert!(!is_subdomain("xcorp.example", "corp.example"));
+        assert!(!is_subdomain("example", "corp.example"));
+    }
+}
@@ -0,0 +1,175 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! Control socket of a running `dnscache serve`.
+//!
+//! Line protocol: one command per line, reply is zero or more lines
+//! followed by a line `ok` or `error: <message>`.
+
+use std::path::Path;
+use std::sync::mpsc::{Receiver, Sender};
+use dnscache::BoxResult;
+use super::Cache;
+
+pub struct Command {
+    pub line: String,
+    pub reply: Sender<String>,
+}
+
+const HELP: &str = "\
+flush <domain>               forget the domain
+flush-suffix <suffix>        forget the domain and all its subdomains
+refresh <domain>             ask upstream for A and AAAA now
+stats                        in-memory counters
+get                          current TTL options
+set <option> <value>         change min-ttl, max-ttl or neg-ttl
+requests                     list requests waiting for upstream
+";
+
+/// Start accepting connections in background threads. Commands are to be
+/// executed by the serving thread, see [`execute_pending`].
+#[cfg(unix)]
+pub fn listen(path: &Path) -> BoxResult<Receiver<Command>> {
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    if let Ok(m) = fs::symlink_metadata(path) {
+        if m.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    // Only the owner (usually root) may flush or tune the cache
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            match conn {
+                Ok(conn) => {
+                    let tx = tx.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(conn, &tx) {
+                            debug!("control connection: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("control socket: {}", e),
+            }
+        }
+    });
+    Ok(rx)
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &Path) -> BoxResult<Receiver<Command>> {
+    Err("control socket is only supported on Unix")?
+}
+
+#[cfg(unix)]
+fn handle_connection(conn: ::std::os::unix::net::UnixStream, tx: &Sender<Command>) -> BoxResult<()> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::mpsc::channel;
+
+    let mut out = conn.try_clone()?;
+    for line in BufReader::new(conn).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (reply_tx, reply_rx) = channel();
+        tx.send(Command { line, reply: reply_tx })?;
+        out.write_all(reply_rx.recv()?.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Execute commands received so far, without waiting for more
+pub fn execute_pending(cache: &mut Cache, commands: &Receiver<Command>) {
+    while let Ok(cmd) = commands.try_recv() {
+        let reply = execute(cache, &cmd.line);
+        let _ = cmd.reply.send(reply);
+    }
+}
+
+fn execute(cache: &mut Cache, line: &str) -> String {
+    let mut out = String::new();
+    match execute1(cache, line, &mut out) {
+        Ok(()) => out.push_str("ok\n"),
+        Err(e) => out.push_str(&format!("error: {}\n", e)),
+    }
+    out
+}
+
+fn execute1(cache: &mut Cache, line: &str, out: &mut String) -> BoxResult<()> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["help"] => out.push_str(HELP),
+        ["flush", dom] => {
+            if cache.flush_domain(dom)? {
+                info!("control: flushed {}", dom);
+            } else {
+                out.push_str("not present\n");
+            }
+        }
+        ["flush-suffix", suffix] => {
+            let n = cache.flush_suffix(suffix)?;
+            info!("control: flushed {} entries under {}", n, suffix);
+            out.push_str(&format!("removed {}\n", n));
+        }
+        ["refresh", dom] => cache.refresh(dom)?,
+        ["stats"] => {
+            let s = cache.stats();
+            out.push_str(&format!("unreplied_requests {}\n", s.unreplied_requests));
+            out.push_str(&format!("awaited_domains {}\n", s.awaited_domains));
+            out.push_str(&format!("direct_requests {}\n", s.direct_requests));
+            let db = cache.database();
+            out.push_str(&format!("hot_cache_entries {}\n", db.len()));
+            out.push_str(&format!("hot_cache_memory {}\n", db.memory()));
+            let metrics = cache.metrics();
+            for (list, n) in metrics.blocked_by_list.lock().unwrap().iter() {
+                out.push_str(&format!("blocked {} {}\n", list, n));
+            }
+            for (zone, n) in metrics.rpz_by_zone.lock().unwrap().iter() {
+                out.push_str(&format!("rpz {} {}\n", zone, n));
+            }
+        }
+        ["get"] => {
+            let o = cache.options();
+            out.push_str(&format!("min-ttl {}\nmax-ttl {}\nneg-ttl {}\n", o.min_ttl, o.max_ttl, o.neg_ttl));
+        }
+        ["set", name, value] => {
+            let o = cache.options_mut();
+            match *name {
+                "min-ttl" => o.min_ttl = value.parse()?,
+                "max-ttl" => o.max_ttl = value.parse()?,
+                "neg-ttl" => o.neg_ttl = value.parse()?,
+                _ => Err(format!("unknown option {}", name))?,
+            }
+            info!("control: {} set to {}", name, value);
+        }
+        ["requests"] => {
+            for r in cache.unreplied_requests() {
+                let client = match r.client {
+                    Some(c) => c.to_string(),
+                    None => "-".to_string(),
+                };
+                let qs: Vec<String> = r.questions.iter().map(|&(ref dom, a4, a6)| {
+                    let t = match (a4, a6) {
+                        (true, true) => "ANY",
+                        (true, false) => "A",
+                        _ => "AAAA",
+                    };
+                    format!("{} {}", t, dom)
+                }).collect();
+                let refresh = if r.refresh_only { " (refresh)" } else { "" };
+                let dnssec_ok = if r.dnssec_ok { " (DO)" } else { "" };
+                let via = if r.upstream != 0 { format!(" via upstream {}", r.upstream) } else { String::new() };
+                out.push_str(&format!("{}\t{}\t{}{}{}{}\n", r.id, client, qs.join(", "), refresh, dnssec_ok, via));
+            }
+        }
+        _ => Err("unknown command, try help")?,
+    }
+    Ok(())
+}
@@ -7,28 +7,89 @@ use dns_parser::QueryType::{A, AAAA, All as QTAll};
 use dns_parser::QueryClass::{IN, Any as QCAny};
 use dns_parser::RRData;
 use bytes::{BufMut, BigEndian as BE};
+use rpz::RpzAction;
+use std::borrow::Cow;
+use std::net::IpAddr;
 use std::time::{SystemTime, UNIX_EPOCH};
+use order::OrderState;
+use tap::{tap_client_response, tap_reply};
+
+/// DNSSEC status of an upstream reply
+#[derive(Default)]
+pub(crate) struct ReplyDnssec {
+    /// Answer is validated by dnscache
+    pub secure: bool,
+    /// Reply has DNSSEC OK bit, so the query had it too
+    pub dnssec_ok: bool,
+    /// RRSIG records of the answer section
+    pub sigs: Vec<wire::Record>,
+    /// Upstream set AD bit. Passed on to clients waiting for this very reply
+    /// with [`Options::trust_upstream_ad`], never saved.
+    pub upstream_ad: bool,
+    /// The reply with all its DNSSEC records, for DNSSEC-aware clients the cache cannot answer
+    pub raw: Vec<u8>,
+}
+
+/// DNSSEC part of a reply made by dnscache
+#[derive(Default)]
+pub(crate) struct AnswerDnssec {
+    /// Set AD bit, if the client wants to know
+    pub authenticated: bool,
+    /// RRSIG records for clients asking with DNSSEC OK bit: owner, TTL, record data
+    pub sigs: Vec<(String, Ttl, Vec<u8>)>,
+}
+
+/// Send upstream reply to the client as is, with client's ID and CD bit and AD bit set by dnscache
+pub(crate) fn send_raw_reply<N: Network>(
+    net: &N,
+    r: &SimplifiedRequest<N::ClientId>,
+    raw: &[u8],
+    authenticated: bool,
+) -> BoxResult<Vec<u8>> {
+    if raw.len() < 12 {
+        Err("packet too short")?;
+    }
+    let mut reply = raw.to_vec();
+    reply[0] = (r.id >> 8) as u8;
+    reply[1] = r.id as u8;
+    reply[3] &= !((wire::FLAG_AD | wire::FLAG_CD) as u8);
+    if authenticated && r.want_ad {
+        reply[3] |= wire::FLAG_AD as u8;
+    }
+    if r.checking_disabled {
+        reply[3] |= wire::FLAG_CD as u8;
+    }
+    if let Some(clientid) = r.clientid {
+        net.send_to_client(&reply, clientid)?;
+    }
+    Ok(reply)
+}
 
 pub(crate) fn send_dns_reply<N: Network>(
     net: &N,
     r: &SimplifiedRequest<N::ClientId>,
+    rcode: u8,
+    cnames: &[(String, String, Ttl)],
     ans_a: &[(String, Vec<AddrTtl>)],
     ans_aaaa: &[(String, Vec<AddrTtl>)],
-) -> BoxResult<()> {
+    dnssec: &AnswerDnssec,
+) -> BoxResult<Vec<u8>> {
 
-    let mut num_answers = ans_a.iter().fold(0, |a, x| a + x.1.len()) +
-        ans_aaaa.iter().fold(0, |a, x| a + x.1.len());
+    let sigs: &[_] = if r.dnssec_ok { &dnssec.sigs } else { &[] };
+    let mut num_answers = cnames.len() + ans_a.iter().fold(0, |a, x| a + x.1.len()) +
+        ans_aaaa.iter().fold(0, |a, x| a + x.1.len()) + sigs.len();
     if num_answers > 0xFFFF {
         num_answers = 0xFFFF;
     } // XXX
 
     let mut reply_buf = Vec::with_capacity(600);
     reply_buf.put_u16::<BE>(r.id);
-    reply_buf.put_u16::<BE>(0x8180); // response, recursion, recursion
+    let ad = if dnssec.authenticated && r.want_ad { 0x0020 } else { 0 };
+    reply_buf.put_u16::<BE>(0x8180 | ad | u16::from(rcode & 0x0F)); // response, recursion, recursion
     reply_buf.put_u16::<BE>(r.q.len() as u16); // q-s
     reply_buf.put_u16::<BE>(num_answers as u16); // a-s
     reply_buf.put_u16::<BE>(0); // auth-s
-    reply_buf.put_u16::<BE>(0); // addit
+    reply_buf.put_u16::<BE>(if r.dnssec_ok { 1 } else { 0 }); // addit
 
     fn putname(reply_buf: &mut Vec<u8>, dom: &str) {
         for l in dom.split('.') {
@@ -52,6 +113,16 @@ pub(crate) fn send_dns_reply<N: Network>(
         }
         reply_buf.put_u16::<BE>(0x0001); // IN
     }
+    for &(ref dom, ref target, ttl) in cnames {
+        putname(&mut reply_buf, dom);
+        reply_buf.put_u16::<BE>(0x0005); // CNAME
+        reply_buf.put_u16::<BE>(0x0001); // IN
+        reply_buf.put_u32::<BE>(ttl); // TTL
+        let mut rdata = Vec::with_capacity(target.len() + 2);
+        putname(&mut rdata, target);
+        reply_buf.put_u16::<BE>(rdata.len() as u16); // data len
+        reply_buf.put(&rdata[..]);
+    }
     for &(ref dom, ref a) in ans_a {
         for &AddrTtl { ref ip, ttl } in a {
             putname(&mut reply_buf, dom);
@@ -78,25 +149,110 @@ pub(crate) fn send_dns_reply<N: Network>(
             reply_buf.put(&ip[..]);
         }
     }
+    for &(ref dom, ttl, ref rdata) in sigs {
+        putname(&mut reply_buf, dom);
+        reply_buf.put_u16::<BE>(0x002E); // RRSIG
+        reply_buf.put_u16::<BE>(0x0001); // IN
+        reply_buf.put_u32::<BE>(ttl); // TTL
+        reply_buf.put_u16::<BE>(rdata.len() as u16); // data len
+        reply_buf.put(&rdata[..]);
+    }
+    if r.dnssec_ok {
+        reply_buf.put_u8(0x00); // root
+        reply_buf.put_u16::<BE>(0x0029); // OPT
+        reply_buf.put_u16::<BE>(1232); // UDP payload size
+        reply_buf.put_u32::<BE>(0x0000_8000); // DNSSEC OK
+        reply_buf.put_u16::<BE>(0); // data len
+    }
+
+    if let Some(clientid) = r.clientid {
+        net.send_to_client(&reply_buf[..], clientid)?;
+    }
+    Ok(reply_buf)
+}
+
+/// Question as it is laid out in a packet
+pub(crate) struct RawQuestion {
+    /// Where the name starts, for compression pointers
+    pub offset: usize,
+    pub qtype: u16,
+}
+
+/// Questions of a query and the offset where the question section ends
+pub(crate) fn raw_questions(query: &[u8]) -> BoxResult<(Vec<RawQuestion>, usize)> {
+    if query.len() < 12 {
+        Err("short packet")?;
+    }
+    let qdcount = (u16::from(query[4]) << 8) | u16::from(query[5]);
+    let mut qs = Vec::with_capacity(qdcount as usize);
+    let mut off = 12;
+    for _ in 0..qdcount {
+        let offset = off;
+        loop {
+            let l = *query.get(off).ok_or("truncated question")? as usize;
+            if l & 0xC0 != 0 {
+                Err("compressed name in question")?;
+            }
+            off += 1 + l;
+            if l == 0 {
+                break;
+            }
+        }
+        if off + 4 > query.len() {
+            Err("truncated question")?;
+        }
+        let qtype = (u16::from(query[off]) << 8) | u16::from(query[off + 1]);
+        qs.push(RawQuestion { offset, qtype });
+        off += 4;
+    }
+    Ok((qs, off))
+}
 
-    net.send_to_client(&reply_buf[..], r.clientid)?;
-    Ok(())
+/// Reply to `query` made up locally: questions are copied, followed by `answers`
+/// given as (offset of owner name within the reply, type, TTL, data).
+/// Questions are at the same offsets as in the query.
+pub(crate) fn synthesize_reply(
+    query: &[u8],
+    rcode: u8,
+    answers: &[(usize, u16, Ttl, Vec<u8>)],
+) -> BoxResult<Vec<u8>> {
+    let (_, qend) = raw_questions(query)?;
+    let qflags = (u16::from(query[2]) << 8) | u16::from(query[3]);
+
+    let mut reply_buf = Vec::with_capacity(qend + answers.len() * 32);
+    reply_buf.put(&query[0..2]); // id
+    reply_buf.put_u16::<BE>(0x8080 | (qflags & 0x7900) | u16::from(rcode & 0x0F)); // response, opcode, rd, ra
+    reply_buf.put(&query[4..6]); // q-s
+    reply_buf.put_u16::<BE>(answers.len() as u16); // a-s
+    reply_buf.put_u16::<BE>(0); // auth-s
+    reply_buf.put_u16::<BE>(0); // addit
+    reply_buf.put(&query[12..qend]);
+    for &(owner, typ, ttl, ref rdata) in answers {
+        reply_buf.put_u16::<BE>(0xC000 | owner as u16);
+        reply_buf.put_u16::<BE>(typ);
+        reply_buf.put_u16::<BE>(0x0001); // IN
+        reply_buf.put_u32::<BE>(ttl);
+        reply_buf.put_u16::<BE>(rdata.len() as u16);
+        reply_buf.put(&rdata[..]);
+    }
+    Ok(reply_buf)
 }
 
 enum TryAnswerRequestResult {
-    Resolved(AdjustTtlResult),
+    /// Also the reply, if it was sent
+    Resolved(AdjustTtlResult, Option<Vec<u8>>),
     UnknownsRemain(usize),
 }
 
 
 #[derive(PartialEq, Debug)]
-enum AdjustTtlResult {
+pub(crate) enum AdjustTtlResult {
     Ok,
     Expired,
     Negative(u64),
 }
 
-fn adjust_ttl(
+pub(crate) fn adjust_ttl(
     v: &[AddrTtl],
     now: Time,
     then: Time,
@@ -125,28 +281,107 @@ fn adjust_ttl(
     (result, vv)
 }
 
+/// Unreadable entries are treated as absent, so that one bad value does not break answering.
+/// They are deleted, so they don't linger unused until garbage collection.
+pub(crate) fn get_entry<DB: Database>(db: &mut DB, dom: &str) -> Option<CacheEntry> {
+    match db.get(dom) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("  unreadable entry for {}: {}, deleting", dom, e);
+            if let Err(e) = db.delete(dom) {
+                warn!("  failed to delete {}: {}", dom, e);
+            }
+            None
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)] // all the state answers are made from
 fn try_answer_request<DB: Database, N: Network>(
     db: &mut DB,
+    local: &LocalRecords,
     now: Time,
     net: &N,
     r: &SimplifiedRequest<N::ClientId>,
-    max_ttl: u32,
-    min_ttl: u32,
+    opts: &Options,
+    order: &mut OrderState,
+    require_dnssec_ok: bool,
+    upstream_ad: bool,
 ) -> BoxResult<TryAnswerRequestResult> {
+    let (max_ttl, min_ttl) = (opts.max_ttl, opts.min_ttl);
 
     let mut num_unknowns = 0;
 
+    let mut cnames = Vec::new();
     let mut ans_a4 = Vec::with_capacity(4);
     let mut ans_a6 = Vec::with_capacity(4);
 
     let mut ttl_status = AdjustTtlResult::Ok;
+    // Local records and DNS64 synthesis are never authenticated.
+    // Cached data is, when validated or when the client waits for an upstream reply with trusted AD bit.
+    let mut dnssec = AnswerDnssec {
+        authenticated: true,
+        sigs: vec![],
+    };
 
     for q in &r.q {
         assert!(q.a4 || q.a6);
-        if let Some(ce) = db.get(q.dom.as_str())? {
-            if q.a4 {
-                if let Some(a4) = ce.a4 {
+        if let Some(la) = local.resolve(&q.dom) {
+            dnssec.authenticated = false;
+            cnames.extend(la.cnames);
+            if let Some((a4, a6)) = la.addrs {
+                if q.a4 {
+                    ans_a4.push((la.owner.clone(), a4));
+                }
+                if q.a6 {
+                    ans_a6.push((la.owner, a6));
+                }
+            } else {
+                // Local CNAME to outside: use whatever is cached for the target, as is.
+                // Uncached target is asked upstream, see `fetch_local_target`.
+                let ce = get_entry(db, &la.owner).unwrap_or_default();
+                match (q.a4, ce.a4) {
+                    (true, Some(a4)) => ans_a4.push((la.owner.clone(), adjust_ttl(&a4.a, now, a4.t, max_ttl, min_ttl).1)),
+                    (true, None) => num_unknowns += 1,
+                    _ => (),
+                }
+                match (q.a6, ce.a6) {
+                    (true, Some(a6)) => ans_a6.push((la.owner, adjust_ttl(&a6.a, now, a6.t, max_ttl, min_ttl).1)),
+                    (true, None) => num_unknowns += 1,
+                    _ => (),
+                }
+            }
+            continue;
+        }
+        let ce = get_entry(db, q.dom.as_str());
+        let (skip_a4, skip_a6) = (
+            q.a4 && opts.family.suppresses(false, ce.as_ref()),
+            q.a6 && opts.family.suppresses(true, ce.as_ref()),
+        );
+        if skip_a4 || skip_a6 {
+            dnssec.authenticated = false;
+            if skip_a4 {
+                ans_a4.push((q.dom.clone(), vec![]));
+            }
+            if skip_a6 {
+                ans_a6.push((q.dom.clone(), vec![]));
+            }
+            if (!q.a4 || skip_a4) && (!q.a6 || skip_a6) {
+                continue;
+            }
+        }
+        if let Some(ce) = ce {
+            if q.a4 && !skip_a4 {
+                if let Some(ref a4) = ce.a4 {
+                    if require_dnssec_ok && !a4.dnssec_ok {
+                        num_unknowns += 1;
+                        continue;
+                    }
                     let (tr, a4adj) = adjust_ttl(&a4.a, now, a4.t, max_ttl, min_ttl);
+                    dnssec.authenticated &= a4.secure || upstream_ad;
+                    if let Some(x) = a4adj.first() {
+                        dnssec.sigs.extend(a4.sigs.iter().map(|s| (q.dom.clone(), x.ttl, s.rdata.clone())));
+                    }
                     if ttl_status == AdjustTtlResult::Ok {
                         ttl_status = tr
                     }
@@ -157,12 +392,30 @@ fn try_answer_request<DB: Database, N: Network>(
                 }
             }
 
-            if q.a6 {
-                if let Some(a6) = ce.a6 {
-                    let (tr, a6adj) = adjust_ttl(&a6.a, now, a6.t, max_ttl, min_ttl);
+            if q.a6 && !skip_a6 {
+                if let Some(ref a6) = ce.a6 {
+                    if require_dnssec_ok && !a6.dnssec_ok {
+                        num_unknowns += 1;
+                        continue;
+                    }
+                    let synthesized = opts.dns64.as_ref().is_some_and(|x| !x.has_usable_aaaa(&a6.a));
+                    dnssec.authenticated &= (a6.secure || upstream_ad) && !synthesized;
+                    let (tr, a6adj) = match opts.dns64 {
+                        Some(ref dns64) => match dns64.answer(ce.a4.as_ref(), a6, now, opts) {
+                            Some(x) => x,
+                            None => {
+                                num_unknowns += 1;
+                                continue;
+                            }
+                        },
+                        None => adjust_ttl(&a6.a, now, a6.t, max_ttl, min_ttl),
+                    };
                     if ttl_status == AdjustTtlResult::Ok {
                         ttl_status = tr
                     }
+                    if let (false, Some(x)) = (synthesized, a6adj.first()) {
+                        dnssec.sigs.extend(a6.sigs.iter().map(|s| (q.dom.clone(), x.ttl, s.rdata.clone())));
+                    }
                     ans_a6.push((q.dom.clone(), a6adj));
                 } else {
                     num_unknowns += 1;
@@ -178,12 +431,30 @@ fn try_answer_request<DB: Database, N: Network>(
     if num_unknowns > 0 {
         return Ok(TryAnswerRequestResult::UnknownsRemain(num_unknowns));
     }
+    let mut reply = None;
     if !r.inhibit_send {
-        send_dns_reply(net, r, &ans_a4, &ans_a6)?;
+        let client = r.clientid.and_then(|x| net.client_ip(x));
+        for (sets, covered) in [(&mut ans_a4, 0x0001), (&mut ans_a6, 0x001C)] {
+            for &mut (ref dom, ref mut a) in sets.iter_mut() {
+                if order.arrange(&opts.order, dom, client, a) {
+                    // Signature is for the whole set
+                    dnssec.sigs.retain(|s| s.0 != *dom || wire::rrsig_type_covered(&s.2) != Some(covered));
+                }
+            }
+        }
+        reply = Some(send_dns_reply(net, r, 0, &cnames, &ans_a4, &ans_a6, &dnssec)?);
     }
-    Ok(TryAnswerRequestResult::Resolved(ttl_status))
+    Ok(TryAnswerRequestResult::Resolved(ttl_status, reply))
 }
 
+/// Address of an A or AAAA record
+fn rr_ip(data: &RRData) -> Option<IpAddr> {
+    match *data {
+        RRData::A(x) => Some(IpAddr::V4(x)),
+        RRData::AAAA(x) => Some(IpAddr::V6(x)),
+        _ => None,
+    }
+}
 
 #[derive(PartialEq)]
 enum StepResult {
@@ -209,31 +480,78 @@ macro_rules! may_return_early {
 }
 
 impl<DB: Database, N: Network> DnsCache<DB, N> {
-    fn packet_from_upstream(&mut self, buf: &[u8]) -> BoxResult<()> {
+    fn packet_from_upstream(&mut self, buf: &[u8], upstream: UpstreamId) -> BoxResult<()> {
         info!("  upstream");
+        for t in &mut self.taps {
+            t.forwarder_response(upstream, buf);
+        }
+        #[cfg(feature = "dnssec")]
+        {
+            // Direct forwards keep the client's ID, which may be the same as of an own key query
+            if self.opts.dnssec.is_some() && wire::Message::parse(buf).is_ok_and(|m| self.is_key_reply(&m, upstream)) {
+                return self.validate_upstream_reply(buf, upstream);
+            }
+        }
+        if self.handle_direct_replies(buf, upstream)? == EarlyReturn {
+            return Ok(());
+        }
+        #[cfg(feature = "dnssec")]
+        {
+            if self.opts.dnssec.is_some() {
+                return self.validate_upstream_reply(buf, upstream);
+            }
+        }
+        self.process_unvalidated_reply(buf, upstream)
+    }
+
+    /// Upstream reply not validated by dnscache: nothing gets saved as authenticated
+    pub(crate) fn process_unvalidated_reply(&mut self, buf: &[u8], upstream: UpstreamId) -> BoxResult<()> {
+        let m = wire::Message::parse(buf)?;
+        let dnssec = ReplyDnssec {
+            secure: false,
+            dnssec_ok: m.dnssec_ok(),
+            upstream_ad: m.flags & wire::FLAG_AD != 0,
+            raw: buf.to_vec(),
+            sigs: vec![],
+        };
+        self.process_split_reply(m, upstream, dnssec)
+    }
+
+    /// Process upstream reply without its DNSSEC records, keeping the signatures of the answers
+    pub(crate) fn process_split_reply(&mut self, m: wire::Message, upstream: UpstreamId, mut dnssec: ReplyDnssec) -> BoxResult<()> {
+        let (buf, sigs) = wire::split_dnssec_records(m);
+        dnssec.sigs = sigs;
+        self.process_upstream_reply(&buf, upstream, dnssec)
+    }
+
+    /// Rest of upstream reply handling, with DNSSEC records already split off
+    pub(crate) fn process_upstream_reply(&mut self, buf: &[u8], upstream: UpstreamId, dnssec: ReplyDnssec) -> BoxResult<()> {
         let p = Packet::parse(buf)?;
+        self.note_upstream_reply(p.header.id);
 
         may_return_early!{
-            handle_direct_replies(self, buf, &p)?;
-            check_questions(self, &p)?;
+            check_questions(self, &p, upstream)?;
         };
 
         let mut cnames = HashMap::new();
         let mut actual_answers = vec![];
+        let mut tampered = vec![];
 
         may_return_early! {
             get_cname_redirs(&p, &mut cnames)?;
             make_list_of_ips(&p, &cnames, &mut actual_answers)?;
-            check_answers(self, &p, &actual_answers)?;
+            check_answers(self, &p, &mut actual_answers, upstream)?;
+            filter_bogus_ips(self, &mut actual_answers, &mut tampered)?;
+            check_response_ips(self, &p, &actual_answers)?;
         }
 
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
         let mut tmp: HashMap<String, CacheEntry> = HashMap::new();
 
         may_return_early! {
-            build_new_entries(&p, actual_answers, &mut tmp, now)?;
-            save_entries_to_database(self, &mut tmp)?;
-            reply_to_client(self, tmp, now)?;
+            build_new_entries(&p, actual_answers, &mut tmp, now, &dnssec)?;
+            save_entries_to_database(self, &mut tmp, &tampered, now)?;
+            reply_to_client(self, tmp, now, p.header.id, &dnssec)?;
         }
 
         Ok(())
@@ -243,22 +561,31 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
 
     // 1. Handle direct requests
 
-    fn handle_direct_replies(&mut self, buf: &[u8], p: &Packet) -> BoxResult<StepResult> {
-        if let Some(ca) = self.r2a.remove(&p.header.id) {
+    fn handle_direct_replies(&mut self, buf: &[u8], upstream: UpstreamId) -> BoxResult<StepResult> {
+        if buf.len() < 12 {
+            Err("packet too short")?;
+        }
+        let id = (u16::from(buf[0]) << 8) | u16::from(buf[1]);
+        if self.r2a.get(&id).map(|x| x.2) != Some(upstream) {
+            return Ok(GoOn);
+        }
+        if let Some((ca, received, _)) = self.r2a.remove(&id) {
             info!("  direct reply");
+            self.note_upstream_reply(id);
             self.net.send_to_client(buf, ca)?;
+            tap_reply(&mut self.taps, ca, received, buf, Outcome::Forwarded);
             Ok(EarlyReturn)
         } else {
             Ok(GoOn)
         }
     }
 
-    fn check_dom(&self, dom: &str, id: u16) -> bool {
+    pub(crate) fn check_dom(&self, dom: &str, id: u16, upstream: UpstreamId) -> bool {
         if let Some(rqs) = self.dom_update_subscriptions.get_vec(dom) {
             let mut good = false;
             for i in rqs {
                 if let Some(rq) = self.unreplied_requests.get(*i) {
-                    if rq.id == id {
+                    if rq.id == id && rq.upstream == upstream {
                         good = true;
                     }
                 } else {
@@ -267,23 +594,25 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
                 }
             }
             if !good {
-                warn!("  ID mismatch");
+                warn!("  ID or upstream mismatch");
+                Metrics::inc(&self.metrics.id_mismatch);
                 return false;
             } else {
                 return true;
             }
         } else {
             info!("  unsolicited reply for {}", dom);
+            Metrics::inc(&self.metrics.unsolicited);
             return false;
         }
     }
 
     // 2. Check if questin list cache poisoning attempt
 
-    fn check_questions(&self, p: &Packet) -> BoxResult<StepResult> {
+    fn check_questions(&self, p: &Packet, upstream: UpstreamId) -> BoxResult<StepResult> {
         for q in &p.questions {
             let dom = q.qname.to_string();
-            if !self.check_dom(dom.as_str(), p.header.id) {
+            if !self.check_dom(dom.as_str(), p.header.id, upstream) {
                 return Ok(EarlyReturn);
             }
         }
@@ -342,15 +671,90 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
     fn check_answers(
         &self,
         p: &Packet,
-        actual_answers: &[(String, &RRData, Ttl)],
+        actual_answers: &mut Vec<(String, &RRData, Ttl)>,
+        upstream: UpstreamId,
     ) -> BoxResult<StepResult> {
 
-        for &(ref dom, data, _) in actual_answers {
-            if !self.check_dom(dom.as_str(), p.header.id) {
+        for &(ref dom, data, _) in actual_answers.iter() {
+            if !self.check_dom(dom.as_str(), p.header.id, upstream) {
                 error!("  offending entry: {:?}", data);
                 return Ok(EarlyReturn);
             }
         }
+
+        if let Some(ref rebind) = self.opts.rebind {
+            let metrics = &self.metrics;
+            actual_answers.retain(|&(ref dom, data, _)| {
+                let ip = match rr_ip(data) {
+                    Some(x) => x,
+                    None => return true,
+                };
+                if rebind.rejects(dom, ip) {
+                    warn!("  rebinding protection: dropping {} for {}", ip, dom);
+                    Metrics::inc(&metrics.rebind_filtered);
+                    return false;
+                }
+                true
+            });
+        }
+        Ok(GoOn)
+    }
+
+    // 5a. Remove bogus addresses. Names with nothing else in the reply are treated as failed lookups:
+    // they are not saved, and only answered if something is cached for them.
+    fn filter_bogus_ips(
+        &self,
+        actual_answers: &mut Vec<(String, &RRData, Ttl)>,
+        tampered: &mut Vec<String>,
+    ) -> BoxResult<StepResult> {
+        if self.opts.bogus_ips.is_empty() {
+            return Ok(GoOn);
+        }
+        let is_bogus = |data: &RRData| {
+            rr_ip(data).is_some_and(|ip| self.opts.bogus_ips.iter().any(|&(net, prefix)| in_network(ip, net, prefix)))
+        };
+        let mut bogus_doms: Vec<String> = actual_answers
+            .iter()
+            .filter(|&&(_, data, _)| is_bogus(data))
+            .map(|x| x.0.clone())
+            .collect();
+        if bogus_doms.is_empty() {
+            return Ok(GoOn);
+        }
+        bogus_doms.sort();
+        bogus_doms.dedup();
+        for dom in bogus_doms {
+            let (bad, good): (Vec<_>, Vec<_>) = actual_answers
+                .iter()
+                .filter(|x| x.0 == dom)
+                .map(|x| x.1)
+                .partition(|&data| is_bogus(data));
+            if good.is_empty() {
+                error!("  suspected tampering: only bogus addresses for {}: {:?}", dom, bad);
+                Metrics::inc(&self.metrics.bogus_replies);
+                tampered.push(dom);
+            } else {
+                warn!("  ignoring bogus addresses for {}: {:?}", dom, bad);
+            }
+        }
+        actual_answers.retain(|&(_, data, _)| !is_bogus(data));
+        Ok(GoOn)
+    }
+
+    // 5b. Apply response IP triggers of response policy zones. Matching replies are not saved.
+    fn check_response_ips(
+        &mut self,
+        p: &Packet,
+        actual_answers: &[(String, &RRData, Ttl)],
+    ) -> BoxResult<StepResult> {
+        if self.rpz.is_empty() {
+            return Ok(GoOn);
+        }
+        let ips: Vec<IpAddr> = actual_answers.iter().filter_map(|x| rr_ip(x.1)).collect();
+        if let Some((zone, action)) = self.rpz_ip_policy(&p.questions, &ips) {
+            self.reply_rpz_waiting(p, zone, &action)?;
+            return Ok(EarlyReturn);
+        }
         Ok(GoOn)
     }
 
@@ -363,6 +767,7 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
         actual_answers: Vec<(String, &RRData, Ttl)>,
         tmp: &mut HashMap<String, CacheEntry>,
         now: Time,
+        dnssec: &ReplyDnssec,
     ) -> BoxResult<StepResult> {
 
 
@@ -378,12 +783,18 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
                 ce.a4 = Some(CacheEntry2 {
                     t: now,
                     a: Vec::new(),
+                    secure: dnssec.secure,
+                    dnssec_ok: dnssec.dnssec_ok,
+                    sigs: Vec::new(),
                 });
             }
             if q.qtype == AAAA || q.qtype == QTAll {
                 ce.a6 = Some(CacheEntry2 {
                     t: now,
                     a: Vec::new(),
+                    secure: dnssec.secure,
+                    dnssec_ok: dnssec.dnssec_ok,
+                    sigs: Vec::new(),
                 });
             }
         }
@@ -397,6 +808,9 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
                         ce.a4 = Some(CacheEntry2 {
                             t: now,
                             a: Vec::new(),
+                            secure: dnssec.secure,
+                            dnssec_ok: dnssec.dnssec_ok,
+                            sigs: Vec::new(),
                         });
                     }
                     let v = ce.a4.as_mut().unwrap();
@@ -410,6 +824,9 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
                         ce.a6 = Some(CacheEntry2 {
                             t: now,
                             a: Vec::new(),
+                            secure: dnssec.secure,
+                            dnssec_ok: dnssec.dnssec_ok,
+                            sigs: Vec::new(),
                         });
                     }
                     let v = ce.a6.as_mut().unwrap();
@@ -424,6 +841,38 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
                 }
             }
         }
+
+        // DNSSEC-aware clients are only answered from complete sets with their signatures.
+        // Negative answers (denial proofs are not kept), ones reached via CNAME and filtered
+        // ones are left to upstream.
+        for (dom, ce) in tmp.iter_mut() {
+            for (ce2, covered) in [(ce.a4.as_mut(), 0x0001), (ce.a6.as_mut(), 0x001C)] {
+                let ce2 = match ce2 {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let received = p.answers.iter().filter(|ans| {
+                    ans.name.to_string() == *dom && match ans.data {
+                        RRData::A(_) => covered == 0x0001,
+                        RRData::AAAA(_) => covered == 0x001C,
+                        _ => false,
+                    }
+                });
+                if ce2.a.is_empty() || received.count() != ce2.a.len() {
+                    ce2.dnssec_ok = false;
+                    continue;
+                }
+                ce2.sigs.extend(
+                    dnssec.sigs
+                        .iter()
+                        .filter(|sig| wire::name_to_string(&sig.name) == *dom && wire::rrsig_type_covered(&sig.rdata) == Some(covered))
+                        .map(|sig| Rrsig {
+                            ttl: sig.ttl,
+                            rdata: sig.rdata.clone(),
+                        }),
+                );
+            }
+        }
         Ok(GoOn)
     }
 
@@ -431,12 +880,24 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
     fn save_entries_to_database(
         &mut self,
         tmp: &mut HashMap<String, CacheEntry>,
+        tampered: &[String],
+        now: Time,
     ) -> BoxResult<StepResult> {
 
+        // Suspected tampering is like a failed lookup: nothing is saved, and names with nothing
+        // cached are left unanswered
+        let db = &mut self.db;
+        tmp.retain(|dom, _| !tampered.contains(dom) || get_entry(db, dom).is_some());
+
+        let mut batch = Vec::with_capacity(tmp.len());
         for (dom, mut entry) in tmp {
+            if tampered.contains(dom) {
+                info!("  not saved: {}", dom);
+                continue;
+            }
 
             let cached: CacheEntry;
-            if let Some(ce) = self.db.get(dom)? {
+            if let Some(ce) = get_entry(&mut self.db, dom) {
                 cached = ce;
             } else {
                 cached = Default::default();
@@ -458,6 +919,7 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
                 if let Some(CacheEntry2 { a: ref cached_a4, .. }) = cached.a4 {
                     if new_a4.is_empty() && !cached_a4.is_empty() {
                         info!("  refusing to forget A entries");
+                        Metrics::inc(&self.metrics.refused_to_forget);
                         use_cached_a4 = true;
                     }
                 }
@@ -466,21 +928,34 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
                 if let Some(CacheEntry2 { a: ref cached_a6, .. }) = cached.a6 {
                     if new_a6.is_empty() && !cached_a6.is_empty() {
                         info!("  refusing to forget AAAA entries");
+                        Metrics::inc(&self.metrics.refused_to_forget);
                         use_cached_a6 = true;
                     }
                 }
             }
 
+            for ce2 in cached.a4.iter().chain(cached.a6.iter()) {
+                self.reverse.remove(dom, ce2);
+            }
+
             if use_cached_a4 {
                 entry.a4 = cached.a4;
             }
             if use_cached_a6 {
                 entry.a6 = cached.a6;
             }
+            if entry.u.is_none() {
+                entry.u = cached.u;
+            }
+
+            for ce2 in entry.a4.iter().chain(entry.a6.iter()) {
+                self.reverse.add(dom, ce2, now, &self.opts);
+            }
 
-            self.db.put(dom.as_str(), entry)?;
+            batch.push(BatchOp::Put(dom.clone(), entry.clone()));
             info!("  saved to database: {}", dom);
         }
+        self.db.write_batch(batch)?;
         self.db.flush()?;
         Ok(GoOn)
     }
@@ -491,8 +966,11 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
         &mut self,
         tmp: HashMap<String, CacheEntry>,
         now: Time,
+        reply_id: u16,
+        dnssec: &ReplyDnssec,
     ) -> BoxResult<StepResult> {
 
+        let mut need_a = Vec::new();
         for (dom, _) in tmp {
             let subs = self.dom_update_subscriptions.remove(&dom).unwrap();
             let mut unhappy = Vec::new();
@@ -501,16 +979,33 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
                 use self::TryAnswerRequestResult::*;
                 if let Some(r) = self.unreplied_requests.get(sub_id) {
                     let dummy_request = r.inhibit_send;
+                    // This very reply answers the query the request sent
+                    let own = r.id == reply_id;
+                    let upstream_ad = own && dnssec.upstream_ad && self.opts.trust_upstream_ad;
                     let result = try_answer_request(
                         &mut self.db,
+                        &self.local,
                         now,
                         &self.net,
                         r,
-                        self.opts.max_ttl,
-                        self.opts.min_ttl,
+                        &self.opts,
+                        &mut self.order,
+                        r.dnssec_ok,
+                        upstream_ad,
                     )?;
+                    let result = match result {
+                        UnknownsRemain(_) if own && r.dnssec_ok && !dummy_request => {
+                            // Negative or CNAME answer: the cache has no proofs or signatures for it
+                            let reply = send_raw_reply(&self.net, r, &dnssec.raw, dnssec.secure || upstream_ad)?;
+                            Resolved(AdjustTtlResult::Ok, Some(reply))
+                        }
+                        x => x,
+                    };
+                    if let Resolved(_, Some(ref reply)) = result {
+                        tap_client_response(&mut self.taps, r, reply, Outcome::Queued);
+                    }
                     match result {
-                        Resolved(AdjustTtlResult::Ok) => {
+                        Resolved(AdjustTtlResult::Ok, _) => {
                             if !dummy_request {
                                 info!("  replied.");
                             } else {
@@ -518,7 +1013,7 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
                             }
                             happy.push(sub_id);
                         }
-                        Resolved(AdjustTtlResult::Expired) => {
+                        Resolved(AdjustTtlResult::Expired, _) => {
                             if !dummy_request {
                                 info!("  replied?");
                                 happy.push(sub_id);
@@ -526,11 +1021,14 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
                                 unhappy.push(sub_id);
                             }
                         }
-                        Resolved(AdjustTtlResult::Negative(_)) => {
+                        Resolved(AdjustTtlResult::Negative(_), _) => {
                             info!("  replied...");
                             happy.push(sub_id);
                         }
                         UnknownsRemain(_) => {
+                            if self.opts.dns64.is_some() {
+                                need_a.extend(r.q.iter().filter(|q| q.a6).map(|q| q.dom.clone()));
+                            }
                             unhappy.push(sub_id);
                         }
                     }
@@ -547,6 +1045,7 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
                 );
             }
         }
+        self.dns64_fetch_a(need_a, now)?;
         Ok(GoOn)
     }
 
@@ -555,7 +1054,23 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
 
 
     fn packet_from_client(&mut self, src: N::ClientId, buf: &[u8]) -> BoxResult<()> {
-        let p = Packet::parse(buf)?;
+        let received = SystemTime::now();
+        for t in &mut self.taps {
+            t.client_query(src, buf, received);
+        }
+        if !self.ratelimit_query(src, received, buf) {
+            return Ok(());
+        }
+        let p = match Packet::parse(buf) {
+            Ok(p) => p,
+            Err(e) => {
+                self.tap_dropped(src, received, buf);
+                Err(e)?
+            }
+        };
+        if !self.ratelimit_response(src, received, buf, &p)? {
+            return Ok(());
+        }
         let mut weird_querty = false;
 
         let mut simplified_questions = Vec::with_capacity(1);
@@ -580,7 +1095,7 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
             }
 
             let dom = q.qname.to_string();
-            print!("{:?}\t{}", q.qtype, dom);
+            info!("{:?}\t{}", q.qtype, dom);
             let sq = SimplifiedQuestion {
                 dom,
                 a4: q.qtype == A || q.qtype == QTAll,
@@ -589,73 +1104,222 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
             simplified_questions.push(sq);
         }
 
+        // Policy answers and PTR answers from the reverse index are made here,
+        // so they fall under the ACL for cached answers even for direct query types
+        let rpz = self.rpz_qname_policy(&simplified_questions);
+        let blocklist = match rpz {
+            None => self.find_blocklist(&simplified_questions),
+            Some(_) => None,
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let policy = blocklist.is_some() || rpz.as_ref().is_some_and(|x| !x.1.is_passthru());
+        let ptr = if policy { None } else { self.ptr_answer(&p, now)? };
+
+        let allowed = if weird_querty && !policy && ptr.is_none() {
+            self.acl_allows(src, |a| &a.direct)
+        } else {
+            self.acl_allows(src, |a| &a.cached)
+        };
+        if !allowed {
+            return self.reply_denied(src, received, buf);
+        }
+
+        match rpz {
+            Some((zone, RpzAction::Passthru)) => info!("  passthru by RPZ {}", self.rpz[zone].name()),
+            Some((zone, action)) => {
+                return self.reply_rpz(src, received, buf, simplified_questions, zone, &action)
+            }
+            None => {
+                if let Some(list) = blocklist {
+                    return self.reply_blocked(src, received, buf, list);
+                }
+            }
+        }
+
+        if let Some(names) = ptr {
+            let max_size = p.opt.as_ref().map_or(512, |o| usize::from(o.udp).max(512));
+            return self.reply_ptr(src, received, buf, &names, max_size);
+        }
+
+        // A packet goes to one upstream, so queries with several questions (which hardly
+        // exist in practice) are routed by the first one
+        let upstream = simplified_questions.first().map_or(0, |q| self.upstream_for(&q.dom));
+
         if weird_querty {
             info!("  direct");
-            self.r2a.insert(p.header.id, src);
-            self.net.send_to_upstream(buf)?;
+            Metrics::inc(&self.metrics.direct);
+            self.r2a.insert(p.header.id, (src, received, upstream));
+            self.send_upstream(p.header.id, buf, upstream)?;
             return Ok(());
         }
 
-        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-
+        let dnssec_ok = p.opt.as_ref().is_some_and(|o| o.flags & 0x8000 != 0);
         let mut r = SimplifiedRequest {
             id: p.header.id,
             q: simplified_questions,
-            clientid: src,
+            clientid: Some(src),
             inhibit_send: false,
+            received,
+            upstream,
+            want_ad: p.header.authenticated_data || dnssec_ok,
+            dnssec_ok,
+            checking_disabled: p.header.checking_disabled,
+            query: if self.taps.is_empty() { vec![] } else { buf.to_vec() },
         };
 
         use self::TryAnswerRequestResult::*;
         let result = try_answer_request(
             &mut self.db,
+            &self.local,
             now,
             &self.net,
             &r,
-            self.opts.max_ttl,
-            self.opts.min_ttl,
+            &self.opts,
+            &mut self.order,
+            dnssec_ok,
+            false,
         )?;
 
+        if let Resolved(ref status, ref reply) = result {
+            self.touch_entries(&r, now)?;
+            let outcome = match *status {
+                AdjustTtlResult::Ok => Outcome::Cached,
+                AdjustTtlResult::Negative(x) if x < self.opts.neg_ttl => Outcome::Negative,
+                _ => Outcome::Stale,
+            };
+            if let Some(ref reply) = *reply {
+                tap_client_response(&mut self.taps, &r, reply, outcome);
+            }
+        }
+
+        let upstream_allowed = self.acl_allows(src, |a| &a.upstream);
+
         match result {
-            Resolved(AdjustTtlResult::Ok) => {
+            Resolved(AdjustTtlResult::Ok, _) => {
                 info!("  cached");
+                Metrics::inc(&self.metrics.cached);
                 return Ok(());
             }
-            Resolved(AdjustTtlResult::Expired) => {
+            Resolved(AdjustTtlResult::Expired, _) => {
                 info!("  cached, but refreshing");
+                Metrics::inc(&self.metrics.refreshing);
                 r.inhibit_send = true;
             }
-            Resolved(AdjustTtlResult::Negative(x)) => {
+            Resolved(AdjustTtlResult::Negative(x), _) => {
                 if x >= self.opts.neg_ttl {
                     info!("  cached, negative {}, refreshing", x);
+                    Metrics::inc(&self.metrics.refreshing);
                     r.inhibit_send = true;
                 } else {
                     info!("  cached, negative {}.", x);
+                    Metrics::inc(&self.metrics.negative);
                     return Ok(());
                 }
             }
             UnknownsRemain(_) => {
+                if !upstream_allowed {
+                    return self.reply_denied(src, received, buf);
+                }
                 info!("  queued");
+                Metrics::inc(&self.metrics.queued);
             }
         }
 
+        if !upstream_allowed {
+            info!("  not refreshing: client may not query upstream");
+            return Ok(());
+        }
+
+        let ask_dnssec_ok = r.dnssec_ok || self.cached_with_dnssec_ok(&r.q);
         let id = self.unreplied_requests.insert(r);
         let r = self.unreplied_requests.get(id).unwrap();
 
+        // Targets of local CNAMEs are asked on their own, other questions by sending the query on
+        let mut targets = Vec::new();
         for q in &r.q {
-            self.dom_update_subscriptions.insert(q.dom.clone(), id);
+            match self.local.resolve(&q.dom) {
+                Some(la) if la.addrs.is_none() => targets.push((la.owner, q.a4, q.a6)),
+                _ => self.dom_update_subscriptions.insert(q.dom.clone(), id),
+            }
         }
-        // Send to upstream as is.
-        self.net.send_to_upstream(buf)?;
+        let forward = targets.len() < r.q.len();
+        let need_a = match self.opts.dns64 {
+            Some(_) => r.q.iter().filter(|q| q.a6).map(|q| q.dom.clone()).collect(),
+            None => vec![],
+        };
+        for (target, a4, a6) in targets {
+            self.fetch_local_target(id, target, a4, a6)?;
+        }
+        if forward {
+            // Send to upstream as is, unless DNSSEC records are needed
+            let query = self.upstream_query(buf, ask_dnssec_ok)?;
+            self.send_upstream(p.header.id, &query, upstream)?;
+        }
+        self.dns64_fetch_a(need_a, now)?;
         Ok(())
     }
 
+    /// Query for A or AAAA to send upstream: with DNSSEC records requested when validating
+    /// or when `dnssec_ok` is set
+    pub(crate) fn upstream_query<'a>(&self, buf: &'a [u8], dnssec_ok: bool) -> BoxResult<Cow<'a, [u8]>> {
+        #[cfg(feature = "dnssec")]
+        {
+            if self.opts.dnssec.is_some() {
+                return Ok(Cow::Owned(wire::with_dnssec_ok(buf, wire::FLAG_CD)?));
+            }
+        }
+        if dnssec_ok {
+            return Ok(Cow::Owned(wire::with_dnssec_ok(buf, 0)?));
+        }
+        Ok(Cow::Borrowed(buf))
+    }
+
+    /// Whether some of the cached entries for the questions were asked with DNSSEC OK bit,
+    /// so their refreshes should be too
+    pub(crate) fn cached_with_dnssec_ok(&mut self, q: &[SimplifiedQuestion]) -> bool {
+        q.iter().any(|q| {
+            get_entry(&mut self.db, &q.dom).is_some_and(|ce| ce.a4.iter().chain(ce.a6.iter()).any(|x| x.dnssec_ok))
+        })
+    }
+
+    /// Remove requests subscribed to the domain, also from subscriptions for their other questions
+    pub(crate) fn take_subscribed_requests(&mut self, dom: &str) -> Vec<SimplifiedRequest<N::ClientId>> {
+        let subs = match self.dom_update_subscriptions.remove(dom) {
+            Some(x) => x,
+            None => return vec![],
+        };
+        let mut requests = Vec::with_capacity(subs.len());
+        for id in subs {
+            let r = match self.unreplied_requests.remove(id) {
+                Some(r) => r,
+                None => continue,
+            };
+            for q2 in &r.q {
+                if let Some(v) = self.dom_update_subscriptions.get_vec_mut(&q2.dom) {
+                    v.retain(|x| *x != id);
+                }
+                if self.dom_update_subscriptions.get_vec(&q2.dom).is_some_and(|v| v.is_empty()) {
+                    self.dom_update_subscriptions.remove(&q2.dom);
+                }
+            }
+            requests.push(r);
+        }
+        requests
+    }
+
     pub(crate) fn serve1(&mut self, buf: &mut [u8]) -> BoxResult<()> {
         let (amt, src) = self.net.recv_from(buf)?;
         let buf = &buf[..amt];
-        match src {
-            ReceiveResult::FromUpstream => self.packet_from_upstream(buf)?,
-            ReceiveResult::FromClient(src) => self.packet_from_client(src, buf)?,
-        }
-        Ok(())
+        let result = match src {
+            ReceiveResult::FromUpstream => self.packet_from_upstream(buf, 0),
+            ReceiveResult::FromUpstreamN(u) => self.packet_from_upstream(buf, u),
+            ReceiveResult::FromClient(src) => self.packet_from_client(src, buf),
+            ReceiveResult::Nothing => Ok(()),
+        };
+
+        self.update_gauges();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.gc_tick(now);
+        result
     }
 }
@@ -0,0 +1,144 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! DNS64 (RFC 6147): AAAA records made up from A records for IPv6-only clients behind NAT64
+
+use super::*;
+
+use details::{adjust_ttl, get_entry, AdjustTtlResult};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Well-Known Prefix from RFC 6052
+const WELL_KNOWN_PREFIX: Ipv6Addr = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0);
+
+/// How AAAA records get synthesized
+#[derive(Debug, Clone)]
+pub struct Dns64 {
+    /// /96 prefix the IPv4 address is appended to
+    pub prefix: Ipv6Addr,
+    /// A records in these networks are not synthesized from
+    pub exclude_a: Vec<(Ipv4Addr, u8)>,
+    /// AAAA records in these networks do not count, names with only such addresses get synthesized ones
+    pub exclude_aaaa: Vec<(Ipv6Addr, u8)>,
+}
+
+impl Default for Dns64 {
+    fn default() -> Self {
+        Dns64::with_prefix(WELL_KNOWN_PREFIX)
+    }
+}
+
+impl Dns64 {
+    /// IPv4-mapped AAAA records are ignored (RFC 6147 section 5.1.4) and local-only IPv4
+    /// addresses are not synthesized from. The Well-Known Prefix additionally must not be used
+    /// with private IPv4 addresses (RFC 6052 section 3.1).
+    pub fn with_prefix(prefix: Ipv6Addr) -> Self {
+        let mut exclude_a = vec![
+            (Ipv4Addr::new(0, 0, 0, 0), 8),
+            (Ipv4Addr::new(127, 0, 0, 0), 8),
+            (Ipv4Addr::new(169, 254, 0, 0), 16),
+            (Ipv4Addr::new(255, 255, 255, 255), 32),
+        ];
+        if prefix == WELL_KNOWN_PREFIX {
+            exclude_a.extend_from_slice(&[
+                (Ipv4Addr::new(10, 0, 0, 0), 8),
+                (Ipv4Addr::new(100, 64, 0, 0), 10),
+                (Ipv4Addr::new(172, 16, 0, 0), 12),
+                (Ipv4Addr::new(192, 168, 0, 0), 16),
+            ]);
+        }
+        Dns64 {
+            prefix,
+            exclude_a,
+            exclude_aaaa: vec![(Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0), 96)],
+        }
+    }
+
+    /// Whether there is some AAAA record outside of excluded ranges
+    pub fn has_usable_aaaa(&self, a6: &[AddrTtl]) -> bool {
+        a6.iter().any(|x| match x.ip_addr() {
+            Some(ip) => !self.exclude_aaaa.iter().any(|&(net, prefix)| in_network(ip, IpAddr::V6(net), prefix)),
+            None => false,
+        })
+    }
+
+    /// AAAA records for the non-excluded A records, with the same TTLs
+    pub fn synthesize(&self, a4: &[AddrTtl]) -> Vec<AddrTtl> {
+        a4.iter()
+            .filter(|x| match x.ip_addr() {
+                Some(ip @ IpAddr::V4(_)) => {
+                    !self.exclude_a.iter().any(|&(net, prefix)| in_network(ip, IpAddr::V4(net), prefix))
+                }
+                _ => false,
+            })
+            .map(|x| {
+                let mut ip = self.prefix.octets()[..12].to_vec();
+                ip.extend_from_slice(&x.ip);
+                AddrTtl { ip, ttl: x.ttl }
+            })
+            .collect()
+    }
+
+    /// AAAA answer from the cache entry: real records if there are usable ones, otherwise
+    /// synthesized from A records. `None` if A records are needed, but not known yet.
+    /// Answer is stale if either of the entries it was made from is stale.
+    pub(crate) fn answer(
+        &self,
+        a4: Option<&CacheEntry2>,
+        a6: &CacheEntry2,
+        now: Time,
+        opts: &Options,
+    ) -> Option<(AdjustTtlResult, Vec<AddrTtl>)> {
+        if self.has_usable_aaaa(&a6.a) {
+            return Some(adjust_ttl(&a6.a, now, a6.t, opts.max_ttl, opts.min_ttl));
+        }
+        let a4 = a4?;
+        let (tr6, _) = adjust_ttl(&[], now, a6.t, opts.max_ttl, opts.min_ttl);
+        let (tr4, v) = adjust_ttl(&self.synthesize(&a4.a), now, a4.t, opts.max_ttl, opts.min_ttl);
+        if tr4 == AdjustTtlResult::Expired {
+            Some((tr4, v))
+        } else {
+            Some((tr6, v))
+        }
+    }
+
+    /// Whether A records should be fetched to answer AAAA query for the entry
+    pub(crate) fn needs_a(&self, ce: &CacheEntry, now: Time, opts: &Options) -> bool {
+        match (ce.a4.as_ref(), ce.a6.as_ref()) {
+            (_, None) => false,
+            (_, Some(a6)) if self.has_usable_aaaa(&a6.a) => false,
+            (None, _) => true,
+            (Some(a4), _) => {
+                adjust_ttl(&a4.a, now, a4.t, opts.max_ttl, opts.min_ttl).0 == AdjustTtlResult::Expired
+            }
+        }
+    }
+}
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Ask upstream for A records of the domains where they are missing or stale,
+    /// but needed to synthesize AAAA answers. Skips domains already being asked about.
+    pub(crate) fn dns64_fetch_a(&mut self, doms: Vec<String>, now: Time) -> BoxResult<()> {
+        for dom in doms {
+            let needed = match (self.opts.dns64.as_ref(), get_entry(&mut self.db, &dom)) {
+                (Some(dns64), Some(ce)) => dns64.needs_a(&ce, now, &self.opts),
+                _ => false,
+            };
+            if !needed || self.own_a_query_pending(&dom) {
+                continue;
+            }
+            info!("  DNS64: asking for A records of {}", dom);
+            self.send_own_query(&dom, 0x0001)?;
+        }
+        Ok(())
+    }
+
+    fn own_a_query_pending(&self, dom: &str) -> bool {
+        self.dom_update_subscriptions.get_vec(dom).is_some_and(|ids| {
+            ids.iter().any(|&id| {
+                self.unreplied_requests
+                    .get(id)
+                    .is_some_and(|r| r.clientid.is_none() && r.q.iter().any(|q| q.a4 && q.dom == dom))
+            })
+        })
+    }
+}
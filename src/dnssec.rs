@@ -0,0 +1,1255 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! DNSSEC validation: signatures, DS/DNSKEY chains from trust anchors
+//! and authenticated denial of existence (NSEC, NSEC3).
+//!
+//! Validation is pull-based: [`Validator`] says which DS or DNSKEY answers it is missing,
+//! they are fetched and added with [`Validator::add_fetched`], then validation is retried.
+
+use super::*;
+
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use std::collections::HashSet;
+use std::str::FromStr;
+use wire::*;
+
+/// How long validated keys and DS proofs are remembered at most, seconds
+const MAX_KEY_CACHE_TIME: Time = 3600;
+/// How long a bogus key chain is remembered before trying again, seconds
+const BOGUS_CACHE_TIME: Time = 60;
+/// NSEC3 with more iterations is treated as insecure (RFC 9276)
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// DS record of a trusted key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustAnchor {
+    /// Zone name, empty for the root
+    pub zone: String,
+    /// Key tag of the DNSKEY
+    pub key_tag: u16,
+    /// DNSKEY algorithm
+    pub algorithm: u8,
+    /// 1 = SHA-1, 2 = SHA-256, 4 = SHA-384
+    pub digest_type: u8,
+    /// Digest of owner name and DNSKEY data
+    pub digest: Vec<u8>,
+}
+
+impl TrustAnchor {
+    /// Root zone key signing keys KSK-2017 and KSK-2024
+    pub fn root() -> Vec<TrustAnchor> {
+        vec![
+            ". 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D".parse().unwrap(),
+            ". 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16".parse().unwrap(),
+        ]
+    }
+
+    fn ds_rdata(&self) -> Vec<u8> {
+        let mut v = vec![(self.key_tag >> 8) as u8, self.key_tag as u8, self.algorithm, self.digest_type];
+        v.extend_from_slice(&self.digest);
+        v
+    }
+}
+
+impl FromStr for TrustAnchor {
+    type Err = String;
+    /// DS record in presentation format without class and type: `zone key-tag algorithm digest-type digest`,
+    /// e.g. `. 20326 8 2 E06D44...`
+    fn from_str(s: &str) -> Result<Self, String> {
+        let w: Vec<&str> = s.split_whitespace().collect();
+        if w.len() < 5 {
+            return Err(format!("expected zone, key tag, algorithm, digest type and digest: {}", s));
+        }
+        let bad = |what: &str| format!("bad {} in trust anchor {}", what, s);
+        let hex: String = w[4..].concat();
+        if hex.len() % 2 != 0 {
+            return Err(bad("digest"));
+        }
+        let digest = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| bad("digest"))?;
+        Ok(TrustAnchor {
+            zone: w[0].trim_end_matches('.').to_ascii_lowercase(),
+            key_tag: w[1].parse().map_err(|_| bad("key tag"))?,
+            algorithm: w[2].parse().map_err(|_| bad("algorithm"))?,
+            digest_type: w[3].parse().map_err(|_| bad("digest type"))?,
+            digest,
+        })
+    }
+}
+
+/// DNSSEC validation settings
+#[derive(Debug, Clone)]
+pub struct DnssecOptions {
+    /// Keys validation starts from. Root KSKs by default.
+    pub trust_anchors: Vec<TrustAnchor>,
+}
+
+impl Default for DnssecOptions {
+    fn default() -> Self {
+        DnssecOptions {
+            trust_anchors: TrustAnchor::root(),
+        }
+    }
+}
+
+/// Result of validating an answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    /// Everything is signed by keys chaining to a trust anchor
+    Secure,
+    /// Some of the data is provably unsigned
+    Insecure,
+    /// Validation failed, with the reason
+    Bogus(String),
+}
+
+/// Either done, or DS or DNSKEY answers for these names are needed first
+#[derive(Debug)]
+pub(crate) enum Lookup<T> {
+    Done(T),
+    Need(Vec<(Name, u16)>),
+}
+use self::Lookup::*;
+
+macro_rules! pull {
+    ($e:expr) => {
+        match $e {
+            Done(x) => x,
+            Need(n) => return Need(n),
+        }
+    };
+}
+
+fn supported_algorithm(alg: u8) -> bool {
+    [8, 10, 13, 14, 15].contains(&alg)
+}
+
+fn supported_digest(dt: u8) -> bool {
+    [1, 2, 4].contains(&dt)
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Dnskey {
+    flags: u16,
+    alg: u8,
+    tag: u16,
+    key: Vec<u8>,
+}
+
+/// Key tag (RFC 4034 appendix B)
+pub(crate) fn key_tag(rdata: &[u8]) -> u16 {
+    let mut ac: u32 = 0;
+    for (i, &b) in rdata.iter().enumerate() {
+        ac += if i & 1 == 0 { u32::from(b) << 8 } else { u32::from(b) };
+    }
+    ac += (ac >> 16) & 0xFFFF;
+    ac as u16
+}
+
+fn parse_dnskey(rdata: &[u8]) -> Option<Dnskey> {
+    if rdata.len() < 5 || rdata[2] != 3 {
+        return None;
+    }
+    Some(Dnskey {
+        flags: (u16::from(rdata[0]) << 8) | u16::from(rdata[1]),
+        alg: rdata[3],
+        tag: key_tag(rdata),
+        key: rdata[4..].to_vec(),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Rrsig {
+    type_covered: u16,
+    alg: u8,
+    labels: u8,
+    orig_ttl: u32,
+    expiration: u32,
+    inception: u32,
+    key_tag: u16,
+    /// Lowercase
+    signer: Name,
+    signature: Vec<u8>,
+    /// RDATA without the signature, in canonical form
+    signed_part: Vec<u8>,
+}
+
+fn parse_rrsig(rdata: &[u8]) -> Option<Rrsig> {
+    let be32 = |o: usize| {
+        (u32::from(rdata[o]) << 24) | (u32::from(rdata[o + 1]) << 16) | (u32::from(rdata[o + 2]) << 8) | u32::from(rdata[o + 3])
+    };
+    if rdata.len() < 19 {
+        return None;
+    }
+    let signer_len = name_len(&rdata[18..])?;
+    let end = 18 + signer_len;
+    Some(Rrsig {
+        type_covered: (u16::from(rdata[0]) << 8) | u16::from(rdata[1]),
+        alg: rdata[2],
+        labels: rdata[3],
+        orig_ttl: be32(4),
+        expiration: be32(8),
+        inception: be32(12),
+        key_tag: (u16::from(rdata[16]) << 8) | u16::from(rdata[17]),
+        signer: lowercase(&rdata[18..end]),
+        signature: rdata[end..].to_vec(),
+        signed_part: canonical_rdata(TYPE_RRSIG, &rdata[..end]),
+    })
+}
+
+/// Records of the same owner, type and class, with signatures covering them
+#[derive(Debug)]
+pub(crate) struct RrSet<'a> {
+    name: Name,
+    typ: u16,
+    class: u16,
+    rrs: Vec<&'a Record>,
+    sigs: Vec<Rrsig>,
+}
+
+impl<'a> RrSet<'a> {
+    fn ttl(&self) -> Time {
+        Time::from(self.rrs.iter().map(|x| x.ttl).min().unwrap_or(0))
+    }
+}
+
+/// Group records into RRsets. OPT records are skipped, RRSIGs are attached to what they cover.
+pub(crate) fn rrsets(records: &[Record]) -> Vec<RrSet<'_>> {
+    let mut sets: Vec<RrSet> = Vec::new();
+    for rr in records.iter().filter(|rr| rr.typ != TYPE_OPT && rr.typ != TYPE_RRSIG) {
+        let name = lowercase(&rr.name);
+        match sets.iter_mut().find(|s| s.name == name && s.typ == rr.typ && s.class == rr.class) {
+            Some(s) => s.rrs.push(rr),
+            None => sets.push(RrSet {
+                name,
+                typ: rr.typ,
+                class: rr.class,
+                rrs: vec![rr],
+                sigs: vec![],
+            }),
+        }
+    }
+    for rr in records.iter().filter(|rr| rr.typ == TYPE_RRSIG) {
+        let sig = match parse_rrsig(&rr.rdata) {
+            Some(x) => x,
+            None => continue,
+        };
+        let name = lowercase(&rr.name);
+        if let Some(s) = sets.iter_mut().find(|s| s.name == name && s.typ == sig.type_covered && s.class == rr.class) {
+            s.sigs.push(sig);
+        }
+    }
+    sets
+}
+
+/// Data covered by the signature (RFC 4034 3.1.8.1)
+pub(crate) fn signed_data(set: &RrSet, sig: &Rrsig) -> Vec<u8> {
+    let mut owner = set.name.clone();
+    let nlabels = label_count(&owner);
+    if usize::from(sig.labels) < nlabels {
+        // Wildcard expansion: the signature is for `*.` and the rightmost `labels` labels
+        let mut o = &owner[..];
+        for _ in 0..nlabels - usize::from(sig.labels) {
+            o = parent(o).unwrap_or(o);
+        }
+        let mut w = vec![1, b'*'];
+        w.extend_from_slice(o);
+        owner = w;
+    }
+    let mut rdatas: Vec<Vec<u8>> = set.rrs.iter().map(|rr| canonical_rdata(rr.typ, &rr.rdata)).collect();
+    rdatas.sort();
+    rdatas.dedup();
+    let mut data = sig.signed_part.clone();
+    for rd in rdatas {
+        data.extend_from_slice(&owner);
+        data.extend_from_slice(&[(set.typ >> 8) as u8, set.typ as u8, (set.class >> 8) as u8, set.class as u8]);
+        data.extend_from_slice(&sig.orig_ttl.to_be_bytes());
+        data.extend_from_slice(&(rd.len() as u16).to_be_bytes());
+        data.extend_from_slice(&rd);
+    }
+    data
+}
+
+fn strip_zeros(b: &[u8]) -> &[u8] {
+    let n = b.iter().take_while(|&&x| x == 0).count();
+    &b[n..]
+}
+
+/// Check signature made by the DNSKEY public key of given algorithm
+fn verify_signature(alg: u8, key: &[u8], data: &[u8], sig: &[u8]) -> bool {
+    match alg {
+        8 | 10 => {
+            // RFC 3110: exponent length, exponent, modulus
+            let (elen, off) = match key.first() {
+                Some(&0) if key.len() > 3 => ((usize::from(key[1]) << 8) | usize::from(key[2]), 3),
+                Some(&l) => (usize::from(l), 1),
+                None => return false,
+            };
+            if key.len() <= off + elen {
+                return false;
+            }
+            let pk = RsaPublicKeyComponents {
+                e: strip_zeros(&key[off..off + elen]),
+                n: strip_zeros(&key[off + elen..]),
+            };
+            let params = if alg == 8 {
+                &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY
+            } else {
+                &signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY
+            };
+            pk.verify(params, data, sig).is_ok()
+        }
+        13 | 14 => {
+            let mut pk = vec![4];
+            pk.extend_from_slice(key);
+            let params = if alg == 13 {
+                &signature::ECDSA_P256_SHA256_FIXED
+            } else {
+                &signature::ECDSA_P384_SHA384_FIXED
+            };
+            UnparsedPublicKey::new(params, pk).verify(data, sig).is_ok()
+        }
+        15 => UnparsedPublicKey::new(&signature::ED25519, key).verify(data, sig).is_ok(),
+        _ => false,
+    }
+}
+
+/// Serial number arithmetic comparison `a <= b` (RFC 1982)
+fn serial_le(a: u32, b: u32) -> bool {
+    b.wrapping_sub(a) < 0x8000_0000
+}
+
+/// Whether some signature of the set is valid now and made by one of the keys
+fn rrset_verified_by(set: &RrSet, signer: &[u8], keys: &[Dnskey], now: Time) -> bool {
+    let now = now as u32;
+    set.sigs.iter().any(|sig| {
+        sig.signer == signer
+            && usize::from(sig.labels) <= label_count(&set.name)
+            && serial_le(sig.inception, now)
+            && serial_le(now, sig.expiration)
+            && keys.iter().any(|k| {
+                k.tag == sig.key_tag
+                    && k.alg == sig.alg
+                    && k.flags & 0x0100 != 0
+                    && verify_signature(k.alg, &k.key, &signed_data(set, sig), &sig.signature)
+            })
+    })
+}
+
+/// DS digest of the key
+fn ds_digest(owner: &[u8], dnskey_rdata: &[u8], digest_type: u8) -> Option<Vec<u8>> {
+    let alg = match digest_type {
+        1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        2 => &digest::SHA256,
+        4 => &digest::SHA384,
+        _ => return None,
+    };
+    let mut ctx = digest::Context::new(alg);
+    ctx.update(&lowercase(owner));
+    ctx.update(dnskey_rdata);
+    Some(ctx.finish().as_ref().to_vec())
+}
+
+/// Whether the type bitmap of NSEC or NSEC3 has the type
+fn bitmap_has(mut bitmap: &[u8], typ: u16) -> bool {
+    let (window, bit) = ((typ >> 8) as u8, (typ & 0xFF) as usize);
+    while bitmap.len() >= 2 {
+        let (w, len) = (bitmap[0], bitmap[1] as usize);
+        if bitmap.len() < 2 + len {
+            return false;
+        }
+        if w == window {
+            return bit / 8 < len && bitmap[2 + bit / 8] & (0x80 >> (bit % 8)) != 0;
+        }
+        bitmap = &bitmap[2 + len..];
+    }
+    false
+}
+
+struct Nsec {
+    owner: Name,
+    next: Name,
+    bitmap: Vec<u8>,
+}
+
+impl Nsec {
+    fn parse(owner: &[u8], rdata: &[u8]) -> Option<Nsec> {
+        let l = name_len(rdata)?;
+        Some(Nsec {
+            owner: lowercase(owner),
+            next: lowercase(&rdata[..l]),
+            bitmap: rdata[l..].to_vec(),
+        })
+    }
+
+    fn matches(&self, name: &[u8]) -> bool {
+        self.owner == lowercase(name)
+    }
+
+    /// Name is between owner and next, so it does not exist
+    fn covers(&self, name: &[u8]) -> bool {
+        use std::cmp::Ordering::*;
+        let after_owner = canonical_cmp(&self.owner, name) == Less;
+        let before_next = canonical_cmp(name, &self.next) == Less;
+        if canonical_cmp(&self.owner, &self.next) == Less {
+            after_owner && before_next
+        } else {
+            // The last NSEC of the zone points back to the apex
+            after_owner || before_next
+        }
+    }
+}
+
+struct Nsec3 {
+    hash: Vec<u8>,
+    opt_out: bool,
+    iterations: u16,
+    salt: Vec<u8>,
+    next: Vec<u8>,
+    bitmap: Vec<u8>,
+}
+
+fn base32hex_decode(s: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut acc, mut bits) = (0u32, 0);
+    for &c in s {
+        let v = match c.to_ascii_lowercase() {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'v' => c.to_ascii_lowercase() - b'a' + 10,
+            _ => return None,
+        };
+        acc = (acc << 5) | u32::from(v);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn nsec3_hash(name: &[u8], salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut h = lowercase(name);
+    for _ in 0..=iterations {
+        let mut ctx = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        ctx.update(&h);
+        ctx.update(salt);
+        h = ctx.finish().as_ref().to_vec();
+    }
+    h
+}
+
+impl Nsec3 {
+    fn parse(owner: &[u8], rdata: &[u8]) -> Option<Nsec3> {
+        if rdata.len() < 5 || rdata[0] != 1 {
+            return None;
+        }
+        let first = labels(owner).next()?;
+        let salt_len = usize::from(rdata[4]);
+        let hash_pos = 5 + salt_len;
+        let hash_len = usize::from(*rdata.get(hash_pos)?);
+        let bitmap_pos = hash_pos + 1 + hash_len;
+        if rdata.len() < bitmap_pos {
+            return None;
+        }
+        Some(Nsec3 {
+            hash: base32hex_decode(first)?,
+            opt_out: rdata[1] & 1 != 0,
+            iterations: (u16::from(rdata[2]) << 8) | u16::from(rdata[3]),
+            salt: rdata[5..hash_pos].to_vec(),
+            next: rdata[hash_pos + 1..bitmap_pos].to_vec(),
+            bitmap: rdata[bitmap_pos..].to_vec(),
+        })
+    }
+
+    fn hash_of(&self, name: &[u8]) -> Vec<u8> {
+        nsec3_hash(name, &self.salt, self.iterations)
+    }
+
+    fn matches(&self, name: &[u8]) -> bool {
+        self.hash_of(name) == self.hash
+    }
+
+    fn covers(&self, name: &[u8]) -> bool {
+        let h = self.hash_of(name);
+        if self.hash < self.next {
+            self.hash < h && h < self.next
+        } else {
+            self.hash < h || h < self.next
+        }
+    }
+}
+
+/// What validated NSEC or NSEC3 records prove about a name
+#[derive(Debug, PartialEq, Eq)]
+enum Denial {
+    /// The name exists, with these types absent: (has NS, has SOA, has the asked type, has CNAME)
+    Exists { ns: bool, soa: bool, typ: bool, cname: bool },
+    /// The name does not exist, and no wildcard could have matched
+    NxDomain,
+    /// Covered by an opt-out NSEC3 span: unsigned delegations may be there
+    OptOut,
+    /// Nothing provable
+    Unknown,
+}
+
+/// Evaluate denial of existence records (already validated) for the name and type
+fn denial(nsecs: &[Nsec], nsec3s: &[Nsec3], name: &[u8], typ: u16) -> Denial {
+    let exists = |bitmap: &[u8]| Denial::Exists {
+        ns: bitmap_has(bitmap, 2),
+        soa: bitmap_has(bitmap, TYPE_SOA),
+        typ: bitmap_has(bitmap, typ),
+        cname: bitmap_has(bitmap, TYPE_CNAME),
+    };
+    if let Some(n) = nsecs.iter().find(|n| n.matches(name)) {
+        return exists(&n.bitmap);
+    }
+    if let Some(n) = nsec3s.iter().find(|n| n.matches(name)) {
+        return exists(&n.bitmap);
+    }
+    if nsecs.iter().any(|n| n.covers(name)) {
+        // Closest encloser is the longest ancestor of the name shared with some NSEC owner or next name;
+        // the wildcard below it must not exist either.
+        let wildcard_denied = ancestors(name).skip(1).any(|ce| {
+            let ce_known = nsecs.iter().any(|n| is_under(&n.owner, ce) || is_under(&n.next, ce));
+            let mut wc = vec![1, b'*'];
+            wc.extend_from_slice(ce);
+            ce_known && nsecs.iter().any(|n| n.covers(&wc))
+        });
+        if wildcard_denied {
+            return Denial::NxDomain;
+        }
+        return Denial::Unknown;
+    }
+    // NSEC3 closest encloser proof (RFC 5155 8.4)
+    let mut next_closer: Option<&[u8]> = None;
+    for ce in ancestors(name) {
+        if nsec3s.iter().any(|n| n.matches(ce)) {
+            let nc = match next_closer {
+                Some(x) => x,
+                None => return Denial::Unknown,
+            };
+            return match nsec3s.iter().find(|n| n.covers(nc)) {
+                Some(n) if n.opt_out => Denial::OptOut,
+                Some(_) => {
+                    let mut wc = vec![1, b'*'];
+                    wc.extend_from_slice(ce);
+                    if nsec3s.iter().any(|n| n.covers(&wc)) {
+                        Denial::NxDomain
+                    } else {
+                        Denial::Unknown
+                    }
+                }
+                None => Denial::Unknown,
+            };
+        }
+        next_closer = Some(ce);
+    }
+    Denial::Unknown
+}
+
+/// Whether some of the NSEC3 records use too many iterations to bother
+fn nsec3_too_expensive(nsec3s: &[Nsec3]) -> bool {
+    nsec3s.iter().any(|n| n.iterations > MAX_NSEC3_ITERATIONS)
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum ZoneKeys {
+    Secure(Vec<Dnskey>),
+    Insecure,
+    Bogus(String),
+}
+
+#[derive(Debug, Clone)]
+enum DsState {
+    Secure(Vec<Vec<u8>>),
+    /// Provably no DS. `cut` tells whether the name is a delegation (then it is an insecure zone)
+    /// or just a name inside the parent zone.
+    NoDs { cut: bool },
+    /// Parent zone is insecure itself
+    Insecure,
+    Bogus(String),
+}
+
+/// Validated keys and DS proofs, and DS or DNSKEY answers waiting to be examined
+#[derive(Default)]
+pub(crate) struct Validator {
+    /// Fetched DS and DNSKEY answers with the time they arrived
+    fetched: HashMap<(Name, u16), (Message, Time)>,
+    keys: HashMap<Name, (ZoneKeys, Time)>,
+    ds: HashMap<Name, (DsState, Time)>,
+    /// Names whose DS is being validated right now, to catch loops
+    ds_pending: HashSet<Name>,
+}
+
+/// Validated NSEC and NSEC3 records of a message section, or a reason they are not usable
+enum Proofs {
+    Valid(Vec<Nsec>, Vec<Nsec3>),
+    Insecure,
+    Bogus(String),
+}
+
+impl Validator {
+    /// Remember DS or DNSKEY answer fetched for validation
+    pub fn add_fetched(&mut self, name: &[u8], qtype: u16, m: Message, now: Time) {
+        self.fetched.insert((lowercase(name), qtype), (m, now));
+    }
+
+    /// Forget expired keys and proofs
+    pub fn prune(&mut self, now: Time) {
+        self.keys.retain(|_, x| x.1 > now);
+        self.ds.retain(|_, x| x.1 > now);
+        self.fetched.retain(|_, x| x.1 + BOGUS_CACHE_TIME > now);
+    }
+
+    fn take_fetched(&mut self, name: &[u8], qtype: u16) -> Lookup<Message> {
+        match self.fetched.get(&(name.to_vec(), qtype)) {
+            Some(x) => Done(x.0.clone()),
+            None => Need(vec![(name.to_vec(), qtype)]),
+        }
+    }
+
+    /// Keys of the zone (lowercase name), validated up to a trust anchor
+    pub fn zone_keys(&mut self, zone: &[u8], anchors: &[TrustAnchor], now: Time) -> Lookup<ZoneKeys> {
+        if let Some(&(ref k, exp)) = self.keys.get(zone) {
+            if exp > now {
+                return Done(k.clone());
+            }
+        }
+        let zone_str = name_to_string(zone);
+        let anchored: Vec<Vec<u8>> = anchors.iter().filter(|a| name_from_str(&a.zone) == zone).map(|a| a.ds_rdata()).collect();
+        let ds = if !anchored.is_empty() {
+            anchored
+        } else {
+            match pull!(self.ds_state(zone, anchors, now)) {
+                DsState::Secure(ds) => ds,
+                DsState::NoDs { cut: true } | DsState::Insecure => {
+                    return Done(self.remember_keys(zone, ZoneKeys::Insecure, now + MAX_KEY_CACHE_TIME));
+                }
+                DsState::NoDs { cut: false } => {
+                    // Not a zone apex: keys are those of the enclosing zone
+                    return match parent(zone) {
+                        Some(p) => self.zone_keys(p, anchors, now),
+                        None => Done(ZoneKeys::Bogus("no trust anchor for the root".to_string())),
+                    };
+                }
+                DsState::Bogus(e) => {
+                    return Done(self.remember_keys(zone, ZoneKeys::Bogus(e), now + BOGUS_CACHE_TIME));
+                }
+            }
+        };
+        let usable: Vec<&Vec<u8>> = ds
+            .iter()
+            .filter(|d| d.len() > 4 && supported_algorithm(d[2]) && supported_digest(d[3]))
+            .collect();
+        if usable.is_empty() {
+            info!("  DNSSEC: no supported algorithms for {}, treating as insecure", zone_str);
+            return Done(self.remember_keys(zone, ZoneKeys::Insecure, now + MAX_KEY_CACHE_TIME));
+        }
+
+        let m = pull!(self.take_fetched(zone, TYPE_DNSKEY));
+        let sets = rrsets(&m.answers);
+        let set = match sets.iter().find(|s| s.name == zone && s.typ == TYPE_DNSKEY) {
+            Some(x) => x,
+            None => {
+                let e = format!("no DNSKEY for {}", zone_str);
+                return Done(self.remember_keys(zone, ZoneKeys::Bogus(e), now + BOGUS_CACHE_TIME));
+            }
+        };
+        let keys: Vec<Dnskey> = set.rrs.iter().filter_map(|rr| parse_dnskey(&rr.rdata)).collect();
+        let trusted: Vec<Dnskey> = set
+            .rrs
+            .iter()
+            .filter(|rr| {
+                usable.iter().any(|d| {
+                    let tag = (u16::from(d[0]) << 8) | u16::from(d[1]);
+                    tag == key_tag(&rr.rdata)
+                        && d[2] == rr.rdata.get(3).cloned().unwrap_or(0)
+                        && ds_digest(zone, &rr.rdata, d[3]).as_ref().map(|x| &x[..]) == Some(&d[4..])
+                })
+            })
+            .filter_map(|rr| parse_dnskey(&rr.rdata))
+            .collect();
+        let result = if trusted.is_empty() {
+            ZoneKeys::Bogus(format!("no DNSKEY of {} matches DS", zone_str))
+        } else if rrset_verified_by(set, zone, &trusted, now) {
+            ZoneKeys::Secure(keys)
+        } else {
+            ZoneKeys::Bogus(format!("DNSKEY set of {} is not signed by a key matching DS", zone_str))
+        };
+        let exp = match result {
+            ZoneKeys::Bogus(_) => now + BOGUS_CACHE_TIME,
+            _ => now + set.ttl().min(MAX_KEY_CACHE_TIME),
+        };
+        Done(self.remember_keys(zone, result, exp))
+    }
+
+    fn remember_keys(&mut self, zone: &[u8], k: ZoneKeys, exp: Time) -> ZoneKeys {
+        if let ZoneKeys::Bogus(ref e) = k {
+            warn!("  DNSSEC: {}", e);
+        }
+        self.keys.insert(zone.to_vec(), (k.clone(), exp));
+        k
+    }
+
+    fn remember_ds(&mut self, name: &[u8], d: DsState, exp: Time) -> DsState {
+        self.ds.insert(name.to_vec(), (d.clone(), exp));
+        d
+    }
+
+    /// Validate SOA, NSEC and NSEC3 records of the section. For a denial of DS of `ds_of`,
+    /// the records must be signed by a zone above it.
+    fn proofs(&mut self, records: &[Record], ds_of: Option<&[u8]>, anchors: &[TrustAnchor], now: Time) -> Lookup<Proofs> {
+        let sets = rrsets(records);
+        let mut nsecs = vec![];
+        let mut nsec3s = vec![];
+        for set in sets.iter().filter(|s| s.typ == TYPE_NSEC || s.typ == TYPE_NSEC3 || s.typ == TYPE_SOA) {
+            match pull!(self.rrset_status(set, ds_of, anchors, now)) {
+                Verdict::Secure => {}
+                Verdict::Insecure => return Done(Proofs::Insecure),
+                Verdict::Bogus(e) => return Done(Proofs::Bogus(e)),
+            }
+            for rr in &set.rrs {
+                match set.typ {
+                    TYPE_NSEC => nsecs.extend(Nsec::parse(&rr.name, &rr.rdata)),
+                    TYPE_NSEC3 => nsec3s.extend(Nsec3::parse(&rr.name, &rr.rdata)),
+                    _ => {}
+                }
+            }
+        }
+        if nsec3_too_expensive(&nsec3s) {
+            return Done(Proofs::Insecure);
+        }
+        Done(Proofs::Valid(nsecs, nsec3s))
+    }
+
+    /// Validated answer about DS of the name (lowercase, not covered by a trust anchor)
+    fn ds_state(&mut self, name: &[u8], anchors: &[TrustAnchor], now: Time) -> Lookup<DsState> {
+        if let Some(&(ref d, exp)) = self.ds.get(name) {
+            if exp > now {
+                return Done(d.clone());
+            }
+        }
+        if !self.ds_pending.insert(name.to_vec()) {
+            return Done(DsState::Bogus(format!("DS of {} depends on itself", name_to_string(name))));
+        }
+        let result = self.ds_state_uncached(name, anchors, now);
+        self.ds_pending.remove(name);
+        result
+    }
+
+    fn ds_state_uncached(&mut self, name: &[u8], anchors: &[TrustAnchor], now: Time) -> Lookup<DsState> {
+        let name_str = name_to_string(name);
+        let parent_name = match parent(name) {
+            Some(p) => p.to_vec(),
+            None => return Done(DsState::Bogus("DS of the root".to_string())),
+        };
+        let m = pull!(self.take_fetched(name, TYPE_DS));
+        let sets = rrsets(&m.answers);
+        if let Some(set) = sets.iter().find(|s| s.name == name && s.typ == TYPE_DS) {
+            let result = match pull!(self.rrset_status(set, Some(name), anchors, now)) {
+                Verdict::Secure => DsState::Secure(set.rrs.iter().map(|rr| rr.rdata.clone()).collect()),
+                Verdict::Insecure => DsState::Insecure,
+                Verdict::Bogus(e) => DsState::Bogus(e),
+            };
+            let exp = match result {
+                DsState::Bogus(_) => now + BOGUS_CACHE_TIME,
+                _ => now + set.ttl().min(MAX_KEY_CACHE_TIME),
+            };
+            return Done(self.remember_ds(name, result, exp));
+        }
+
+        // No DS: the denial must be signed, unless the parent zone is insecure
+        let signed = m.authority.iter().any(|rr| rr.typ == TYPE_RRSIG);
+        let result = if !signed {
+            let apex = m
+                .authority
+                .iter()
+                .find(|rr| rr.typ == TYPE_SOA)
+                .map_or(parent_name, |rr| lowercase(&rr.name));
+            if !is_under(name, &apex) || apex == name {
+                DsState::Bogus(format!("unsigned answer about DS of {}", name_str))
+            } else {
+                match pull!(self.zone_keys(&apex, anchors, now)) {
+                    ZoneKeys::Insecure => DsState::Insecure,
+                    ZoneKeys::Bogus(e) => DsState::Bogus(e),
+                    ZoneKeys::Secure(_) => DsState::Bogus(format!("unsigned denial of DS of {} in a signed zone", name_str)),
+                }
+            }
+        } else {
+            match pull!(self.proofs(&m.authority, Some(name), anchors, now)) {
+                Proofs::Insecure => DsState::Insecure,
+                Proofs::Bogus(e) => DsState::Bogus(e),
+                Proofs::Valid(nsecs, nsec3s) => match denial(&nsecs, &nsec3s, name, TYPE_DS) {
+                    Denial::Exists { typ: true, .. } => DsState::Bogus(format!("DS of {} is denied, but listed", name_str)),
+                    Denial::Exists { ns, soa, .. } => DsState::NoDs { cut: ns && !soa },
+                    Denial::NxDomain => DsState::NoDs { cut: false },
+                    Denial::OptOut => DsState::NoDs { cut: true },
+                    Denial::Unknown => DsState::Bogus(format!("no proof that {} has no DS", name_str)),
+                },
+            }
+        };
+        let exp = match result {
+            DsState::Bogus(_) => now + BOGUS_CACHE_TIME,
+            _ => now + MAX_KEY_CACHE_TIME.min(m.authority.iter().map(|rr| Time::from(rr.ttl)).min().unwrap_or(0)),
+        };
+        Done(self.remember_ds(name, result, exp))
+    }
+
+    /// Whether unsigned data for the name is expected, i.e. it is in an insecure zone
+    fn unsigned_allowed(&mut self, name: &[u8], anchors: &[TrustAnchor], now: Time) -> Lookup<Verdict> {
+        let name = lowercase(name);
+        let zone_str = name_to_string(&name);
+        if anchors.iter().any(|a| name_from_str(&a.zone) == name) {
+            return Done(Verdict::Bogus(format!("unsigned data for {}", zone_str)));
+        }
+        Done(match pull!(self.ds_state(&name, anchors, now)) {
+            DsState::NoDs { cut: true } | DsState::Insecure => Verdict::Insecure,
+            DsState::NoDs { cut: false } => match parent(&name) {
+                Some(p) => return self.unsigned_allowed(p, anchors, now),
+                None => Verdict::Bogus("unsigned root".to_string()),
+            },
+            DsState::Secure(_) => Verdict::Bogus(format!("unsigned data for signed zone {}", zone_str)),
+            DsState::Bogus(e) => Verdict::Bogus(e),
+        })
+    }
+
+    /// Validate one RRset. DS sets and, with `ds_of`, records denying DS of that name
+    /// only count as signed by a zone above the name, as the parent zone is responsible for them.
+    fn rrset_status(&mut self, set: &RrSet, ds_of: Option<&[u8]>, anchors: &[TrustAnchor], now: Time) -> Lookup<Verdict> {
+        let ds_of = if set.typ == TYPE_DS { Some(&set.name[..]) } else { ds_of };
+        let signer = set.sigs.iter().find(|s| {
+            is_under(&set.name, &s.signer) && ds_of.is_none_or(|n| is_under(n, &s.signer) && !n.eq_ignore_ascii_case(&s.signer))
+        });
+        let signer = match (signer, ds_of) {
+            (Some(s), _) => s.signer.clone(),
+            (None, None) => return self.unsigned_allowed(&set.name, anchors, now),
+            (None, Some(n)) => match parent(n) {
+                // Unsigned data is fine only in an insecure parent zone
+                Some(p) => return self.unsigned_allowed(p, anchors, now),
+                None => return Done(Verdict::Bogus("unsigned data about DS of the root".to_string())),
+            },
+        };
+        Done(match pull!(self.zone_keys(&signer, anchors, now)) {
+            ZoneKeys::Insecure => Verdict::Insecure,
+            ZoneKeys::Bogus(e) => Verdict::Bogus(e),
+            ZoneKeys::Secure(ref keys) if rrset_verified_by(set, &signer, keys, now) => Verdict::Secure,
+            ZoneKeys::Secure(_) => Verdict::Bogus(format!("bad signature of {} type {}", name_to_string(&set.name), set.typ)),
+        })
+    }
+
+    /// Validate upstream answer to the question. Lists all missing DS and DNSKEY answers at once.
+    pub fn validate(&mut self, m: &Message, qname: &[u8], qtype: u16, anchors: &[TrustAnchor], now: Time) -> Lookup<Verdict> {
+        let mut needs = vec![];
+        let mut verdict = Verdict::Secure;
+        let note = |v: Verdict, verdict: &mut Verdict| match v {
+            Verdict::Secure => {}
+            Verdict::Insecure => {
+                if *verdict == Verdict::Secure {
+                    *verdict = Verdict::Insecure
+                }
+            }
+            Verdict::Bogus(e) => *verdict = Verdict::Bogus(e),
+        };
+
+        let sets = rrsets(&m.answers);
+        let mut wildcards = vec![];
+        for set in sets.iter().filter(|s| s.class == 1) {
+            match self.rrset_status(set, None, anchors, now) {
+                Need(n) => needs.extend(n),
+                Done(v) => {
+                    if v == Verdict::Secure {
+                        let min_labels = set.sigs.iter().map(|s| usize::from(s.labels)).min().unwrap_or(0);
+                        if min_labels < label_count(&set.name) {
+                            wildcards.push((set.name.clone(), min_labels));
+                        }
+                    }
+                    note(v, &mut verdict);
+                }
+            }
+        }
+
+        // Follow CNAMEs to the name the final answer is about
+        let mut target = lowercase(qname);
+        for _ in 0..16 {
+            match sets.iter().find(|s| s.name == target && s.typ == TYPE_CNAME) {
+                Some(s) => target = lowercase(&s.rrs[0].rdata),
+                None => break,
+            }
+        }
+        let answered = sets.iter().any(|s| s.name == target && s.typ == qtype);
+        let rcode = m.flags & 0x000F;
+
+        if !answered || !wildcards.is_empty() {
+            match self.proofs(&m.authority, None, anchors, now) {
+                Need(n) => needs.extend(n),
+                Done(Proofs::Insecure) => note(Verdict::Insecure, &mut verdict),
+                Done(Proofs::Bogus(e)) => note(Verdict::Bogus(e), &mut verdict),
+                Done(Proofs::Valid(nsecs, nsec3s)) => {
+                    let signed = !nsecs.is_empty() || !nsec3s.is_empty();
+                    if !answered && !signed {
+                        match self.unsigned_allowed(&target, anchors, now) {
+                            Need(n) => needs.extend(n),
+                            Done(v) => note(v, &mut verdict),
+                        }
+                    } else if !answered {
+                        let ok = match denial(&nsecs, &nsec3s, &target, qtype) {
+                            Denial::Exists { typ, cname, .. } => rcode == 0 && !typ && !cname,
+                            Denial::NxDomain => rcode == 3,
+                            Denial::OptOut => {
+                                note(Verdict::Insecure, &mut verdict);
+                                true
+                            }
+                            Denial::Unknown => false,
+                        };
+                        if !ok {
+                            note(Verdict::Bogus(format!("no proof of absence of {}", name_to_string(&target))), &mut verdict);
+                        }
+                    }
+                    for (name, min_labels) in wildcards {
+                        // The exact name must not exist for the wildcard to apply
+                        let mut next_closer = &name[..];
+                        for _ in 0..label_count(&name) - min_labels - 1 {
+                            next_closer = parent(next_closer).unwrap_or(next_closer);
+                        }
+                        let proven = nsecs.iter().any(|n| n.covers(&name)) || nsec3s.iter().any(|n| n.covers(next_closer));
+                        if !proven {
+                            note(Verdict::Bogus(format!("wildcard answer for {} without proof", name_to_string(&name))), &mut verdict);
+                        }
+                    }
+                }
+            }
+        }
+
+        if !needs.is_empty() {
+            needs.sort();
+            needs.dedup();
+            return Need(needs);
+        }
+        Done(verdict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair};
+
+    const NOW: Time = 1_700_000_000;
+    const TYPE_A: u16 = 1;
+    const TYPE_NS: u16 = 2;
+    const TYPE_AAAA: u16 = 28;
+
+    enum Key {
+        Ed25519(Ed25519KeyPair),
+        Ecdsa(EcdsaKeyPair),
+    }
+
+    impl Key {
+        fn ed25519() -> Key {
+            let doc = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            Key::Ed25519(Ed25519KeyPair::from_pkcs8(doc.as_ref()).unwrap())
+        }
+
+        fn ecdsa() -> Key {
+            let rng = SystemRandom::new();
+            let alg = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+            let doc = EcdsaKeyPair::generate_pkcs8(alg, &rng).unwrap();
+            Key::Ecdsa(EcdsaKeyPair::from_pkcs8(alg, doc.as_ref(), &rng).unwrap())
+        }
+
+        fn dnskey(&self) -> Vec<u8> {
+            let (alg, key) = match *self {
+                Key::Ed25519(ref k) => (15, k.public_key().as_ref().to_vec()),
+                Key::Ecdsa(ref k) => (13, k.public_key().as_ref()[1..].to_vec()),
+            };
+            let mut v = vec![1, 1, 3, alg]; // zone key, secure entry point
+            v.extend_from_slice(&key);
+            v
+        }
+
+        fn sign(&self, data: &[u8]) -> Vec<u8> {
+            match *self {
+                Key::Ed25519(ref k) => k.sign(data).as_ref().to_vec(),
+                Key::Ecdsa(ref k) => k.sign(&SystemRandom::new(), data).unwrap().as_ref().to_vec(),
+            }
+        }
+    }
+
+    fn rr(name: &str, typ: u16, rdata: Vec<u8>) -> Record {
+        Record {
+            name: name_from_str(name),
+            typ,
+            class: 1,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    /// RRSIG over records of one RRset
+    fn sign(records: &[Record], zone: &str, key: &Key, expiration: u32) -> Record {
+        let dnskey = key.dnskey();
+        let typ = records[0].typ;
+        let mut rdata = vec![(typ >> 8) as u8, typ as u8, dnskey[3], label_count(&records[0].name) as u8];
+        rdata.extend_from_slice(&300u32.to_be_bytes());
+        rdata.extend_from_slice(&expiration.to_be_bytes());
+        rdata.extend_from_slice(&(NOW as u32 - 3600).to_be_bytes());
+        rdata.extend_from_slice(&key_tag(&dnskey).to_be_bytes());
+        rdata.extend_from_slice(&name_from_str(zone));
+        let sig = parse_rrsig(&rdata).unwrap();
+        let sets = rrsets(records);
+        let signature = key.sign(&signed_data(&sets[0], &sig));
+        rdata.extend_from_slice(&signature);
+        Record {
+            name: records[0].name.clone(),
+            typ: TYPE_RRSIG,
+            class: 1,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    fn signed(records: Vec<Record>, zone: &str, key: &Key) -> Vec<Record> {
+        let sig = sign(&records, zone, key, NOW as u32 + 3600);
+        let mut v = records;
+        v.push(sig);
+        v
+    }
+
+    fn ds(owner: &str, key: &Key) -> Vec<u8> {
+        let dnskey = key.dnskey();
+        let mut v = key_tag(&dnskey).to_be_bytes().to_vec();
+        v.extend_from_slice(&[dnskey[3], 2]);
+        v.extend_from_slice(&ds_digest(&name_from_str(owner), &dnskey, 2).unwrap());
+        v
+    }
+
+    fn bitmap(types: &[u16]) -> Vec<u8> {
+        let len = types.iter().map(|t| t / 8 + 1).max().unwrap() as usize;
+        let mut v = vec![0, len as u8];
+        v.resize(2 + len, 0);
+        for &t in types {
+            v[2 + t as usize / 8] |= 0x80 >> (t % 8);
+        }
+        v
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[u16]) -> Record {
+        let mut rdata = name_from_str(next);
+        rdata.extend_from_slice(&bitmap(types));
+        rr(owner, TYPE_NSEC, rdata)
+    }
+
+    fn soa(zone: &str) -> Record {
+        let mut rdata = name_from_str(&format!("ns.{}", zone));
+        rdata.extend_from_slice(&name_from_str(&format!("admin.{}", zone)));
+        for x in &[1u32, 3600, 600, 86400, 300] {
+            rdata.extend_from_slice(&x.to_be_bytes());
+        }
+        rr(zone, TYPE_SOA, rdata)
+    }
+
+    fn message(qname: &str, qtype: u16, rcode: u16, answers: Vec<Record>, authority: Vec<Record>) -> Message {
+        Message {
+            id: 1,
+            flags: 0x8180 | rcode,
+            questions: vec![Question {
+                name: name_from_str(qname),
+                qtype,
+                qclass: 1,
+            }],
+            answers,
+            authority,
+            additional: vec![],
+        }
+    }
+
+    /// Root and `example.` signed with locally generated keys, `insecure.example.` delegated without DS
+    struct Zones {
+        root: Key,
+        example: Key,
+        answers: HashMap<(Name, u16), Message>,
+    }
+
+    impl Zones {
+        fn new() -> Zones {
+            let root = Key::ed25519();
+            let example = Key::ecdsa();
+            let mut answers = HashMap::new();
+            let mut add = |m: Message| {
+                let q = m.questions[0].clone();
+                answers.insert((q.name, q.qtype), m);
+            };
+            add(message(".", TYPE_DNSKEY, 0, signed(vec![rr(".", TYPE_DNSKEY, root.dnskey())], ".", &root), vec![]));
+            add(message("example.", TYPE_DS, 0, signed(vec![rr("example.", TYPE_DS, ds("example.", &example))], ".", &root), vec![]));
+            add(message(
+                "example.",
+                TYPE_DNSKEY,
+                0,
+                signed(vec![rr("example.", TYPE_DNSKEY, example.dnskey())], "example.", &example),
+                vec![],
+            ));
+            let mut authority = signed(vec![soa("example.")], "example.", &example);
+            authority.extend(signed(vec![nsec("insecure.example.", "www.example.", &[TYPE_NS, TYPE_RRSIG, TYPE_NSEC])], "example.", &example));
+            add(message("insecure.example.", TYPE_DS, 0, vec![], authority));
+            let mut authority = signed(vec![soa("example.")], "example.", &example);
+            authority.extend(signed(vec![nsec("www.example.", "example.", &[TYPE_A, TYPE_RRSIG, TYPE_NSEC])], "example.", &example));
+            add(message("www.example.", TYPE_DS, 0, vec![], authority));
+            add(message("host.insecure.example.", TYPE_DS, 0, vec![], vec![soa("insecure.example.")]));
+            Zones { root, example, answers }
+        }
+
+        fn anchors(&self) -> Vec<TrustAnchor> {
+            let d = ds(".", &self.root);
+            vec![TrustAnchor {
+                zone: String::new(),
+                key_tag: u16::from_be_bytes([d[0], d[1]]),
+                algorithm: d[2],
+                digest_type: d[3],
+                digest: d[4..].to_vec(),
+            }]
+        }
+
+        /// Validate, answering DS and DNSKEY queries from the zones
+        fn validate(&self, m: &Message) -> Verdict {
+            let mut v = Validator::default();
+            let anchors = self.anchors();
+            let q = &m.questions[0];
+            for _ in 0..20 {
+                match v.validate(m, &q.name, q.qtype, &anchors, NOW) {
+                    Done(x) => return x,
+                    Need(needs) => {
+                        for (name, qtype) in needs {
+                            let a = self.answers.get(&(name.clone(), qtype)).cloned().unwrap_or_default();
+                            v.add_fetched(&name, qtype, a, NOW);
+                        }
+                    }
+                }
+            }
+            panic!("validation does not finish");
+        }
+
+        fn www_a(&self) -> Vec<Record> {
+            vec![rr("www.example.", TYPE_A, vec![192, 0, 2, 1])]
+        }
+    }
+
+    fn is_bogus(v: &Verdict) -> bool {
+        matches!(*v, Verdict::Bogus(_))
+    }
+
+    #[test]
+    fn secure_answer() {
+        let z = Zones::new();
+        let m = message("www.example.", TYPE_A, 0, signed(z.www_a(), "example.", &z.example), vec![]);
+        assert_eq!(z.validate(&m), Verdict::Secure);
+    }
+
+    #[test]
+    fn secure_answer_mixed_case() {
+        let z = Zones::new();
+        let mut answers = signed(z.www_a(), "example.", &z.example);
+        for rr in &mut answers {
+            rr.name = name_from_str("WwW.ExAmPle.");
+        }
+        let m = message("WwW.ExAmPle.", TYPE_A, 0, answers, vec![]);
+        assert_eq!(z.validate(&m), Verdict::Secure);
+    }
+
+    #[test]
+    fn tampered_answer() {
+        let z = Zones::new();
+        let mut answers = signed(z.www_a(), "example.", &z.example);
+        answers[0].rdata = vec![203, 0, 113, 1];
+        let m = message("www.example.", TYPE_A, 0, answers, vec![]);
+        assert!(is_bogus(&z.validate(&m)));
+    }
+
+    #[test]
+    fn expired_signature() {
+        let z = Zones::new();
+        let mut answers = z.www_a();
+        let sig = sign(&answers, "example.", &z.example, NOW as u32 - 60);
+        answers.push(sig);
+        let m = message("www.example.", TYPE_A, 0, answers, vec![]);
+        assert!(is_bogus(&z.validate(&m)));
+    }
+
+    #[test]
+    fn unsigned_answer_in_signed_zone() {
+        let z = Zones::new();
+        let m = message("www.example.", TYPE_A, 0, z.www_a(), vec![]);
+        assert!(is_bogus(&z.validate(&m)));
+    }
+
+    #[test]
+    fn signed_by_wrong_key() {
+        let z = Zones::new();
+        let m = message("www.example.", TYPE_A, 0, signed(z.www_a(), "example.", &Key::ecdsa()), vec![]);
+        assert!(is_bogus(&z.validate(&m)));
+    }
+
+    #[test]
+    fn insecure_delegation() {
+        let z = Zones::new();
+        let m = message("host.insecure.example.", TYPE_A, 0, vec![rr("host.insecure.example.", TYPE_A, vec![192, 0, 2, 2])], vec![]);
+        assert_eq!(z.validate(&m), Verdict::Insecure);
+    }
+
+    #[test]
+    fn secure_nodata() {
+        let z = Zones::new();
+        let mut authority = signed(vec![soa("example.")], "example.", &z.example);
+        authority.extend(signed(vec![nsec("www.example.", "example.", &[TYPE_A, TYPE_RRSIG, TYPE_NSEC])], "example.", &z.example));
+        let m = message("www.example.", TYPE_AAAA, 0, vec![], authority.clone());
+        assert_eq!(z.validate(&m), Verdict::Secure);
+
+        // The same proof does not deny A
+        let m = message("www.example.", TYPE_A, 0, vec![], authority);
+        assert!(is_bogus(&z.validate(&m)));
+    }
+
+    #[test]
+    fn secure_nxdomain() {
+        let z = Zones::new();
+        let mut authority = signed(vec![soa("example.")], "example.", &z.example);
+        // Covers both `nope.example.` and `*.example.`
+        authority.extend(signed(vec![nsec("example.", "insecure.example.", &[TYPE_NS, TYPE_SOA, TYPE_RRSIG, TYPE_NSEC, TYPE_DNSKEY])], "example.", &z.example));
+        authority.extend(signed(vec![nsec("insecure.example.", "www.example.", &[TYPE_NS, TYPE_RRSIG, TYPE_NSEC])], "example.", &z.example));
+        let m = message("nope.example.", TYPE_A, 3, vec![], authority.clone());
+        assert_eq!(z.validate(&m), Verdict::Secure);
+
+        // NXDOMAIN proof is not enough for NOERROR
+        let m = message("nope.example.", TYPE_A, 0, vec![], authority);
+        assert!(is_bogus(&z.validate(&m)));
+    }
+
+    #[test]
+    fn ds_denial_signed_by_child() {
+        let mut z = Zones::new();
+        let evil = Key::ed25519();
+        let mut authority = signed(vec![soa("evil.example.")], "evil.example.", &evil);
+        authority.extend(signed(vec![nsec("evil.example.", "www.example.", &[TYPE_NS, TYPE_RRSIG, TYPE_NSEC])], "evil.example.", &evil));
+        let ds_denial = message("evil.example.", TYPE_DS, 0, vec![], authority);
+        z.answers.insert((name_from_str("evil.example."), TYPE_DS), ds_denial);
+        let keys = signed(vec![rr("evil.example.", TYPE_DNSKEY, evil.dnskey())], "evil.example.", &evil);
+        z.answers.insert((name_from_str("evil.example."), TYPE_DNSKEY), message("evil.example.", TYPE_DNSKEY, 0, keys, vec![]));
+        let a = vec![rr("host.evil.example.", TYPE_A, vec![192, 0, 2, 3])];
+        let m = message("host.evil.example.", TYPE_A, 0, signed(a, "evil.example.", &evil), vec![]);
+        assert!(is_bogus(&z.validate(&m)));
+    }
+
+    #[test]
+    fn trust_anchor_from_str() {
+        let a: TrustAnchor = "example. 12345 13 2 0A0b".parse().unwrap();
+        assert_eq!(a.zone, "example");
+        assert_eq!(a.ds_rdata(), vec![0x30, 0x39, 13, 2, 0x0A, 0x0B]);
+        assert!("example. 12345 13 2 0A0".parse::<TrustAnchor>().is_err());
+        assert_eq!(TrustAnchor::root().len(), 2);
+    }
+}
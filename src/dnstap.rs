@@ -0,0 +1,302 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! dnstap output: protobuf messages in Frame Streams, written to a file or a Unix socket.
+//! The outcome of client responses (`cached`, `stale`, `negative`, `queued`, `forwarded`, `blocked`, `rpz`)
+//! is stored in the `extra` field.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use dnscache::{ip_octets, BoxResult, Outcome, Tap, UpstreamId};
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+const QUEUE_LEN: usize = 10000;
+
+// Frame Streams control frame types
+const FSTRM_ACCEPT: u32 = 1;
+const FSTRM_START: u32 = 2;
+const FSTRM_READY: u32 = 4;
+const FSTRM_FIELD_CONTENT_TYPE: u32 = 1;
+
+// dnstap.Message.Type
+const CLIENT_QUERY: u64 = 5;
+const CLIENT_RESPONSE: u64 = 6;
+const FORWARDER_QUERY: u64 = 7;
+const FORWARDER_RESPONSE: u64 = 8;
+
+#[derive(Debug, Clone)]
+pub enum Destination {
+    File(PathBuf),
+    #[cfg(unix)]
+    Socket(PathBuf),
+}
+
+/// Protobuf encoding of the few types dnstap needs
+struct Proto(Vec<u8>);
+
+impl Proto {
+    fn varint(&mut self, mut x: u64) {
+        while x >= 0x80 {
+            self.0.push((x as u8) | 0x80);
+            x >>= 7;
+        }
+        self.0.push(x as u8);
+    }
+    fn uint(&mut self, field: u32, x: u64) {
+        self.varint(u64::from(field) << 3);
+        self.varint(x);
+    }
+    fn bytes(&mut self, field: u32, b: &[u8]) {
+        self.varint(u64::from(field) << 3 | 2);
+        self.varint(b.len() as u64);
+        self.0.extend_from_slice(b);
+    }
+    fn fixed32(&mut self, field: u32, x: u32) {
+        self.varint(u64::from(field) << 3 | 5);
+        self.0.extend_from_slice(&[x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8]);
+    }
+}
+
+struct MessageInfo<'a> {
+    typ: u64,
+    query_addr: SocketAddr,
+    response_addr: SocketAddr,
+    query_time: Option<SystemTime>,
+    response_time: Option<SystemTime>,
+    query: Option<&'a [u8]>,
+    response: Option<&'a [u8]>,
+    outcome: Option<Outcome>,
+}
+
+fn encode(m: &MessageInfo) -> Vec<u8> {
+    let mut msg = Proto(Vec::with_capacity(256));
+    msg.uint(1, m.typ);
+    msg.uint(2, if m.query_addr.is_ipv4() { 1 } else { 2 }); // INET, INET6
+    msg.uint(3, 1); // UDP
+    msg.bytes(4, &ip_octets(m.query_addr.ip()));
+    msg.bytes(5, &ip_octets(m.response_addr.ip()));
+    msg.uint(6, u64::from(m.query_addr.port()));
+    msg.uint(7, u64::from(m.response_addr.port()));
+    if let Some(d) = m.query_time.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        msg.uint(8, d.as_secs());
+        msg.fixed32(9, d.subsec_nanos());
+    }
+    if let Some(q) = m.query {
+        msg.bytes(10, q);
+    }
+    if let Some(d) = m.response_time.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        msg.uint(12, d.as_secs());
+        msg.fixed32(13, d.subsec_nanos());
+    }
+    if let Some(r) = m.response {
+        msg.bytes(14, r);
+    }
+
+    let mut dt = Proto(Vec::with_capacity(msg.0.len() + 64));
+    dt.bytes(2, concat!("dnscache ", env!("CARGO_PKG_VERSION")).as_bytes());
+    if let Some(o) = m.outcome {
+        dt.bytes(3, o.name().as_bytes());
+    }
+    dt.bytes(14, &msg.0);
+    dt.uint(15, 1); // MESSAGE
+    dt.0
+}
+
+pub struct DnstapTap {
+    tx: SyncSender<Vec<u8>>,
+    listen: SocketAddr,
+    /// Addresses by [`UpstreamId`]
+    upstreams: Vec<SocketAddr>,
+    dropped: u64,
+}
+
+impl DnstapTap {
+    fn send(&mut self, m: &MessageInfo) {
+        match self.tx.try_send(encode(m)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                if self.dropped.is_power_of_two() {
+                    warn!("dnstap: output is too slow, dropped {} messages", self.dropped);
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+impl Tap<SocketAddr> for DnstapTap {
+    fn client_query(&mut self, client: SocketAddr, query: &[u8], time: SystemTime) {
+        let listen = self.listen;
+        self.send(&MessageInfo {
+            typ: CLIENT_QUERY,
+            query_addr: client,
+            response_addr: listen,
+            query_time: Some(time),
+            response_time: None,
+            query: Some(query),
+            response: None,
+            outcome: None,
+        });
+    }
+
+    fn client_response(&mut self, client: SocketAddr, response: &[u8], outcome: Outcome, query_time: SystemTime) {
+        let listen = self.listen;
+        self.send(&MessageInfo {
+            typ: CLIENT_RESPONSE,
+            query_addr: client,
+            response_addr: listen,
+            query_time: Some(query_time),
+            response_time: Some(SystemTime::now()),
+            query: None,
+            response: Some(response),
+            outcome: Some(outcome),
+        });
+    }
+
+    fn forwarder_query(&mut self, upstream: UpstreamId, query: &[u8]) {
+        let (listen, upstream) = (self.listen, self.upstreams[upstream]);
+        self.send(&MessageInfo {
+            typ: FORWARDER_QUERY,
+            query_addr: listen,
+            response_addr: upstream,
+            query_time: Some(SystemTime::now()),
+            response_time: None,
+            query: Some(query),
+            response: None,
+            outcome: None,
+        });
+    }
+
+    fn forwarder_response(&mut self, upstream: UpstreamId, response: &[u8]) {
+        let (listen, upstream) = (self.listen, self.upstreams[upstream]);
+        self.send(&MessageInfo {
+            typ: FORWARDER_RESPONSE,
+            query_addr: listen,
+            response_addr: upstream,
+            query_time: None,
+            response_time: Some(SystemTime::now()),
+            query: None,
+            response: Some(response),
+            outcome: None,
+        });
+    }
+}
+
+fn control_frame(typ: u32) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(64);
+    payload.extend_from_slice(&typ.to_be_bytes());
+    payload.extend_from_slice(&FSTRM_FIELD_CONTENT_TYPE.to_be_bytes());
+    payload.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+    payload.extend_from_slice(CONTENT_TYPE);
+    let mut frame = Vec::with_capacity(payload.len() + 8);
+    frame.extend_from_slice(&0u32.to_be_bytes()); // escape
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+fn read_u32(r: &mut dyn Read) -> io::Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn open(dest: &Destination) -> BoxResult<Box<dyn Write>> {
+    match *dest {
+        Destination::File(ref path) => {
+            let mut f = BufWriter::new(File::create(path)?);
+            f.write_all(&control_frame(FSTRM_START))?;
+            Ok(Box::new(f))
+        }
+        #[cfg(unix)]
+        Destination::Socket(ref path) => {
+            use std::os::unix::net::UnixStream;
+            let mut s = UnixStream::connect(path)?;
+            s.set_read_timeout(Some(Duration::from_secs(5)))?;
+            // Bidirectional handshake: READY, expect ACCEPT, then START
+            s.write_all(&control_frame(FSTRM_READY))?;
+            if read_u32(&mut s)? != 0 {
+                Err("dnstap: expected control frame from the reader")?;
+            }
+            let len = read_u32(&mut s)? as usize;
+            let mut payload = vec![0; len];
+            s.read_exact(&mut payload)?;
+            if len < 4 || read_u32(&mut &payload[..4])? != FSTRM_ACCEPT {
+                Err("dnstap: reader did not accept the stream")?;
+            }
+            s.write_all(&control_frame(FSTRM_START))?;
+            Ok(Box::new(BufWriter::new(s)))
+        }
+    }
+}
+
+fn write_frames(w: &mut dyn Write, first: &[u8], rx: &Receiver<Vec<u8>>) -> io::Result<()> {
+    w.write_all(&(first.len() as u32).to_be_bytes())?;
+    w.write_all(first)?;
+    while let Ok(frame) = rx.try_recv() {
+        w.write_all(&(frame.len() as u32).to_be_bytes())?;
+        w.write_all(&frame)?;
+    }
+    w.flush()
+}
+
+fn run_writer(dest: &Destination, rx: &Receiver<Vec<u8>>) {
+    loop {
+        let mut w = match open(dest) {
+            Ok(w) => w,
+            Err(e) => {
+                error!("dnstap: cannot open {:?}: {}", dest, e);
+                if let Destination::File(_) = *dest {
+                    return;
+                }
+                thread::sleep(Duration::from_secs(5));
+                while rx.try_recv().is_ok() {}
+                continue;
+            }
+        };
+        info!("dnstap: writing to {:?}", dest);
+        loop {
+            let frame = match rx.recv() {
+                Ok(x) => x,
+                Err(_) => return,
+            };
+            if let Err(e) = write_frames(&mut *w, &frame, rx) {
+                error!("dnstap: {}", e);
+                break;
+            }
+        }
+        if let Destination::File(_) = *dest {
+            return;
+        }
+    }
+}
+
+/// Start the writer thread and return the tap to feed it
+/// `upstreams` are addresses of the default upstream and then ones of forwarding rules, by [`UpstreamId`]
+pub fn start(dest: Destination, listen: SocketAddr, upstreams: Vec<SocketAddr>) -> DnstapTap {
+    let (tx, rx) = sync_channel(QUEUE_LEN);
+    thread::spawn(move || run_writer(&dest, &rx));
+    DnstapTap {
+        tx,
+        listen,
+        upstreams,
+        dropped: 0,
+    }
+}
+
+pub fn destination(file: Option<&Path>, socket: Option<&Path>) -> BoxResult<Option<Destination>> {
+    match (file, socket) {
+        (None, None) => Ok(None),
+        (Some(f), None) => Ok(Some(Destination::File(f.to_path_buf()))),
+        #[cfg(unix)]
+        (None, Some(s)) => Ok(Some(Destination::Socket(s.to_path_buf()))),
+        #[cfg(not(unix))]
+        (None, Some(_)) => Err("dnstap socket is only supported on Unix")?,
+        (Some(_), Some(_)) => Err("--dnstap-file and --dnstap-socket are mutually exclusive")?,
+    }
+}
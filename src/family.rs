@@ -0,0 +1,66 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! Address family filtering: empty answers to A or AAAA queries on single-stack networks
+
+use super::*;
+
+use std::str::FromStr;
+
+/// Which A and AAAA questions get answered with no records without asking upstream.
+/// Local records are not affected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FamilyMode {
+    /// Both A and AAAA are resolved
+    #[default]
+    Both,
+    /// AAAA questions always get empty answers
+    Ipv4Only,
+    /// A questions always get empty answers
+    Ipv6Only,
+    /// AAAA questions get empty answers when the name has cached A records
+    PreferIpv4,
+    /// A questions get empty answers when the name has cached AAAA records
+    PreferIpv6,
+}
+
+impl FromStr for FamilyMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "both" => Ok(FamilyMode::Both),
+            "ipv4-only" => Ok(FamilyMode::Ipv4Only),
+            "ipv6-only" => Ok(FamilyMode::Ipv6Only),
+            "prefer-ipv4" => Ok(FamilyMode::PreferIpv4),
+            "prefer-ipv6" => Ok(FamilyMode::PreferIpv6),
+            _ => Err(format!(
+                "unknown address family mode {}, expected both, ipv4-only, ipv6-only, prefer-ipv4 or prefer-ipv6",
+                s
+            )),
+        }
+    }
+}
+
+impl FamilyMode {
+    /// Whether A (`aaaa` = false) or AAAA records are never looked up
+    pub fn disables(self, aaaa: bool) -> bool {
+        match self {
+            FamilyMode::Ipv4Only => aaaa,
+            FamilyMode::Ipv6Only => !aaaa,
+            _ => false,
+        }
+    }
+
+    /// Whether A (`aaaa` = false) or AAAA records of the name with this cache entry
+    /// are left out of answers
+    pub fn suppresses(self, aaaa: bool, ce: Option<&CacheEntry>) -> bool {
+        let other_cached = |ce: &CacheEntry| {
+            let other = if aaaa { &ce.a4 } else { &ce.a6 };
+            other.as_ref().is_some_and(|x| !x.is_negative())
+        };
+        match self {
+            FamilyMode::PreferIpv4 => aaaa && ce.is_some_and(other_cached),
+            FamilyMode::PreferIpv6 => !aaaa && ce.is_some_and(other_cached),
+            _ => self.disables(aaaa),
+        }
+    }
+}
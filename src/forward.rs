@@ -0,0 +1,26 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+use super::*;
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Send queries for domains under these suffixes to other upstreams, see
+    /// [`Network::send_to_upstream_n`]. The longest matching suffix wins;
+    /// other domains go to upstream 0.
+    pub fn set_forward_rules(&mut self, rules: Vec<(String, UpstreamId)>) {
+        let mut rules: Vec<_> = rules
+            .into_iter()
+            .map(|(suffix, u)| (suffix.trim_matches('.').to_string(), u))
+            .collect();
+        rules.sort_by_key(|x| ::std::cmp::Reverse(x.0.len()));
+        self.forward_rules = rules;
+    }
+
+    /// Upstream that is asked about the domain. Client queries with several questions go to the
+    /// upstream of the first one.
+    pub fn upstream_for(&self, dom: &str) -> UpstreamId {
+        self.forward_rules
+            .iter()
+            .find(|x| is_subdomain(dom, &x.0))
+            .map_or(0, |x| x.1)
+    }
+}
@@ -0,0 +1,143 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+use super::*;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Default)]
+pub(crate) struct GcState {
+    last_pass: Time,
+    /// Next key to examine. None = no pass in progress
+    cursor: Option<String>,
+    /// Surviving entries of current pass: last use time, size, domain
+    seen: Vec<(Time, usize, String)>,
+    removed: usize,
+}
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Run a complete garbage collection pass (e.g. at startup).
+    /// Does nothing if no limits are set in [`GcOptions`].
+    pub fn collect_garbage(&mut self) -> BoxResult<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        while self.gc_step(now, true)? {}
+        Ok(())
+    }
+
+    /// Garbage collection step done after each packet. Errors are logged rather than returned,
+    /// so they don't get in the way of serving; the failed pass is retried after the interval.
+    pub(crate) fn gc_tick(&mut self, now: Time) {
+        if let Err(e) = self.gc_step(now, false) {
+            error!("gc: {}", e);
+            self.gc.cursor = None;
+            self.gc.seen = Vec::new();
+            self.gc.last_pass = now;
+        }
+    }
+
+    /// Examine next portion of entries. Returns true if pass is still in progress.
+    pub(crate) fn gc_step(&mut self, now: Time, force: bool) -> BoxResult<bool> {
+        if !self.opts.gc.enabled() {
+            return Ok(false);
+        }
+        if self.gc.cursor.is_none() {
+            if !force && now.saturating_sub(self.gc.last_pass) < self.opts.gc.interval {
+                return Ok(false);
+            }
+            self.gc.cursor = Some(String::new());
+            self.gc.seen.clear();
+            self.gc.removed = 0;
+        }
+
+        let limit = self.opts.gc.batch.max(1);
+        let mut batch = Vec::with_capacity(limit);
+        {
+            let from = self.gc.cursor.as_ref().unwrap();
+            self.db.scan(from, &mut |dom, ce| {
+                batch.push((ce.last_used(), ce.approx_size() + dom.len(), dom.to_string()));
+                batch.len() < limit
+            })?;
+        }
+        let finished = batch.len() < limit;
+        if let Some((_, _, dom)) = batch.last() {
+            self.gc.cursor = Some(format!("{}\0", dom));
+        }
+
+        for (t, size, dom) in batch {
+            if let Some(max_age) = self.opts.gc.max_age {
+                if now.saturating_sub(t) > max_age {
+                    debug!("  gc: {} is too old", dom);
+                    reverse::delete_entry(&mut self.db, &mut self.reverse, &dom)?;
+                    self.gc.removed += 1;
+                    continue;
+                }
+            }
+            self.gc.seen.push((t, size, dom));
+        }
+
+        if !finished {
+            self.db.flush()?;
+            return Ok(true);
+        }
+
+        self.gc_enforce_limits()?;
+        self.db.flush()?;
+        info!(
+            "gc: removed {} entries, {} remain",
+            self.gc.removed,
+            self.gc.seen.len()
+        );
+        self.gc.cursor = None;
+        self.gc.seen = Vec::new();
+        self.gc.last_pass = now;
+        Ok(false)
+    }
+
+    /// Evict least recently used entries seen during the pass until limits are satisfied
+    fn gc_enforce_limits(&mut self) -> BoxResult<()> {
+        let max_entries = self.opts.gc.max_entries.unwrap_or(usize::MAX);
+        let max_size = self.opts.gc.max_size.unwrap_or(usize::MAX);
+
+        let mut count = self.gc.seen.len();
+        let mut size = self.gc.seen.iter().fold(0, |a, x| a + x.1);
+        if count <= max_entries && size <= max_size {
+            return Ok(());
+        }
+
+        self.gc.seen.sort();
+        let mut evicted = 0;
+        for (_, esize, dom) in &self.gc.seen {
+            if count <= max_entries && size <= max_size {
+                break;
+            }
+            debug!("  gc: evicting {}", dom);
+            reverse::delete_entry(&mut self.db, &mut self.reverse, dom)?;
+            count -= 1;
+            size -= *esize;
+            evicted += 1;
+        }
+        self.gc.seen.drain(..evicted);
+        self.gc.removed += evicted;
+        Ok(())
+    }
+
+    /// Record that entries were used for answering, so garbage collection keeps them
+    pub(crate) fn touch_entries(&mut self, r: &SimplifiedRequest<N::ClientId>, now: Time) -> BoxResult<()> {
+        if !self.opts.gc.enabled() {
+            return Ok(());
+        }
+        let mut touched = false;
+        for q in &r.q {
+            if let Some(mut ce) = details::get_entry(&mut self.db, q.dom.as_str()) {
+                if now.saturating_sub(ce.u.unwrap_or(0)) >= self.opts.gc.touch_interval {
+                    ce.u = Some(now);
+                    self.db.put(q.dom.as_str(), &ce)?;
+                    touched = true;
+                }
+            }
+        }
+        if touched {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+}
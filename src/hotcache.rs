@@ -0,0 +1,146 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+use super::*;
+
+use std::collections::BTreeMap;
+
+struct HotEntry {
+    /// None means "known to be absent from the underlying database"
+    entry: Option<CacheEntry>,
+    size: usize,
+    tick: u64,
+}
+
+/// Bounded in-memory LRU of decoded entries in front of another `Database`.
+/// Writes go through to the underlying database immediately.
+pub struct HotCache<DB: Database> {
+    db: DB,
+    max_entries: usize,
+    max_memory: usize,
+
+    memory: usize,
+    tick: u64,
+    entries: HashMap<String, HotEntry>,
+    lru: BTreeMap<u64, String>,
+}
+
+impl<DB: Database> HotCache<DB> {
+    /// Wrap `db`, keeping at most `max_entries` entries occupying roughly `max_memory` bytes.
+    /// Zero `max_entries` disables caching; zero `max_memory` means no memory limit.
+    pub fn new(db: DB, max_entries: usize, max_memory: usize) -> Self {
+        HotCache {
+            db,
+            max_entries,
+            max_memory,
+            memory: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    /// Access the underlying database
+    pub fn inner(&mut self) -> &mut DB {
+        &mut self.db
+    }
+
+    /// Number of entries currently held in memory
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether nothing is held in memory
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Approximate number of bytes held in memory
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    fn remember(&mut self, dom: &str, entry: Option<CacheEntry>) {
+        if self.max_entries == 0 {
+            return;
+        }
+        self.forget(dom);
+
+        let size = dom.len() + entry.as_ref().map_or(0, CacheEntry::approx_size);
+        self.tick += 1;
+        self.entries.insert(
+            dom.to_string(),
+            HotEntry {
+                entry,
+                size,
+                tick: self.tick,
+            },
+        );
+        self.lru.insert(self.tick, dom.to_string());
+        self.memory += size;
+
+        while self.entries.len() > self.max_entries ||
+            (self.max_memory > 0 && self.memory > self.max_memory && self.entries.len() > 1)
+        {
+            let oldest = match self.lru.keys().next() {
+                Some(&t) => t,
+                None => break,
+            };
+            let victim = self.lru.remove(&oldest).unwrap();
+            self.forget(&victim);
+        }
+    }
+
+    fn forget(&mut self, dom: &str) {
+        if let Some(he) = self.entries.remove(dom) {
+            self.lru.remove(&he.tick);
+            self.memory -= he.size;
+        }
+    }
+}
+
+impl<DB: Database> Database for HotCache<DB> {
+    fn get(&mut self, dom: &str) -> BoxResult<Option<CacheEntry>> {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(he) = self.entries.get_mut(dom) {
+            self.lru.remove(&he.tick);
+            self.lru.insert(tick, dom.to_string());
+            he.tick = tick;
+            return Ok(he.entry.clone());
+        }
+
+        let entry = self.db.get(dom)?;
+        self.remember(dom, entry.clone());
+        Ok(entry)
+    }
+    fn put(&mut self, dom: &str, entry: &CacheEntry) -> BoxResult<()> {
+        self.db.put(dom, entry)?;
+        self.remember(dom, Some(entry.clone()));
+        Ok(())
+    }
+    fn flush(&mut self) -> BoxResult<()> {
+        self.db.flush()
+    }
+    fn delete(&mut self, dom: &str) -> BoxResult<()> {
+        self.db.delete(dom)?;
+        self.remember(dom, None);
+        Ok(())
+    }
+    fn scan(&mut self, from: &str, f: &mut dyn FnMut(&str, CacheEntry) -> bool) -> BoxResult<()> {
+        self.db.scan(from, f)
+    }
+    fn write_batch(&mut self, batch: Vec<BatchOp>) -> BoxResult<()> {
+        let mut updates = Vec::with_capacity(batch.len());
+        for op in &batch {
+            match *op {
+                BatchOp::Put(ref dom, ref entry) => updates.push((dom.clone(), Some(entry.clone()))),
+                BatchOp::Delete(ref dom) => updates.push((dom.clone(), None)),
+            }
+        }
+        self.db.write_batch(batch)?;
+        for (dom, entry) in updates {
+            self.remember(&dom, entry);
+        }
+        Ok(())
+    }
+}
@@ -14,9 +14,14 @@ extern crate multimap;
 extern crate clamp;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "dnssec")]
+extern crate ring;
 
 
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 use compactmap::wrapped::CompactMap;
 use multimap::MultiMap;
 
@@ -30,6 +35,29 @@ pub struct Options {
     pub max_ttl: u32,
     /// Limit TTL from below (in seconds)
     pub min_ttl: u32,
+    /// Removal of old entries from the database
+    pub gc: GcOptions,
+    /// Synthesize AAAA records from A records for names without AAAA
+    pub dns64: Option<Dns64>,
+    /// Per-client query and response rate limits
+    pub ratelimit: RateLimitOptions,
+    /// Which clients may get what
+    pub acl: AccessControl,
+    /// Remove private addresses from upstream answers for public names
+    pub rebind: Option<RebindProtection>,
+    /// Networks (e.g. ad servers of a hijacking ISP resolver) upstream answers must not point to.
+    /// Replies with only such addresses are ignored in favour of cached ones.
+    pub bogus_ips: Vec<(IpAddr, u8)>,
+    /// Order and number of addresses in answers
+    pub order: AnswerOrder,
+    /// Pass AD bit of upstream replies on to the clients waiting for them.
+    /// Without DNSSEC validation, it is never saved to the database.
+    pub trust_upstream_ad: bool,
+    /// Answer A or AAAA questions with no records without asking upstream
+    pub family: FamilyMode,
+    /// Validate upstream answers with DNSSEC, replying SERVFAIL to bogus ones
+    #[cfg(feature = "dnssec")]
+    pub dnssec: Option<DnssecOptions>,
 }
 
 impl Default for Options {
@@ -38,10 +66,58 @@ impl Default for Options {
             neg_ttl: 30,
             max_ttl: 0xFFFF_FFFF,
             min_ttl: 0,
+            gc: Default::default(),
+            dns64: None,
+            ratelimit: Default::default(),
+            acl: Default::default(),
+            rebind: None,
+            bogus_ips: Vec::new(),
+            order: Default::default(),
+            trust_upstream_ad: false,
+            family: FamilyMode::Both,
+            #[cfg(feature = "dnssec")]
+            dnssec: None,
         }
     }
 }
 
+/// Garbage collection settings. Nothing is removed unless at least one limit is set.
+#[derive(Debug)]
+pub struct GcOptions {
+    /// Remove entries that were neither refreshed nor used for answering for this long, seconds
+    pub max_age: Option<u64>,
+    /// Keep at most this many entries, evicting least recently used ones
+    pub max_entries: Option<usize>,
+    /// Keep approximate total size of entries below this many bytes, evicting least recently used ones
+    pub max_size: Option<usize>,
+    /// Seconds between garbage collection passes while serving
+    pub interval: u64,
+    /// Number of entries examined per served packet while a pass is in progress
+    pub batch: usize,
+    /// Granularity of last use timestamps written to the database, seconds
+    pub touch_interval: u64,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        GcOptions {
+            max_age: None,
+            max_entries: None,
+            max_size: None,
+            interval: 3600,
+            batch: 100,
+            touch_interval: 3600,
+        }
+    }
+}
+
+impl GcOptions {
+    /// Whether any limit is set
+    pub fn enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_entries.is_some() || self.max_size.is_some()
+    }
+}
+
 
 /// What [`Network::recv_from`] returns
 pub enum ReceiveResult<C: Copy> {
@@ -49,6 +125,10 @@ pub enum ReceiveResult<C: Copy> {
     FromClient(C),
     /// This is a packet from upstream DNS server
     FromUpstream,
+    /// This is a packet from other upstream DNS server, see [`DnsCache::set_forward_rules`]
+    FromUpstreamN(UpstreamId),
+    /// Nothing was received (e.g. read timeout expired); gives the caller a chance to do other work
+    Nothing,
 }
 
 /// Network abstraction
@@ -59,6 +139,17 @@ pub trait Network {
     fn send_to_client(&self, buf: &[u8], client: Self::ClientId) -> BoxResult<()>;
     /// Like UdpSocket::send_to
     fn send_to_upstream(&self, buf: &[u8]) -> BoxResult<()>;
+    /// Send to upstream selected by a forwarding rule. 0 is the default upstream.
+    fn send_to_upstream_n(&self, buf: &[u8], upstream: UpstreamId) -> BoxResult<()> {
+        let _ = upstream;
+        self.send_to_upstream(buf)
+    }
+    /// IP address of the client, for rate limits and access control lists.
+    /// Clients without one are neither limited nor denied. See [`ClientIp`].
+    fn client_ip(&self, client: Self::ClientId) -> Option<IpAddr> {
+        let _ = client;
+        None
+    }
     /// Like UdpSocket::recv_from
     fn recv_from(&self, buf: &mut [u8]) -> BoxResult<(usize, ReceiveResult<Self::ClientId>)>;
 }
@@ -72,17 +163,101 @@ pub trait Database {
     fn put(&mut self, dom: &str, entry: &CacheEntry) -> BoxResult<()>;
     /// flush previous puts
     fn flush(&mut self) -> BoxResult<()>;
+    /// remove entry, if any. Not supported by default.
+    fn delete(&mut self, _dom: &str) -> BoxResult<()> {
+        Err("deleting entries is not supported by the database")?
+    }
+    /// Visit entries in key order, starting from `from` (inclusive), until `f` returns false.
+    /// Not supported by default: garbage collection, flushing by suffix and
+    /// [`DnsCache::build_reverse_index`] then fail with an error.
+    fn scan(&mut self, _from: &str, _f: &mut dyn FnMut(&str, CacheEntry) -> bool) -> BoxResult<()> {
+        Err("iterating over entries is not supported by the database")?
+    }
+
+    /// Visit entries with keys in `from..to` in key order until `f` returns false
+    fn scan_range(
+        &mut self,
+        from: &str,
+        to: &str,
+        f: &mut dyn FnMut(&str, CacheEntry) -> bool,
+    ) -> BoxResult<()> {
+        self.scan(from, &mut |dom, ce| dom < to && f(dom, ce))
+    }
+    /// Visit entries which keys start with `prefix` in key order until `f` returns false
+    fn scan_prefix(
+        &mut self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, CacheEntry) -> bool,
+    ) -> BoxResult<()> {
+        self.scan(prefix, &mut |dom, ce| dom.starts_with(prefix) && f(dom, ce))
+    }
+    /// Apply several modifications at once. Should be atomic if the database supports it.
+    fn write_batch(&mut self, batch: Vec<BatchOp>) -> BoxResult<()> {
+        for op in batch {
+            match op {
+                BatchOp::Put(dom, entry) => self.put(&dom, &entry)?,
+                BatchOp::Delete(dom) => self.delete(&dom)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One modification in [`Database::write_batch`]
+#[derive(Debug)]
+pub enum BatchOp {
+    /// create or replace entry
+    Put(String, CacheEntry),
+    /// remove entry, if any
+    Delete(String),
+}
+
+impl<D: Database + ?Sized> Database for Box<D> {
+    fn get(&mut self, dom: &str) -> BoxResult<Option<CacheEntry>> {
+        (**self).get(dom)
+    }
+    fn put(&mut self, dom: &str, entry: &CacheEntry) -> BoxResult<()> {
+        (**self).put(dom, entry)
+    }
+    fn flush(&mut self) -> BoxResult<()> {
+        (**self).flush()
+    }
+    fn delete(&mut self, dom: &str) -> BoxResult<()> {
+        (**self).delete(dom)
+    }
+    fn scan(&mut self, from: &str, f: &mut dyn FnMut(&str, CacheEntry) -> bool) -> BoxResult<()> {
+        (**self).scan(from, f)
+    }
+    fn write_batch(&mut self, batch: Vec<BatchOp>) -> BoxResult<()> {
+        (**self).write_batch(batch)
+    }
 }
 
 /// Main object. DNS proxy with forced caching.
 pub struct DnsCache<DB: Database, N: Network> {
     db: DB,
     net: N,
-    r2a: HashMap<u16, N::ClientId>,
+    r2a: HashMap<u16, (N::ClientId, SystemTime, UpstreamId)>,
     opts: Options,
 
     unreplied_requests: UnrepliedRequests<N::ClientId>,
     dom_update_subscriptions: DomUpdateSubstriptions,
+    gc: gc::GcState,
+
+    metrics: Arc<Metrics>,
+    /// When queries with these IDs were sent upstream
+    upstream_sent: HashMap<u16, Instant>,
+    taps: Vec<Box<dyn Tap<N::ClientId>>>,
+    local: LocalRecords,
+    blocklists: Vec<Blocklist>,
+    rpz: Vec<Rpz>,
+    /// Domain suffix and upstream, longest suffix first
+    forward_rules: Vec<(String, UpstreamId)>,
+    reverse: reverse::ReverseIndex,
+    ratelimit: ratelimit::RateLimiter,
+    order: order::OrderState,
+    #[cfg(feature = "dnssec")]
+    dnssec: validate::DnssecState,
 }
 
 
@@ -92,36 +267,140 @@ pub type Time = u64;
 pub type BoxResult<T> = Result<T, Box<std::error::Error>>;
 /// TTL of a resource record, seconds
 pub type Ttl = u32;
+/// Index of upstream DNS server: 0 is the default one, others come from forwarding rules
+pub type UpstreamId = usize;
 
 
-/// Simplified record: some address with TTL
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Default)]
+/// Simplified record: some address with TTL.
+/// Serialized as `[ttl, ip]` pair to keep stored entries small.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Default, Clone)]
+#[serde(from = "AddrTtlPair", into = "AddrTtlPair")]
 pub struct AddrTtl {
     /// Time to Live, seconds
     pub ttl: Ttl,
 
     /// IPv4 or IPv6 address. Must be appropriate length (4 / 16 bytes respectively)
     // FIXME: should be a static array in the better world
-    #[serde(with = "serde_bytes")]
     pub ip: Vec<u8>,
 }
 
+#[derive(Serialize, Deserialize)]
+struct AddrTtlPair(Ttl, #[serde(with = "serde_bytes")] Vec<u8>);
+
+impl From<AddrTtlPair> for AddrTtl {
+    fn from(x: AddrTtlPair) -> Self {
+        AddrTtl { ttl: x.0, ip: x.1 }
+    }
+}
+
+impl From<AddrTtl> for AddrTtlPair {
+    fn from(x: AddrTtl) -> Self {
+        AddrTtlPair(x.ttl, x.ip)
+    }
+}
+
+impl AddrTtl {
+    /// Address as `IpAddr`, if it has appropriate length
+    pub fn ip_addr(&self) -> Option<std::net::IpAddr> {
+        use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+        match self.ip.len() {
+            4 => {
+                let mut b = [0; 4];
+                b.copy_from_slice(&self.ip);
+                Some(IpAddr::V4(Ipv4Addr::from(b)))
+            }
+            16 => {
+                let mut b = [0; 16];
+                b.copy_from_slice(&self.ip);
+                Some(IpAddr::V6(Ipv6Addr::from(b)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// RRSIG record covering the addresses of [`CacheEntry2`]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Default, Clone)]
+pub struct Rrsig {
+    /// Time to Live, seconds, as received
+    pub ttl: Ttl,
+    /// Record data in wire format, signer name uncompressed
+    #[serde(with = "serde_bytes")]
+    pub rdata: Vec<u8>,
+}
+
 /// Result of resolution of A or AAAA entries of some domain
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Default)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Default, Clone)]
 pub struct CacheEntry2 {
     /// Answer time, UNIX timestamp, seconds
     pub t: Time,
     /// Answer result
     pub a: Vec<AddrTtl>,
+    /// Answer was authenticated with DNSSEC: validated by dnscache or, when not validating,
+    /// marked with AD bit by upstream
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub secure: bool,
+    /// Answer was asked with DNSSEC OK bit, so `sigs` are all the signatures there are.
+    /// Other entries are not used for answering DO queries.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dnssec_ok: bool,
+    /// Signatures of the addresses, returned to clients asking with DNSSEC OK bit
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sigs: Vec<Rrsig>,
+}
+
+impl CacheEntry2 {
+    /// Addresses with TTLs as they would be sent to clients at `now`:
+    /// clamped between `min_ttl` and `max_ttl` and reduced by the age of the answer.
+    /// The flag tells whether the entry needs refreshing (for negative entries, per `neg_ttl`).
+    pub fn adjusted(&self, now: Time, opts: &Options) -> (Vec<AddrTtl>, bool) {
+        let (result, v) = details::adjust_ttl(&self.a, now, self.t, opts.max_ttl, opts.min_ttl);
+        let expired = match result {
+            details::AdjustTtlResult::Ok => false,
+            details::AdjustTtlResult::Expired => true,
+            details::AdjustTtlResult::Negative(x) => x >= opts.neg_ttl,
+        };
+        (v, expired)
+    }
+
+    /// No addresses were returned
+    pub fn is_negative(&self) -> bool {
+        self.a.is_empty()
+    }
 }
 
 /// Remembered status about some domain
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Default)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Default, Clone)]
 pub struct CacheEntry {
     /// Information about A records, if any. None = unqueried yet
     pub a4: Option<CacheEntry2>,
     /// Information about AAAA records, if any. None = unqueried yet
     pub a6: Option<CacheEntry2>,
+    /// Last time this entry was used for answering, UNIX timestamp, seconds.
+    /// Only maintained (coarsely) when garbage collection is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub u: Option<Time>,
+}
+
+impl CacheEntry {
+    /// Rough estimate of memory occupied by this entry, in bytes
+    pub fn approx_size(&self) -> usize {
+        let mut size = std::mem::size_of::<CacheEntry>();
+        for ce2 in self.a4.iter().chain(self.a6.iter()) {
+            size += ce2.a.iter().fold(0, |a, x| {
+                a + std::mem::size_of::<AddrTtl>() + x.ip.len()
+            });
+            size += ce2.sigs.iter().fold(0, |a, x| a + std::mem::size_of::<Rrsig>() + x.rdata.len());
+        }
+        size
+    }
+
+    /// Latest of answer times and last use time
+    pub fn last_used(&self) -> Time {
+        let t4 = self.a4.as_ref().map_or(0, |x| x.t);
+        let t6 = self.a6.as_ref().map_or(0, |x| x.t);
+        t4.max(t6).max(self.u.unwrap_or(0))
+    }
 }
 
 
@@ -136,9 +415,24 @@ pub(crate) struct SimplifiedQuestion {
 }
 pub(crate) struct SimplifiedRequest<C: Copy> {
     id: u16,
-    clientid: C,
+    /// None for requests made by dnscache itself
+    clientid: Option<C>,
     q: Vec<SimplifiedQuestion>,
     inhibit_send: bool,
+    /// When the query came from the client
+    received: SystemTime,
+    /// Where the query was sent
+    upstream: UpstreamId,
+    /// Client set AD or DO bit, so it wants to know whether the answer is authenticated
+    want_ad: bool,
+    /// Client set DNSSEC OK bit and wants signatures
+    dnssec_ok: bool,
+    /// Client set CD bit and wants answers even if DNSSEC validation fails
+    #[cfg_attr(not(feature = "dnssec"), allow(dead_code))]
+    checking_disabled: bool,
+    /// Query as received from the client, for reporting it to taps if it gets dropped.
+    /// Empty when there are no taps.
+    query: Vec<u8>,
 }
 
 declare_compactmap_token!(UnrepliedRequestId);
@@ -156,6 +450,19 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
             r2a: HashMap::new(),
             unreplied_requests: CompactMap::new(),
             dom_update_subscriptions: MultiMap::new(),
+            gc: Default::default(),
+            metrics: Default::default(),
+            upstream_sent: HashMap::new(),
+            taps: Vec::new(),
+            local: LocalRecords::new(),
+            blocklists: Vec::new(),
+            rpz: Vec::new(),
+            forward_rules: Vec::new(),
+            reverse: Default::default(),
+            ratelimit: Default::default(),
+            order: Default::default(),
+            #[cfg(feature = "dnssec")]
+            dnssec: Default::default(),
         }
     }
     
@@ -177,4 +484,45 @@ impl<DB: Database, N: Network> DnsCache<DB, N> {
     }
 }
 
+mod acl;
+mod blocklist;
+mod control;
 mod details;
+mod dns64;
+#[cfg(feature = "dnssec")]
+mod dnssec;
+mod family;
+mod forward;
+mod gc;
+mod hotcache;
+mod local;
+mod memdb;
+mod metrics;
+mod order;
+mod ratelimit;
+mod rebind;
+mod reverse;
+mod rpz;
+mod tap;
+mod textformat;
+#[cfg(feature = "dnssec")]
+mod validate;
+mod wire;
+
+pub use acl::{AccessControl, Acl, ClientIp, DenyAction};
+pub use blocklist::{BlockAction, Blocklist};
+pub use control::{in_network, ip_octets, is_subdomain, PendingRequest, Stats};
+pub use dns64::Dns64;
+pub use family::FamilyMode;
+#[cfg(feature = "dnssec")]
+pub use dnssec::{DnssecOptions, TrustAnchor};
+pub use hotcache::HotCache;
+pub use local::LocalRecords;
+pub use memdb::MemoryDatabase;
+pub use metrics::{Histogram, Metrics};
+pub use order::{AnswerOrder, OrderPolicy, SortlistRule};
+pub use ratelimit::RateLimitOptions;
+pub use rebind::{is_private_addr, RebindProtection};
+pub use rpz::Rpz;
+pub use tap::{Outcome, QuerySummary, Tap};
+pub use textformat::{parse_hosts, parse_network, parse_ttl, parse_zone, ZoneData, ZoneRecord};
@@ -0,0 +1,146 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+use super::*;
+
+use std::net::IpAddr;
+
+/// Maximum length of CNAME chain followed within local records
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Records of one name
+#[derive(Debug, Default, Clone)]
+pub(crate) struct LocalName {
+    pub a4: Vec<AddrTtl>,
+    pub a6: Vec<AddrTtl>,
+    pub cname: Option<(String, Ttl)>,
+}
+
+/// Static records answered before looking into the database and never asked from upstream
+#[derive(Debug, Default, Clone)]
+pub struct LocalRecords {
+    /// By lowercase name, as names are compared ignoring letter case
+    names: HashMap<String, LocalName>,
+}
+
+/// Result of resolving a name through local records
+pub(crate) struct LocalAnswer {
+    /// CNAME records to put before addresses: owner, target, TTL
+    pub cnames: Vec<(String, String, Ttl)>,
+    /// Name the addresses belong to
+    pub owner: String,
+    /// A and AAAA records, or None if the CNAME chain leads outside local records
+    pub addrs: Option<(Vec<AddrTtl>, Vec<AddrTtl>)>,
+}
+
+impl LocalRecords {
+    /// No records
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Number of names with records
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// There are no records
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Add A or AAAA record
+    pub fn add_address(&mut self, name: &str, ip: IpAddr, ttl: Ttl) {
+        let ln = self.names.entry(name.trim_end_matches('.').to_ascii_lowercase()).or_default();
+        match ip {
+            IpAddr::V4(x) => ln.a4.push(AddrTtl { ttl, ip: x.octets().to_vec() }),
+            IpAddr::V6(x) => ln.a6.push(AddrTtl { ttl, ip: x.octets().to_vec() }),
+        }
+    }
+
+    /// Add CNAME record. Addresses of the same name are ignored then.
+    pub fn add_cname(&mut self, name: &str, target: &str, ttl: Ttl) {
+        let ln = self.names.entry(name.trim_end_matches('.').to_ascii_lowercase()).or_default();
+        ln.cname = Some((target.trim_end_matches('.').to_ascii_lowercase(), ttl));
+    }
+
+    /// Add entries of `/etc/hosts`-style text
+    pub fn add_hosts(&mut self, text: &str, ttl: Ttl) {
+        for (ip, name) in parse_hosts(text) {
+            self.add_address(&name, ip, ttl);
+        }
+    }
+
+    /// Add A, AAAA and CNAME records of zone file text. Other records are ignored.
+    pub fn add_zone(&mut self, text: &str, origin: &str, default_ttl: Ttl) -> BoxResult<()> {
+        for r in parse_zone(text, origin)? {
+            let ttl = r.ttl.unwrap_or(default_ttl);
+            match r.data {
+                ZoneData::A(ip) => self.add_address(&r.name, IpAddr::V4(ip), ttl),
+                ZoneData::AAAA(ip) => self.add_address(&r.name, IpAddr::V6(ip), ttl),
+                ZoneData::CNAME(ref target) => self.add_cname(&r.name, target, ttl),
+                ZoneData::Other(ref typ, _) => debug!("local records: ignoring {} of {}", typ, r.name),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the name has local records, ignoring letter case
+    pub fn contains(&self, dom: &str) -> bool {
+        self.names.contains_key(&dom.to_ascii_lowercase())
+    }
+
+    pub(crate) fn resolve(&self, dom: &str) -> Option<LocalAnswer> {
+        let mut ln = self.names.get(&dom.to_ascii_lowercase())?;
+        let mut ans = LocalAnswer {
+            cnames: vec![],
+            owner: dom.to_string(),
+            addrs: None,
+        };
+        while let Some((ref target, ttl)) = ln.cname {
+            if ans.cnames.len() >= MAX_CNAME_CHAIN {
+                warn!("  local CNAME chain of {} is too long", dom);
+                return Some(ans);
+            }
+            ans.cnames.push((ans.owner.clone(), target.clone(), ttl));
+            ans.owner = target.clone();
+            match self.names.get(target) {
+                Some(x) => ln = x,
+                None => return Some(ans),
+            }
+        }
+        ans.addrs = Some((ln.a4.clone(), ln.a6.clone()));
+        Some(ans)
+    }
+}
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Replace static records, e.g. after the file they came from has changed
+    pub fn set_local_records(&mut self, local: LocalRecords) {
+        self.local = local;
+    }
+
+    /// Make the request wait for the target of a local CNAME and ask upstream for
+    /// its A and/or AAAA records which are not cached and not asked for already
+    pub(crate) fn fetch_local_target(&mut self, id: UnrepliedRequestId, target: String, a4: bool, a6: bool) -> BoxResult<()> {
+        let ce = details::get_entry(&mut self.db, &target).unwrap_or_default();
+        for &(wanted, cached, qtype) in &[(a4, ce.a4.is_some(), 0x0001), (a6, ce.a6.is_some(), 0x001C)] {
+            if wanted && !cached && !self.query_pending(&target, qtype == 0x001C) {
+                info!("  asking for target {} of local CNAME", target);
+                self.send_own_query(&target, qtype)?;
+            }
+        }
+        self.dom_update_subscriptions.insert(target, id);
+        Ok(())
+    }
+
+    /// Whether some request waits for A (`aaaa` = false) or AAAA records of the name
+    fn query_pending(&self, dom: &str, aaaa: bool) -> bool {
+        self.dom_update_subscriptions.get_vec(dom).is_some_and(|ids| {
+            ids.iter().any(|&id| {
+                self.unreplied_requests
+                    .get(id)
+                    .is_some_and(|r| r.q.iter().any(|q| q.dom == dom && if aaaa { q.a6 } else { q.a4 }))
+            })
+        })
+    }
+}
@@ -0,0 +1,59 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! Static records from hosts and zone files given on command line, reloaded when the files change.
+
+use std::fs;
+use std::path::PathBuf;
+use dnscache::{BoxResult, LocalRecords};
+use watch::WatchedFiles;
+use super::Cache;
+
+pub struct LocalFiles {
+    hosts: Vec<PathBuf>,
+    zones: Vec<PathBuf>,
+    ttl: u32,
+    watch: WatchedFiles,
+}
+
+impl LocalFiles {
+    pub fn new(hosts: &[PathBuf], zones: &[PathBuf], ttl: u32) -> Self {
+        LocalFiles {
+            hosts: hosts.to_vec(),
+            zones: zones.to_vec(),
+            ttl,
+            watch: WatchedFiles::new(hosts.iter().chain(zones)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watch.is_empty()
+    }
+
+    /// Read all the files. Fails if any of them is missing or malformed.
+    pub fn load(&self) -> BoxResult<LocalRecords> {
+        let mut local = LocalRecords::new();
+        for path in &self.hosts {
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            local.add_hosts(&text, self.ttl);
+        }
+        for path in &self.zones {
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            local.add_zone(&text, "", self.ttl).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(local)
+    }
+
+    /// Reload records if some file has changed. On error previous records stay in use.
+    pub fn reload_if_changed(&mut self, cache: &mut Cache) {
+        if !self.watch.changed() {
+            return;
+        }
+        match self.load() {
+            Ok(local) => {
+                info!("Reloaded local records: {} names", local.len());
+                cache.set_local_records(local);
+            }
+            Err(e) => error!("Keeping previous local records: {}", e),
+        }
+    }
+}
@@ -1,36 +1,92 @@
 // Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
 
 extern crate dnscache;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_cbor;
+extern crate serde_json;
 extern crate serde_bytes;
 extern crate rusty_leveldb;
+#[cfg(feature = "sled")]
+extern crate sled;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
 extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
 extern crate println_logger;
+#[macro_use]
+extern crate log;
 
-use std::net::{UdpSocket, SocketAddr};
-use rusty_leveldb::DB as LevelDB;
-use serde_cbor::de::from_slice;
-use serde_cbor::ser::to_vec;
+use std::net::{IpAddr, Ipv6Addr, UdpSocket, SocketAddr};
 use structopt::StructOpt;
+use std::ffi::OsString;
 use std::path::PathBuf;
-use dnscache::{DnsCache, Options as CacheOptions, Network};
-use dnscache::{Database, ReceiveResult, CacheEntry, BoxResult};
+use std::time::Duration;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use dnscache::{DnsCache, Options as CacheOptions, GcOptions, Network, HotCache};
+use dnscache::{ReceiveResult, BoxResult, Database, BlockAction, Dns64, RateLimitOptions, UpstreamId};
+use dnscache::{AccessControl, Acl, ClientIp, DenyAction, RebindProtection};
+use dnscache::{AnswerOrder, FamilyMode, OrderPolicy, SortlistRule};
+#[cfg(feature = "dnssec")]
+use dnscache::{DnssecOptions, TrustAnchor};
+
+mod admin;
+mod blockfiles;
+mod ctlsock;
+mod dnstap;
+mod localfiles;
+mod prometheus;
+mod querylog;
+mod storage;
+mod transfer;
+mod watch;
 
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "dnscache", about = "Simple DNS cacher.")]
-struct Opt {
-    #[structopt(help = "Listen address and port")]
-    listen_addr: SocketAddr,
+#[allow(clippy::large_enum_variant)] // parsed once
+enum Cmd {
+    #[structopt(name = "serve", about = "Run the DNS proxy")]
+    Serve(ServeOpt),
 
-    #[structopt(help = "Upstream DNS server address and port")]
-    upstream_addr: SocketAddr,
+    #[structopt(name = "export", about = "Write all database entries to a file or stdout")]
+    Export(ExportOpt),
 
-    #[structopt(help = "Path to LevelDB database directory", parse(from_os_str))]
+    #[structopt(name = "import", about = "Add entries from a file or stdin to the database")]
+    Import(ImportOpt),
+
+    #[structopt(name = "list", about = "Print all entries, one per line")]
+    List(ListOpt),
+
+    #[structopt(name = "show", about = "Print everything known about a domain")]
+    Show(ShowOpt),
+
+    #[structopt(name = "delete", about = "Delete domains or whole subtrees from the database")]
+    Delete(DeleteOpt),
+
+    #[structopt(name = "purge", about = "Remove negative or unused entries from the database")]
+    Purge(PurgeOpt),
+
+    #[structopt(name = "stats", about = "Print database summary")]
+    Stats(StatsOpt),
+}
+
+#[derive(StructOpt, Debug)]
+struct DbOpt {
+    #[structopt(help = "Path to database directory or file", parse(from_os_str))]
     db: PathBuf,
 
+    #[structopt(long = "backend",
+                help = "Database backend: leveldb, sled, sqlite or memory (nothing is saved)",
+                default_value = "leveldb", parse(try_from_str))]
+    backend: storage::Backend,
+}
+
+#[derive(StructOpt, Debug)]
+struct TtlOpt {
     #[structopt(long = "neg-ttl", help = "Negative reply TTL, seconds", default_value = "30",
                 parse(try_from_str))]
     neg_ttl: u64,
@@ -42,94 +98,680 @@ struct Opt {
     #[structopt(long = "min-ttl", help = "Minimum TTL of A or AAAA entry, seconds",
                 default_value = "0", parse(try_from_str))]
     min_ttl: u32,
+}
+
+impl TtlOpt {
+    fn to_options(&self) -> CacheOptions {
+        CacheOptions {
+            neg_ttl: self.neg_ttl,
+            max_ttl: self.max_ttl,
+            min_ttl: self.min_ttl,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct ExportOpt {
+    #[structopt(flatten)]
+    db: DbOpt,
+
+    #[structopt(long = "format", short = "f", help = "jsonl, hosts or zone",
+                default_value = "jsonl", parse(try_from_str))]
+    format: transfer::Format,
+
+    #[structopt(long = "output", short = "o", help = "Output file instead of stdout",
+                parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+struct ImportOpt {
+    #[structopt(flatten)]
+    db: DbOpt,
+
+    #[structopt(long = "format", short = "f", help = "jsonl, hosts or zone",
+                default_value = "jsonl", parse(try_from_str))]
+    format: transfer::Format,
+
+    #[structopt(long = "input", short = "i", help = "Input file instead of stdin",
+                parse(from_os_str))]
+    input: Option<PathBuf>,
+
+    #[structopt(long = "ttl", help = "TTL for hosts entries and zone entries without one, seconds",
+                default_value = "86400", parse(try_from_str))]
+    ttl: u32,
+}
+
+#[derive(StructOpt, Debug)]
+struct ListOpt {
+    #[structopt(flatten)]
+    db: DbOpt,
+
+    #[structopt(flatten)]
+    ttl: TtlOpt,
+
+    #[structopt(long = "suffix", help = "Only list this domain and its subdomains")]
+    suffix: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+struct ShowOpt {
+    #[structopt(flatten)]
+    db: DbOpt,
+
+    #[structopt(help = "Domain name")]
+    domain: String,
+
+    #[structopt(flatten)]
+    ttl: TtlOpt,
+}
+
+#[derive(StructOpt, Debug)]
+struct DeleteOpt {
+    #[structopt(flatten)]
+    db: DbOpt,
+
+    #[structopt(help = "Exact domain names to delete")]
+    names: Vec<String>,
+
+    #[structopt(long = "suffix", help = "Delete this domain and all its subdomains")]
+    suffixes: Vec<String>,
+}
+
+#[derive(StructOpt, Debug)]
+struct PurgeOpt {
+    #[structopt(flatten)]
+    db: DbOpt,
+
+    #[structopt(long = "negative", help = "Forget all negative A and AAAA results")]
+    negative: bool,
+
+    #[structopt(long = "older-than",
+                help = "Delete entries neither refreshed nor used for this long, like 3600, 12h or 30d",
+                parse(try_from_str = "admin::parse_duration"))]
+    older_than: Option<u64>,
+}
+
+#[derive(StructOpt, Debug)]
+struct StatsOpt {
+    #[structopt(flatten)]
+    db: DbOpt,
+
+    #[structopt(flatten)]
+    ttl: TtlOpt,
+}
+
+#[derive(StructOpt, Debug)]
+struct ServeOpt {
+    #[structopt(help = "Listen address and port")]
+    listen_addr: SocketAddr,
+
+    #[structopt(help = "Upstream DNS server address and port")]
+    upstream_addr: SocketAddr,
+
+    #[structopt(flatten)]
+    db: DbOpt,
+
+    #[structopt(flatten)]
+    ttl: TtlOpt,
+
+    #[structopt(long = "forward",
+                help = "Send queries for the domain and its subdomains to other upstream, like corp.example=10.0.0.53:53; may be repeated",
+                parse(try_from_str))]
+    forward: Vec<ForwardRule>,
+
+    #[structopt(long = "hot-cache-entries",
+                help = "Number of decoded entries to keep in memory, 0 to disable",
+                default_value = "10000", parse(try_from_str))]
+    hot_cache_entries: usize,
+
+    #[structopt(long = "hot-cache-memory",
+                help = "Approximate memory limit for in-memory entries, bytes, 0 for no limit",
+                default_value = "16777216", parse(try_from_str))]
+    hot_cache_memory: usize,
+
+    #[structopt(long = "gc-max-age",
+                help = "Remove entries neither refreshed nor used for this long, seconds",
+                parse(try_from_str))]
+    gc_max_age: Option<u64>,
+
+    #[structopt(long = "gc-max-entries",
+                help = "Keep at most this many entries, removing least recently used ones",
+                parse(try_from_str))]
+    gc_max_entries: Option<usize>,
+
+    #[structopt(long = "gc-max-size",
+                help = "Keep approximate total size of entries below this, bytes",
+                parse(try_from_str))]
+    gc_max_size: Option<usize>,
+
+    #[structopt(long = "gc-interval", help = "Seconds between garbage collection passes",
+                default_value = "3600", parse(try_from_str))]
+    gc_interval: u64,
+
+    #[structopt(long = "metrics-addr",
+                help = "Serve Prometheus metrics over HTTP at this address, path /metrics",
+                parse(try_from_str))]
+    metrics_addr: Option<SocketAddr>,
+
+    #[structopt(long = "query-log",
+                help = "Append one JSON line per client query to this file, - for stdout",
+                parse(from_os_str))]
+    query_log: Option<PathBuf>,
+
+    #[structopt(long = "dnstap-file", help = "Write dnstap messages to this file",
+                parse(from_os_str))]
+    dnstap_file: Option<PathBuf>,
+
+    #[structopt(long = "dnstap-socket",
+                help = "Send dnstap messages to this Unix socket (e.g. of dnstap or fstrm_capture)",
+                parse(from_os_str))]
+    dnstap_socket: Option<PathBuf>,
+
+    #[structopt(long = "control-socket",
+                help = "Unix socket for runtime commands like flushing or refreshing entries",
+                parse(from_os_str))]
+    control_socket: Option<PathBuf>,
+
+    #[structopt(long = "local-hosts",
+                help = "Answer names from this hosts file without asking upstream; may be repeated",
+                parse(from_os_str))]
+    local_hosts: Vec<PathBuf>,
+
+    #[structopt(long = "local-zone",
+                help = "Answer A, AAAA and CNAME records from this zone file; may be repeated",
+                parse(from_os_str))]
+    local_zones: Vec<PathBuf>,
+
+    #[structopt(long = "local-ttl",
+                help = "TTL for local hosts entries, zone entries and RPZ local data without one, seconds",
+                default_value = "60", parse(try_from_str))]
+    local_ttl: u32,
+
+    #[structopt(long = "blocklist",
+                help = "Block domains listed in this file (hosts format or one domain per line) and their subdomains; may be repeated",
+                parse(from_os_str))]
+    blocklists: Vec<PathBuf>,
+
+    #[structopt(long = "block-action", help = "Answer to blocked names: nxdomain, zero (0.0.0.0 and ::) or refused",
+                default_value = "nxdomain", parse(try_from_str))]
+    block_action: BlockAction,
+
+    #[structopt(long = "rpz",
+                help = "Apply this response policy zone file; may be repeated. Consulted before blocklists.",
+                parse(from_os_str))]
+    rpz: Vec<PathBuf>,
+
+    #[structopt(long = "dns64",
+                help = "Make up AAAA records from A records for names without AAAA, for NAT64")]
+    dns64: bool,
+
+    #[structopt(long = "dns64-prefix", help = "/96 prefix of synthesized AAAA records",
+                default_value = "64:ff9b::/96", parse(try_from_str = "parse_dns64_prefix"))]
+    dns64_prefix: Ipv6Addr,
+
+    #[structopt(long = "dns64-exclude",
+                help = "Also ignore A (or AAAA) records in this network for DNS64, like 192.0.2.0/24; may be repeated",
+                parse(try_from_str = "parse_net"))]
+    dns64_exclude: Vec<(IpAddr, u8)>,
+
+    #[structopt(long = "ratelimit-queries",
+                help = "Drop queries over this many per second from one client network",
+                parse(try_from_str))]
+    ratelimit_queries: Option<u32>,
+
+    #[structopt(long = "ratelimit-responses",
+                help = "Limit responses with the same name and type to one client network to this many per second",
+                parse(try_from_str))]
+    ratelimit_responses: Option<u32>,
+
+    #[structopt(long = "ratelimit-slip",
+                help = "Send every N-th response over the limit as truncated reply instead of dropping it, 0 to drop all",
+                default_value = "2", parse(try_from_str))]
+    ratelimit_slip: u32,
+
+    #[structopt(long = "ratelimit-ipv4-prefix", help = "Prefix length grouping IPv4 clients for rate limits",
+                default_value = "24", parse(try_from_str))]
+    ratelimit_ipv4_prefix: u8,
+
+    #[structopt(long = "ratelimit-ipv6-prefix", help = "Prefix length grouping IPv6 clients for rate limits",
+                default_value = "56", parse(try_from_str))]
+    ratelimit_ipv6_prefix: u8,
+
+    #[structopt(long = "acl",
+                help = "Client ACL for all kinds of queries, like \"allow 10.0.0.0/8, deny all\"; first match wins, unmatched clients are denied",
+                parse(try_from_str))]
+    acl: Option<Acl>,
+
+    #[structopt(long = "acl-cached", help = "Client ACL for answers from cache and local data, overrides --acl",
+                parse(try_from_str))]
+    acl_cached: Option<Acl>,
+
+    #[structopt(long = "acl-upstream", help = "Client ACL for A and AAAA queries asked upstream, overrides --acl",
+                parse(try_from_str))]
+    acl_upstream: Option<Acl>,
+
+    #[structopt(long = "acl-direct", help = "Client ACL for other queries forwarded as is, overrides --acl",
+                parse(try_from_str))]
+    acl_direct: Option<Acl>,
+
+    #[structopt(long = "acl-deny-action", help = "What denied clients get: refuse or drop",
+                default_value = "refuse", parse(try_from_str))]
+    acl_deny_action: DenyAction,
+
+    #[structopt(long = "rebind-protection",
+                help = "Remove private, loopback and link-local addresses from upstream answers for public names")]
+    rebind_protection: bool,
+
+    #[structopt(long = "rebind-allow",
+                help = "Names under this suffix may resolve to private addresses despite --rebind-protection; may be repeated. Suffixes of --forward rules are allowed too.")]
+    rebind_allow: Vec<String>,
+
+    #[structopt(long = "bogus-ip",
+                help = "Treat upstream answers with only addresses in this network (like 0.0.0.0/32 or a hijacking ad server) as failed lookups; may be repeated",
+                parse(try_from_str = "parse_net"))]
+    bogus_ips: Vec<(IpAddr, u8)>,
+
+    #[structopt(long = "answer-order",
+                help = "Order of addresses in answers: fixed (as upstream returned them), round-robin, random or sortlist",
+                default_value = "fixed", parse(try_from_str))]
+    answer_order: OrderPolicy,
+
+    #[structopt(long = "answer-order-for",
+                help = "Order of addresses for names under the suffix, like example.com=random; may be repeated",
+                parse(try_from_str = "parse_order_rule"))]
+    answer_order_for: Vec<(String, OrderPolicy)>,
+
+    #[structopt(long = "sortlist",
+                help = "For the sortlist order: clients in a network get addresses in the listed networks first, like \"10.1.0.0/16: 10.1.0.0/16, 10.0.0.0/8\"; may be repeated, first match wins",
+                parse(try_from_str))]
+    sortlist: Vec<SortlistRule>,
+
+    #[structopt(long = "max-answers", help = "Return at most this many A and this many AAAA records per name",
+                parse(try_from_str))]
+    max_answers: Option<usize>,
+
+    #[structopt(long = "address-family",
+                help = "both; ipv4-only or ipv6-only to answer AAAA or A queries with no records without asking upstream; prefer-ipv4 or prefer-ipv6 to do that only for names with cached addresses of the other family",
+                default_value = "both", parse(try_from_str))]
+    address_family: FamilyMode,
+
+    #[structopt(long = "dnssec",
+                help = "Validate upstream answers to A and AAAA queries with DNSSEC, answering SERVFAIL to bogus ones. Names with --forward rules are not validated.")]
+    dnssec: bool,
+
+    #[structopt(long = "trust-anchor",
+                help = "DS of a trusted key like `. 20326 8 2 E06D44B8...` to use instead of the built-in root keys; may be repeated")]
+    trust_anchors: Vec<String>,
+
+    #[structopt(long = "trust-upstream-ad",
+                help = "Pass the AD bit of upstream replies on to clients waiting for them. Only safe with a trusted link to upstream; it is never saved.")]
+    trust_upstream_ad: bool,
 
     /// Delete information about this domain before startup
     #[structopt(long="delete",short="D")]
     delete_domains: Vec<String>,
 }
 
+#[derive(Debug)]
+struct ForwardRule {
+    suffix: String,
+    upstream: SocketAddr,
+}
+
+impl std::str::FromStr for ForwardRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut parts = s.splitn(2, '=');
+        let suffix = parts.next().unwrap().trim_matches('.');
+        let addr = parts.next().ok_or_else(|| format!("expected suffix=address, got {}", s))?;
+        let upstream = match addr.parse::<SocketAddr>() {
+            Ok(x) => x,
+            Err(_) => match addr.parse::<IpAddr>() {
+                Ok(ip) => SocketAddr::new(ip, 53),
+                Err(_) => return Err(format!("bad upstream address {}", addr)),
+            },
+        };
+        Ok(ForwardRule {
+            suffix: suffix.to_string(),
+            upstream,
+        })
+    }
+}
+
+fn parse_net(s: &str) -> Result<(IpAddr, u8), String> {
+    dnscache::parse_network(s).ok_or_else(|| format!("bad network {}", s))
+}
+
+fn parse_order_rule(s: &str) -> Result<(String, OrderPolicy), String> {
+    let mut parts = s.splitn(2, '=');
+    let suffix = parts.next().unwrap().trim_matches('.');
+    let policy = parts.next().ok_or_else(|| format!("expected suffix=order, got {}", s))?;
+    Ok((suffix.to_string(), policy.parse()?))
+}
+
+fn parse_dns64_prefix(s: &str) -> Result<Ipv6Addr, String> {
+    let addr = s.trim_end_matches("/96");
+    let prefix: Ipv6Addr = addr.parse().map_err(|_| format!("bad IPv6 prefix {}", s))?;
+    if prefix.octets()[12..] != [0; 4] {
+        return Err(format!("{} is not a /96 prefix", s));
+    }
+    Ok(prefix)
+}
+
 struct MyNetwork {
     s: UdpSocket,
-    upstream: SocketAddr,
+    /// The default upstream first, then ones used by forwarding rules
+    upstreams: Vec<SocketAddr>,
 }
-struct MyDatabase(LevelDB);
 
 impl Network for MyNetwork {
     type ClientId = SocketAddr;
     fn send_to_client(&self, buf: &[u8], client: Self::ClientId) -> BoxResult<()> {
-        self.s.send_to(buf, &client)?;
+        self.s.send_to(buf, client)?;
         Ok(())
     }
     fn send_to_upstream(&self, buf: &[u8]) -> BoxResult<()> {
-        self.s.send_to(buf, &self.upstream)?;
+        self.s.send_to(buf, self.upstreams[0])?;
         Ok(())
     }
+    fn send_to_upstream_n(&self, buf: &[u8], upstream: UpstreamId) -> BoxResult<()> {
+        self.s.send_to(buf, self.upstreams[upstream])?;
+        Ok(())
+    }
+    fn client_ip(&self, client: Self::ClientId) -> Option<IpAddr> {
+        client.client_ip()
+    }
     fn recv_from(&self, buf: &mut [u8]) -> BoxResult<(usize, ReceiveResult<Self::ClientId>)> {
-        let (amt, src) = self.s.recv_from(buf)?;
-        if src == self.upstream {
-            Ok((amt, ReceiveResult::FromUpstream))
-        } else {
-            Ok((amt, ReceiveResult::FromClient(src)))
+        let (amt, src) = match self.s.recv_from(buf) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                return Ok((0, ReceiveResult::Nothing));
+            }
+            Err(e) => Err(e)?,
+        };
+        match self.upstreams.iter().position(|x| *x == src) {
+            Some(0) => Ok((amt, ReceiveResult::FromUpstream)),
+            Some(n) => Ok((amt, ReceiveResult::FromUpstreamN(n))),
+            None => Ok((amt, ReceiveResult::FromClient(src))),
         }
     }
 }
 
-impl Database for MyDatabase {
-    fn get(&mut self, dom: &str) -> BoxResult<Option<CacheEntry>> {
-        if let Some(ceb) = self.0.get(dom.as_bytes()) {
-            let ce: CacheEntry = from_slice(&ceb[..])?;
-            Ok(Some(ce))
-        } else {
-            Ok(None)
+type Cache = DnsCache<HotCache<Box<dyn Database>>, MyNetwork>;
+
+fn open_db(opt: &DbOpt) -> BoxResult<Box<dyn Database>> {
+    storage::open(opt.backend, &opt.db)
+}
+
+fn export(opt: &ExportOpt) -> BoxResult<()> {
+    let mut db = open_db(&opt.db)?;
+    let mut out: Box<dyn Write> = match opt.output {
+        Some(ref path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    transfer::export(&mut *db, opt.format, &mut *out)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn import(opt: &ImportOpt) -> BoxResult<()> {
+    let mut db = open_db(&opt.db)?;
+    let stdin = io::stdin();
+    let n = match opt.input {
+        Some(ref path) => {
+            let mut input = BufReader::new(File::open(path)?);
+            transfer::import(&mut *db, opt.format, &mut input, opt.ttl)?
         }
+        None => transfer::import(&mut *db, opt.format, &mut stdin.lock(), opt.ttl)?,
+    };
+    eprintln!("Imported {} entries", n);
+    Ok(())
+}
+
+fn list(opt: &ListOpt) -> BoxResult<()> {
+    let mut db = open_db(&opt.db)?;
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    admin::list(&mut *db, &opt.ttl.to_options(), opt.suffix.as_deref(), &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn show(opt: &ShowOpt) -> BoxResult<()> {
+    let mut db = open_db(&opt.db)?;
+    admin::show(&mut *db, &opt.ttl.to_options(), &opt.domain, &mut io::stdout())
+}
+
+fn delete(opt: &DeleteOpt) -> BoxResult<()> {
+    if opt.names.is_empty() && opt.suffixes.is_empty() {
+        Err("nothing to delete: specify domain names or --suffix")?;
     }
-    fn put(&mut self, dom: &str, entry: &CacheEntry) -> BoxResult<()> {
-        self.0.put(dom.as_bytes(), &to_vec(&entry)?[..])?;
-        Ok(())
+    let mut db = open_db(&opt.db)?;
+    let n = admin::delete(&mut *db, &opt.names, &opt.suffixes)?;
+    eprintln!("Deleted {} entries", n);
+    Ok(())
+}
+
+fn purge(opt: &PurgeOpt) -> BoxResult<()> {
+    if !opt.negative && opt.older_than.is_none() {
+        Err("nothing to purge: specify --negative or --older-than")?;
     }
-    fn flush(&mut self) -> BoxResult<()> {
-        self.0.flush()?;
-        Ok(())
+    let mut db = open_db(&opt.db)?;
+    let (changed, deleted) = admin::purge(&mut *db, opt.negative, opt.older_than)?;
+    eprintln!("Deleted {} entries, changed {} entries", deleted, changed);
+    Ok(())
+}
+
+fn stats(opt: &StatsOpt) -> BoxResult<()> {
+    let mut db = open_db(&opt.db)?;
+    admin::stats(&mut *db, &opt.ttl.to_options(), &mut io::stdout())
+}
+
+fn dns64_options(opt: &ServeOpt) -> Dns64 {
+    let mut dns64 = Dns64::with_prefix(opt.dns64_prefix);
+    for &(net, prefix) in &opt.dns64_exclude {
+        match net {
+            IpAddr::V4(x) => dns64.exclude_a.push((x, prefix)),
+            IpAddr::V6(x) => dns64.exclude_aaaa.push((x, prefix)),
+        }
+    }
+    dns64
+}
+
+fn acl_options(opt: &ServeOpt) -> AccessControl {
+    let common = opt.acl.clone().unwrap_or_default();
+    AccessControl {
+        cached: opt.acl_cached.clone().unwrap_or_else(|| common.clone()),
+        upstream: opt.acl_upstream.clone().unwrap_or_else(|| common.clone()),
+        direct: opt.acl_direct.clone().unwrap_or(common),
+        deny_action: opt.acl_deny_action,
+    }
+}
+
+#[cfg(feature = "dnssec")]
+fn dnssec_options(opt: &ServeOpt) -> BoxResult<Option<DnssecOptions>> {
+    if !opt.dnssec {
+        return Ok(None);
+    }
+    let mut dnssec = DnssecOptions::default();
+    if !opt.trust_anchors.is_empty() {
+        dnssec.trust_anchors = opt.trust_anchors
+            .iter()
+            .map(|x| x.parse::<TrustAnchor>())
+            .collect::<Result<_, _>>()?;
+    }
+    Ok(Some(dnssec))
+}
+
+#[cfg(not(feature = "dnssec"))]
+fn dnssec_options(opt: &ServeOpt) -> BoxResult<()> {
+    if opt.dnssec || !opt.trust_anchors.is_empty() {
+        Err("dnscache is built without DNSSEC support")?;
     }
+    Ok(())
 }
 
-fn run(opt: &Opt) -> BoxResult<()> {
-    let dbopts: rusty_leveldb::Options = Default::default();
-    let mut db = LevelDB::open(
-        &opt.db,
-        dbopts,
-    )?;
+fn serve(opt: &ServeOpt) -> BoxResult<()> {
+    let mut db = open_db(&opt.db)?;
 
     for deldm in &opt.delete_domains {
-        db.delete(deldm.as_bytes())?;
+        db.delete(deldm)?;
     }
 
     let s = UdpSocket::bind(opt.listen_addr)?;
-    let upstream = opt.upstream_addr;
 
-    if opt.listen_addr.ip().is_loopback() && !opt.upstream_addr.ip().is_loopback() {
+    let mut upstreams = vec![opt.upstream_addr];
+    let mut forward_rules = Vec::with_capacity(opt.forward.len());
+    for rule in &opt.forward {
+        let n = match upstreams.iter().position(|x| *x == rule.upstream) {
+            Some(n) => n,
+            None => {
+                upstreams.push(rule.upstream);
+                upstreams.len() - 1
+            }
+        };
+        forward_rules.push((rule.suffix.clone(), n));
+    }
+
+    if opt.listen_addr.ip().is_loopback() && upstreams.iter().any(|x| !x.ip().is_loopback()) {
         eprintln!(
             "Warning: listening on localhost, but sending to non-localhost upstream server is not supported"
         );
     }
 
-    let net = MyNetwork { s, upstream };
+    let dnstap = dnstap::destination(opt.dnstap_file.as_deref(), opt.dnstap_socket.as_deref())?;
+
+    let net = MyNetwork { s, upstreams };
 
+    #[cfg(not(feature = "dnssec"))]
+    dnssec_options(opt)?;
     let dnscache_opts = CacheOptions {
-        neg_ttl: opt.neg_ttl,
-        max_ttl: opt.max_ttl,
-        min_ttl: opt.min_ttl,
+        gc: GcOptions {
+            max_age: opt.gc_max_age,
+            max_entries: opt.gc_max_entries,
+            max_size: opt.gc_max_size,
+            interval: opt.gc_interval,
+            ..Default::default()
+        },
+        dns64: if opt.dns64 { Some(dns64_options(opt)) } else { None },
+        acl: acl_options(opt),
+        bogus_ips: opt.bogus_ips.clone(),
+        trust_upstream_ad: opt.trust_upstream_ad,
+        order: AnswerOrder {
+            default: opt.answer_order,
+            per_domain: opt.answer_order_for.clone(),
+            sortlist: opt.sortlist.clone(),
+            max_answers: opt.max_answers,
+        },
+        family: opt.address_family,
+        #[cfg(feature = "dnssec")]
+        dnssec: dnssec_options(opt)?,
+        rebind: if opt.rebind_protection {
+            Some(RebindProtection {
+                internal_suffixes: opt.rebind_allow
+                    .iter()
+                    .map(|x| x.trim_matches('.').to_string())
+                    .chain(opt.forward.iter().map(|x| x.suffix.clone()))
+                    .collect(),
+            })
+        } else {
+            None
+        },
+        ratelimit: RateLimitOptions {
+            queries_per_sec: opt.ratelimit_queries,
+            responses_per_sec: opt.ratelimit_responses,
+            slip: opt.ratelimit_slip,
+            ipv4_prefix: opt.ratelimit_ipv4_prefix.min(32),
+            ipv6_prefix: opt.ratelimit_ipv6_prefix.min(128),
+        },
+        ..opt.ttl.to_options()
     };
 
-    let mut dnscache = DnsCache::new(MyDatabase(db), net, dnscache_opts);
+    let db = HotCache::new(db, opt.hot_cache_entries, opt.hot_cache_memory);
+
+    let mut dnscache: Cache = DnsCache::new(db, net, dnscache_opts);
+    dnscache.collect_garbage()?;
+    let n = dnscache.build_reverse_index()?;
+    info!("Reverse index: {} addresses", n);
+    dnscache.set_forward_rules(forward_rules);
+
+    if let Some(ref path) = opt.query_log {
+        dnscache.add_tap(Box::new(querylog::QueryLog::open(path)?));
+    }
+
+    if let Some(dest) = dnstap {
+        let upstreams = dnscache.network().upstreams.clone();
+        let tap = dnstap::start(dest, opt.listen_addr, upstreams);
+        dnscache.add_tap(Box::new(tap));
+    }
+
+    if let Some(addr) = opt.metrics_addr {
+        prometheus::listen(addr, dnscache.metrics())?;
+    }
+
+    let mut local = localfiles::LocalFiles::new(&opt.local_hosts, &opt.local_zones, opt.local_ttl);
+    if !local.is_empty() {
+        dnscache.set_local_records(local.load()?);
+    }
+
+    let mut blocks = blockfiles::BlockFiles::new(&opt.blocklists, opt.block_action, &opt.rpz, opt.local_ttl);
+    if !blocks.is_empty() {
+        blocks.load_into(&mut dnscache)?;
+    }
+
+    if opt.control_socket.is_none() && local.is_empty() && blocks.is_empty() {
+        return dnscache.run_endlessly();
+    }
+
+    let commands = match opt.control_socket {
+        Some(ref path) => Some(ctlsock::listen(path)?),
+        None => None,
+    };
+    // Wake up regularly to execute commands and check files even if there is no DNS traffic
+    dnscache.network().s.set_read_timeout(Some(Duration::from_millis(100)))?;
+    loop {
+        if let Err(e) = dnscache.serve_one_packet() {
+            error!("{}", e);
+        }
+        if let Some(ref commands) = commands {
+            ctlsock::execute_pending(&mut dnscache, commands);
+        }
+        local.reload_if_changed(&mut dnscache);
+        blocks.reload_if_changed(&mut dnscache);
+    }
+}
 
-    dnscache.run_endlessly()
+/// Command line with `serve` inserted when it does not start with a subcommand,
+/// so that the old `dnscache <listen_addr> <upstream_addr> <db>` form keeps working
+fn args_with_default_subcommand() -> Vec<OsString> {
+    const NOT_SERVE: &[&str] = &[
+        "serve", "export", "import", "list", "show", "delete", "purge", "stats", "help",
+        "-h", "--help", "-V", "--version",
+    ];
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let implicit = args.get(1).is_some_and(|x| !NOT_SERVE.iter().any(|y| x == y));
+    if implicit {
+        args.insert(1, "serve".into());
+    }
+    args
 }
 
 fn main() {
     println_logger::init();
-    let opt = Opt::from_args();
-    if let Err(e) = run(&opt) {
+    let cmd = Cmd::from_iter(args_with_default_subcommand());
+    let result = match cmd {
+        Cmd::Serve(ref opt) => serve(opt),
+        Cmd::Export(ref opt) => export(opt),
+        Cmd::Import(ref opt) => import(opt),
+        Cmd::List(ref opt) => list(opt),
+        Cmd::Show(ref opt) => show(opt),
+        Cmd::Delete(ref opt) => delete(opt),
+        Cmd::Purge(ref opt) => purge(opt),
+        Cmd::Stats(ref opt) => stats(opt),
+    };
+    if let Err(e) = result {
         eprintln!("error: {}", e);
         std::process::exit(1);
     }
@@ -0,0 +1,41 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+use super::*;
+
+use std::collections::BTreeMap;
+
+/// Database that lives only in memory. Useful for ephemeral caches and tests.
+#[derive(Debug, Default)]
+pub struct MemoryDatabase(pub BTreeMap<String, CacheEntry>);
+
+impl MemoryDatabase {
+    /// Create empty database
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl Database for MemoryDatabase {
+    fn get(&mut self, dom: &str) -> BoxResult<Option<CacheEntry>> {
+        Ok(self.0.get(dom).cloned())
+    }
+    fn put(&mut self, dom: &str, entry: &CacheEntry) -> BoxResult<()> {
+        self.0.insert(dom.to_string(), entry.clone());
+        Ok(())
+    }
+    fn flush(&mut self) -> BoxResult<()> {
+        Ok(())
+    }
+    fn delete(&mut self, dom: &str) -> BoxResult<()> {
+        self.0.remove(dom);
+        Ok(())
+    }
+    fn scan(&mut self, from: &str, f: &mut dyn FnMut(&str, CacheEntry) -> bool) -> BoxResult<()> {
+        for (dom, ce) in self.0.range(from.to_string()..) {
+            if !f(dom, ce.clone()) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
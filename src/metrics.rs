@@ -0,0 +1,220 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+use super::*;
+
+use std::fmt::Write;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of upstream round trip time buckets, seconds
+const RTT_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Forget about upstream queries not answered for this long
+const RTT_FORGET: Duration = Duration::from_secs(60);
+/// Check for forgotten upstream queries when there are this many of them
+const RTT_PRUNE_THRESHOLD: usize = 4096;
+
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Cumulative histogram in Prometheus sense
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; 12],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    /// Record one observation
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9;
+        for (i, &bound) in RTT_BUCKETS.iter().enumerate() {
+            if secs <= bound {
+                self.buckets[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let micros = d.as_secs() * 1_000_000 + u64::from(d.subsec_micros());
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, out: &mut String) {
+        for (i, &bound) in RTT_BUCKETS.iter().enumerate() {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, self.buckets[i].load(Ordering::Relaxed));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Counters updated while serving. Shared with other threads via [`DnsCache::metrics`].
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Client queries answered from fresh cache entries
+    pub cached: AtomicU64,
+    /// Client queries answered from stale cache entries, with refresh sent upstream
+    pub refreshing: AtomicU64,
+    /// Client queries answered from fresh negative cache entries
+    pub negative: AtomicU64,
+    /// Client queries that had to wait for upstream
+    pub queued: AtomicU64,
+    /// Client queries forwarded to upstream as is
+    pub direct: AtomicU64,
+    /// Client queries answered according to a blocklist
+    pub blocked: AtomicU64,
+    /// Blocked queries by blocklist name
+    pub blocked_by_list: Mutex<BTreeMap<String, u64>>,
+    /// Client queries answered according to a response policy zone
+    pub rpz: AtomicU64,
+    /// Such queries by zone name
+    pub rpz_by_zone: Mutex<BTreeMap<String, u64>>,
+    /// Client queries dropped because of rate limits
+    pub ratelimited: AtomicU64,
+    /// Client queries over the response rate limit answered with empty truncated reply
+    pub slipped: AtomicU64,
+    /// Client queries refused or dropped because of access control lists
+    pub denied: AtomicU64,
+    /// Upstream replies for awaited domains, but with unexpected ID
+    pub id_mismatch: AtomicU64,
+    /// Upstream replies for domains nobody waits for
+    pub unsolicited: AtomicU64,
+    /// Replies without addresses ignored because cache has some
+    pub refused_to_forget: AtomicU64,
+    /// Private addresses removed from upstream answers for public names
+    pub rebind_filtered: AtomicU64,
+    /// Upstream answers for a name that had only bogus addresses
+    pub bogus_replies: AtomicU64,
+    /// Upstream answers validated with DNSSEC
+    pub dnssec_secure: AtomicU64,
+    /// Upstream answers provably not signed
+    pub dnssec_insecure: AtomicU64,
+    /// Upstream answers that failed DNSSEC validation
+    pub dnssec_bogus: AtomicU64,
+    /// Requests waiting for upstream to answer A or AAAA questions
+    pub unreplied_requests: AtomicU64,
+    /// Requests forwarded as is, waiting for upstream reply
+    pub direct_requests: AtomicU64,
+    /// Time between sending query to upstream and receiving reply with the same ID
+    pub upstream_rtt: Histogram,
+}
+
+impl Metrics {
+    /// Increment a counter
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Increment the counter of queries blocked by the named list
+    pub fn inc_blocklist(&self, list: &str) {
+        let mut m = self.blocked_by_list.lock().unwrap();
+        *m.entry(list.to_string()).or_insert(0) += 1;
+    }
+
+    /// Increment the counter of queries answered by the named response policy zone
+    pub fn inc_rpz(&self, zone: &str) {
+        let mut m = self.rpz_by_zone.lock().unwrap();
+        *m.entry(zone.to_string()).or_insert(0) += 1;
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::with_capacity(2048);
+        let get = |x: &AtomicU64| x.load(Ordering::Relaxed);
+
+        out.push_str("# HELP dnscache_client_queries_total Client queries by how they were handled.\n");
+        out.push_str("# TYPE dnscache_client_queries_total counter\n");
+        for &(result, x) in &[
+            ("cached", &self.cached),
+            ("refreshing", &self.refreshing),
+            ("negative", &self.negative),
+            ("queued", &self.queued),
+            ("direct", &self.direct),
+            ("blocked", &self.blocked),
+            ("rpz", &self.rpz),
+            ("ratelimited", &self.ratelimited),
+            ("slipped", &self.slipped),
+            ("denied", &self.denied),
+        ] {
+            let _ = writeln!(out, "dnscache_client_queries_total{{result=\"{}\"}} {}", result, get(x));
+        }
+
+        out.push_str("# HELP dnscache_blocked_total Client queries blocked, by blocklist.\n");
+        out.push_str("# TYPE dnscache_blocked_total counter\n");
+        for (list, n) in self.blocked_by_list.lock().unwrap().iter() {
+            let _ = writeln!(out, "dnscache_blocked_total{{list=\"{}\"}} {}", escape_label(list), n);
+        }
+
+        out.push_str("# HELP dnscache_rpz_total Client queries answered by response policy zone.\n");
+        out.push_str("# TYPE dnscache_rpz_total counter\n");
+        for (zone, n) in self.rpz_by_zone.lock().unwrap().iter() {
+            let _ = writeln!(out, "dnscache_rpz_total{{zone=\"{}\"}} {}", escape_label(zone), n);
+        }
+
+        out.push_str("# HELP dnscache_dnssec_answers_total Upstream answers by DNSSEC validation result.\n");
+        out.push_str("# TYPE dnscache_dnssec_answers_total counter\n");
+        for &(result, x) in &[
+            ("secure", &self.dnssec_secure),
+            ("insecure", &self.dnssec_insecure),
+            ("bogus", &self.dnssec_bogus),
+        ] {
+            let _ = writeln!(out, "dnscache_dnssec_answers_total{{result=\"{}\"}} {}", result, get(x));
+        }
+
+        for &(name, help, x) in &[
+            ("dnscache_id_mismatch_total", "Upstream replies with unexpected ID.", &self.id_mismatch),
+            ("dnscache_unsolicited_replies_total", "Upstream replies nobody waits for.", &self.unsolicited),
+            ("dnscache_refused_to_forget_total", "Empty replies ignored in favour of cached addresses.", &self.refused_to_forget),
+            ("dnscache_rebind_filtered_total", "Private addresses removed from answers for public names.", &self.rebind_filtered),
+            ("dnscache_bogus_replies_total", "Answers with only bogus addresses, suspected tampering.", &self.bogus_replies),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, get(x));
+        }
+
+        for &(name, help, x) in &[
+            ("dnscache_unreplied_requests", "Requests waiting for upstream.", &self.unreplied_requests),
+            ("dnscache_direct_requests", "Forwarded requests waiting for upstream.", &self.direct_requests),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, get(x));
+        }
+
+        out.push_str("# HELP dnscache_upstream_rtt_seconds Upstream round trip time.\n");
+        out.push_str("# TYPE dnscache_upstream_rtt_seconds histogram\n");
+        self.upstream_rtt.render("dnscache_upstream_rtt_seconds", &mut out);
+        out
+    }
+}
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Counters, e.g. for exporting from another thread
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Remember when query with this ID was sent upstream
+    pub(crate) fn note_upstream_query(&mut self, id: u16) {
+        let now = Instant::now();
+        if self.upstream_sent.len() >= RTT_PRUNE_THRESHOLD {
+            self.upstream_sent.retain(|_, t| now.duration_since(*t) < RTT_FORGET);
+        }
+        self.upstream_sent.insert(id, now);
+    }
+
+    pub(crate) fn note_upstream_reply(&mut self, id: u16) {
+        if let Some(t) = self.upstream_sent.remove(&id) {
+            self.metrics.upstream_rtt.observe(t.elapsed());
+        }
+    }
+
+    pub(crate) fn update_gauges(&self) {
+        let m = &self.metrics;
+        m.unreplied_requests.store(self.unreplied_requests.len_slow() as u64, Ordering::Relaxed);
+        m.direct_requests.store(self.r2a.len() as u64, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,192 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! Order of addresses in answers: rotation, shuffling, networks preferred by clients
+
+use super::*;
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Forget rotation positions when there are this many of them
+const ROTATION_PRUNE_THRESHOLD: usize = 65536;
+
+/// How addresses of a name are ordered in answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderPolicy {
+    /// As upstream returned them
+    Fixed,
+    /// Rotated by one on each answer
+    RoundRobin,
+    /// Shuffled on each answer
+    Random,
+    /// Addresses in networks preferred for the client first, see [`SortlistRule`]
+    Sortlist,
+}
+
+impl FromStr for OrderPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "fixed" => Ok(OrderPolicy::Fixed),
+            "round-robin" | "cyclic" => Ok(OrderPolicy::RoundRobin),
+            "random" => Ok(OrderPolicy::Random),
+            "sortlist" => Ok(OrderPolicy::Sortlist),
+            _ => Err(format!(
+                "unknown answer order {}, expected fixed, round-robin, random or sortlist",
+                s
+            )),
+        }
+    }
+}
+
+/// Like BIND's sortlist statement: clients in `clients` get addresses in the first of
+/// `preferred` networks first, then ones in the second and so on, then all others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortlistRule {
+    /// Networks of clients the rule is for
+    pub clients: Vec<(IpAddr, u8)>,
+    /// Networks of addresses, most preferred first
+    pub preferred: Vec<(IpAddr, u8)>,
+}
+
+impl FromStr for SortlistRule {
+    type Err = String;
+    /// Client network and preferred networks, like `10.1.0.0/16: 10.1.0.0/16, 10.0.0.0/8`.
+    /// `all` as client network means both `0.0.0.0/0` and `::/0`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut parts = s.splitn(2, ':');
+        let (clients, preferred) = match (parts.next(), parts.next()) {
+            (Some(c), Some(p)) => (c.trim(), p),
+            _ => return Err(format!("expected <client network>: <networks>, got {}", s)),
+        };
+        let clients = match clients {
+            "all" | "any" => vec![(IpAddr::from([0u8; 4]), 0), (IpAddr::from([0u8; 16]), 0)],
+            net => vec![parse_network(net).ok_or_else(|| format!("bad network {}", net))?],
+        };
+        let preferred = preferred
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|net| parse_network(net).ok_or_else(|| format!("bad network {}", net)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SortlistRule { clients, preferred })
+    }
+}
+
+/// Ordering and number of addresses in answers
+#[derive(Debug, Clone)]
+pub struct AnswerOrder {
+    /// Policy for names not under any of `per_domain` suffixes
+    pub default: OrderPolicy,
+    /// Domain suffix and policy for names under it; the longest matching suffix wins
+    pub per_domain: Vec<(String, OrderPolicy)>,
+    /// Rules for `Sortlist` policy, the first one matching the client is used.
+    /// Clients without address or not matched by any rule get addresses as is.
+    pub sortlist: Vec<SortlistRule>,
+    /// Return at most this many A and this many AAAA records per name.
+    /// Signatures of cut sets are not returned.
+    pub max_answers: Option<usize>,
+}
+
+impl Default for AnswerOrder {
+    fn default() -> Self {
+        AnswerOrder {
+            default: OrderPolicy::Fixed,
+            per_domain: Vec::new(),
+            sortlist: Vec::new(),
+            max_answers: None,
+        }
+    }
+}
+
+impl AnswerOrder {
+    /// Policy used for the name
+    pub fn policy_for(&self, dom: &str) -> OrderPolicy {
+        self.per_domain
+            .iter()
+            .filter(|x| is_subdomain(dom, &x.0))
+            .max_by_key(|x| x.0.len())
+            .map_or(self.default, |x| x.1)
+    }
+
+    /// Sortlist rule for the client
+    fn sortlist_rule(&self, client: IpAddr) -> Option<&SortlistRule> {
+        self.sortlist
+            .iter()
+            .find(|r| r.clients.iter().any(|&(net, prefix)| in_network(client, net, prefix)))
+    }
+}
+
+/// Rotation positions and shuffling randomness
+#[derive(Debug)]
+pub(crate) struct OrderState {
+    /// Next rotation offset by name and whether it is about AAAA
+    rotation: HashMap<(String, bool), usize>,
+    /// xorshift state
+    rng: u64,
+}
+
+impl Default for OrderState {
+    fn default() -> Self {
+        let nanos = SystemTime::now().duration_since(::std::time::UNIX_EPOCH).map(|x| x.subsec_nanos()).unwrap_or(0);
+        OrderState {
+            rotation: HashMap::new(),
+            rng: u64::from(nanos) | 1,
+        }
+    }
+}
+
+impl OrderState {
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// Reorder and cut addresses of the name according to options.
+    /// Returns whether some addresses were cut off.
+    pub(crate) fn arrange(&mut self, opts: &AnswerOrder, dom: &str, client: Option<IpAddr>, a: &mut Vec<AddrTtl>) -> bool {
+        if a.len() > 1 {
+            match opts.policy_for(dom) {
+                OrderPolicy::Fixed => (),
+                OrderPolicy::RoundRobin => {
+                    if self.rotation.len() >= ROTATION_PRUNE_THRESHOLD {
+                        self.rotation.clear();
+                    }
+                    let pos = self.rotation.entry((dom.to_string(), a[0].ip.len() == 16)).or_insert(0);
+                    let n = a.len();
+                    a.rotate_left(*pos % n);
+                    *pos = (*pos + 1) % n;
+                }
+                OrderPolicy::Random => {
+                    for i in (1..a.len()).rev() {
+                        let j = (self.next_random() % (i as u64 + 1)) as usize;
+                        a.swap(i, j);
+                    }
+                }
+                OrderPolicy::Sortlist => {
+                    if let Some(rule) = client.and_then(|ip| opts.sortlist_rule(ip)) {
+                        a.sort_by_key(|x| {
+                            let ip = match x.ip_addr() {
+                                Some(ip) => ip,
+                                None => return rule.preferred.len(),
+                            };
+                            rule.preferred
+                                .iter()
+                                .position(|&(net, prefix)| in_network(ip, net, prefix))
+                                .unwrap_or(rule.preferred.len())
+                        });
+                    }
+                }
+            }
+        }
+        match opts.max_answers {
+            Some(max) if a.len() > max => {
+                a.truncate(max);
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,57 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! Minimal HTTP server exposing `/metrics`
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use dnscache::{BoxResult, Metrics};
+
+pub fn listen(addr: SocketAddr, metrics: Arc<Metrics>) -> BoxResult<()> {
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let result = conn.map_err(From::from).and_then(|c| handle(c, &metrics));
+            if let Err(e) = result {
+                debug!("metrics connection: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(conn: TcpStream, metrics: &Metrics) -> BoxResult<()> {
+    conn.set_read_timeout(Some(Duration::from_secs(5)))?;
+    conn.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut out = conn.try_clone()?;
+    let mut reader = BufReader::new(conn);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip headers
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut words = request_line.split_whitespace();
+    let (status, content_type, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", metrics.render())
+        }
+        (Some("GET"), Some(_)) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "Method not allowed\n".to_string()),
+    };
+    write!(
+        out,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,70 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! Query log: one JSON object per client query
+
+use std::fs::OpenOptions;
+use std::io::{self, LineWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use dnscache::{BoxResult, Outcome, QuerySummary, Tap, Ttl};
+
+#[derive(Serialize)]
+struct Line<'a> {
+    /// UNIX timestamp of the query, seconds
+    time: f64,
+    client: SocketAddr,
+    qname: &'a str,
+    qtype: &'a str,
+    outcome: &'static str,
+    answers: &'a [IpAddr],
+    ttl: Option<Ttl>,
+    latency_ms: f64,
+}
+
+/// Outcome as named in query logs, which differs from dnstap for stale and forwarded answers
+fn outcome_name(o: Outcome) -> &'static str {
+    match o {
+        Outcome::Stale => "stale-refresh",
+        Outcome::Forwarded => "direct",
+        o => o.name(),
+    }
+}
+
+pub struct QueryLog {
+    out: Box<dyn Write>,
+}
+
+impl QueryLog {
+    /// `-` means stdout, otherwise the file is appended to
+    pub fn open(path: &Path) -> BoxResult<QueryLog> {
+        let out: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(LineWriter::new(io::stdout()))
+        } else {
+            Box::new(LineWriter::new(OpenOptions::new().create(true).append(true).open(path)?))
+        };
+        Ok(QueryLog { out })
+    }
+}
+
+impl Tap<SocketAddr> for QueryLog {
+    fn query_done(&mut self, s: &QuerySummary<SocketAddr>) {
+        let time = s.received.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let line = Line {
+            time: time.as_secs() as f64 + f64::from(time.subsec_millis()) / 1e3,
+            client: s.client,
+            qname: &s.qname,
+            qtype: &s.qtype,
+            outcome: outcome_name(s.outcome),
+            answers: &s.answers,
+            ttl: s.ttl,
+            latency_ms: s.latency.as_secs() as f64 * 1e3 + f64::from(s.latency.subsec_micros()) / 1e3,
+        };
+        let result = ::serde_json::to_writer(&mut self.out, &line)
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(self.out));
+        if let Err(e) = result {
+            error!("query log: {}", e);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! Per-client query rate limits and response rate limiting (RRL) with slip
+
+use super::*;
+
+use details::{get_entry, synthesize_reply};
+use dns_parser::Packet;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Forget buckets not used for this long
+const BUCKET_FORGET: Duration = Duration::from_secs(60);
+/// Look for forgotten buckets when there are this many of them
+const BUCKET_PRUNE_THRESHOLD: usize = 4096;
+
+/// Client rate limits. Clients are grouped into networks by address prefix,
+/// see [`Network::client_ip`]. Clients without address are not limited.
+#[derive(Debug, Clone)]
+pub struct RateLimitOptions {
+    /// Queries per second accepted from one client network, `None` = unlimited.
+    /// Excess queries are dropped before any other processing.
+    pub queries_per_sec: Option<u32>,
+    /// Responses per second of the same class for one client network, `None` = unlimited.
+    /// Like in BIND RRL, answers are grouped by name and type, but names without cached answer
+    /// (NXDOMAIN is likely for them) by zone, so that random names don't escape the limit.
+    /// Responses are accounted when the query arrives.
+    pub responses_per_sec: Option<u32>,
+    /// Every `slip`-th response over the limit is sent as empty truncated reply, so that
+    /// real clients retry over TCP. Others are dropped. 0 = drop all of them.
+    pub slip: u32,
+    /// Prefix length grouping IPv4 clients
+    pub ipv4_prefix: u8,
+    /// Prefix length grouping IPv6 clients
+    pub ipv6_prefix: u8,
+}
+
+impl Default for RateLimitOptions {
+    fn default() -> Self {
+        RateLimitOptions {
+            queries_per_sec: None,
+            responses_per_sec: None,
+            slip: 2,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+        }
+    }
+}
+
+/// Token bucket holding up to one second worth of tokens
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    /// Responses over the limit so far, for slip
+    over: u32,
+}
+
+impl Bucket {
+    fn new(rate: u32, now: Instant) -> Self {
+        Bucket {
+            tokens: f64::from(rate),
+            last: now,
+            over: 0,
+        }
+    }
+
+    /// Take a token if there is one
+    fn take(&mut self, rate: u32, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last);
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + secs * f64::from(rate)).min(f64::from(rate));
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// What to do with a query over the response limit
+#[derive(Debug, PartialEq)]
+pub(crate) enum RrlVerdict {
+    Pass,
+    Slip,
+    Drop,
+}
+
+/// What responses are grouped by for response rate limiting
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub(crate) enum ResponseClass {
+    /// Lowercase name and type with answer in the cache
+    Answer(String, u16),
+    /// Zone of the name without cached answer: the name without its first label,
+    /// as zone cuts are not known
+    Negative(String),
+}
+
+impl ResponseClass {
+    fn new(dom: &str, qtype: u16, cached: bool) -> Self {
+        let dom = dom.to_ascii_lowercase();
+        if cached {
+            return ResponseClass::Answer(dom, qtype);
+        }
+        match dom.find('.') {
+            Some(i) => ResponseClass::Negative(dom[i + 1..].to_string()),
+            None => ResponseClass::Negative(dom),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    queries: HashMap<Vec<u8>, Bucket>,
+    responses: HashMap<(Vec<u8>, ResponseClass), Bucket>,
+}
+
+/// Address bytes of the client network
+fn client_network(ip: IpAddr, opts: &RateLimitOptions) -> Vec<u8> {
+    let (mut v, prefix) = match ip {
+        IpAddr::V4(x) => (x.octets().to_vec(), opts.ipv4_prefix),
+        IpAddr::V6(x) => (x.octets().to_vec(), opts.ipv6_prefix),
+    };
+    let prefix = usize::from(prefix);
+    for (i, b) in v.iter_mut().enumerate() {
+        if prefix <= i * 8 {
+            *b = 0;
+        } else if prefix < i * 8 + 8 {
+            *b &= 0xFFu8 << (i * 8 + 8 - prefix);
+        }
+    }
+    v
+}
+
+fn prune<K: ::std::hash::Hash + Eq>(buckets: &mut HashMap<K, Bucket>, now: Instant) {
+    if buckets.len() >= BUCKET_PRUNE_THRESHOLD {
+        buckets.retain(|_, b| now.duration_since(b.last) < BUCKET_FORGET);
+    }
+}
+
+impl RateLimiter {
+    /// Whether a query from the client may be processed
+    pub fn allow_query(&mut self, ip: IpAddr, opts: &RateLimitOptions, now: Instant) -> bool {
+        let rate = match opts.queries_per_sec {
+            Some(x) => x,
+            None => return true,
+        };
+        prune(&mut self.queries, now);
+        self.queries
+            .entry(client_network(ip, opts))
+            .or_insert_with(|| Bucket::new(rate, now))
+            .take(rate, now)
+    }
+
+    /// Account for a response of the class going to the client
+    pub fn check_response(&mut self, ip: IpAddr, class: ResponseClass, opts: &RateLimitOptions, now: Instant) -> RrlVerdict {
+        let rate = match opts.responses_per_sec {
+            Some(x) => x,
+            None => return RrlVerdict::Pass,
+        };
+        prune(&mut self.responses, now);
+        let key = (client_network(ip, opts), class);
+        let b = self.responses.entry(key).or_insert_with(|| Bucket::new(rate, now));
+        if b.take(rate, now) {
+            return RrlVerdict::Pass;
+        }
+        b.over = b.over.wrapping_add(1);
+        if opts.slip > 0 && b.over % opts.slip == 0 {
+            RrlVerdict::Slip
+        } else {
+            RrlVerdict::Drop
+        }
+    }
+}
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Query rate limit. Returns false if the query was dropped.
+    pub(crate) fn ratelimit_query(&mut self, src: N::ClientId, received: SystemTime, query: &[u8]) -> bool {
+        let ip = match self.net.client_ip(src) {
+            Some(x) => x,
+            None => return true,
+        };
+        if self.ratelimit.allow_query(ip, &self.opts.ratelimit, Instant::now()) {
+            return true;
+        }
+        debug!("rate limited query from {}", ip);
+        Metrics::inc(&self.metrics.ratelimited);
+        self.tap_unanswered(src, received, query, Outcome::RateLimited);
+        false
+    }
+
+    /// Response rate limit. Returns false if the query got truncated reply or was dropped.
+    pub(crate) fn ratelimit_response(
+        &mut self,
+        src: N::ClientId,
+        received: SystemTime,
+        query: &[u8],
+        p: &Packet,
+    ) -> BoxResult<bool> {
+        if self.opts.ratelimit.responses_per_sec.is_none() {
+            return Ok(true);
+        }
+        let (ip, q) = match (self.net.client_ip(src), p.questions.first()) {
+            (Some(ip), Some(q)) => (ip, q),
+            _ => return Ok(true),
+        };
+        let dom = q.qname.to_string();
+        let qtype = q.qtype as u16;
+        let cached = match get_entry(&mut self.db, &dom) {
+            Some(ce) => {
+                let ce2 = match qtype {
+                    0x0001 => ce.a4,
+                    0x001C => ce.a6,
+                    _ => None,
+                };
+                ce2.is_some_and(|x| !x.is_negative())
+            }
+            None => false,
+        };
+        let class = ResponseClass::new(&dom, qtype, cached);
+        let verdict = self.ratelimit.check_response(ip, class, &self.opts.ratelimit, Instant::now());
+        match verdict {
+            RrlVerdict::Pass => return Ok(true),
+            RrlVerdict::Slip => {
+                info!("  rate limited, truncated");
+                Metrics::inc(&self.metrics.slipped);
+                let mut reply = synthesize_reply(query, 0, &[])?;
+                reply[2] |= 0x02; // TC
+                self.net.send_to_client(&reply, src)?;
+                tap::tap_reply(&mut self.taps, src, received, &reply, Outcome::RateLimited);
+            }
+            RrlVerdict::Drop => {
+                info!("  rate limited, dropped");
+                Metrics::inc(&self.metrics.ratelimited);
+                self.tap_unanswered(src, received, query, Outcome::RateLimited);
+            }
+        }
+        Ok(false)
+    }
+}
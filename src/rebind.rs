@@ -0,0 +1,49 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! DNS rebinding protection: no private addresses for public names
+
+use super::*;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Networks that must not appear in answers about public names
+const PRIVATE_V4: [(Ipv4Addr, u8); 6] = [
+    (Ipv4Addr::new(0, 0, 0, 0), 8),
+    (Ipv4Addr::new(10, 0, 0, 0), 8),
+    (Ipv4Addr::new(127, 0, 0, 0), 8),
+    (Ipv4Addr::new(169, 254, 0, 0), 16),
+    (Ipv4Addr::new(172, 16, 0, 0), 12),
+    (Ipv4Addr::new(192, 168, 0, 0), 16),
+];
+const PRIVATE_V6: [(Ipv6Addr, u8); 4] = [
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 127), // :: and ::1
+    (Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10),
+    (Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7),
+    (Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0), 10),
+];
+
+/// Whether the address is RFC 1918, loopback, link-local, unique local or unspecified.
+/// IPv4-mapped IPv6 addresses are checked as IPv4.
+pub fn is_private_addr(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(x) => PRIVATE_V4.iter().any(|&(net, prefix)| in_network(IpAddr::V4(x), IpAddr::V4(net), prefix)),
+        IpAddr::V6(x) => match x.to_ipv4_mapped() {
+            Some(v4) => is_private_addr(IpAddr::V4(v4)),
+            None => PRIVATE_V6.iter().any(|&(net, prefix)| in_network(IpAddr::V6(x), IpAddr::V6(net), prefix)),
+        },
+    }
+}
+
+/// Removal of private addresses from upstream answers for names outside of internal domains
+#[derive(Debug, Clone, Default)]
+pub struct RebindProtection {
+    /// Names under these suffixes may resolve to private addresses
+    pub internal_suffixes: Vec<String>,
+}
+
+impl RebindProtection {
+    /// Whether the address must be removed from answer for the name
+    pub fn rejects(&self, dom: &str, ip: IpAddr) -> bool {
+        is_private_addr(ip) && !self.internal_suffixes.iter().any(|s| is_subdomain(dom, s))
+    }
+}
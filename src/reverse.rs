@@ -0,0 +1,227 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! PTR answers made from names that resolved to the queried address
+
+use super::*;
+
+use details::{get_entry, raw_questions, synthesize_reply};
+use dns_parser::Packet;
+use dns_parser::QueryClass::{IN, Any as QCAny};
+use dns_parser::QueryType::PTR;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tap::tap_reply;
+
+/// At most this many names go into one PTR answer
+const MAX_PTR_NAMES: usize = 16;
+
+/// Addresses (4 or 16 bytes) seen in A and AAAA answers and names they belong to,
+/// with expiration time of each address record
+#[derive(Default)]
+pub(crate) struct ReverseIndex {
+    map: HashMap<Vec<u8>, HashMap<String, Time>>,
+    /// Number of addresses after last removal of expired ones
+    pruned_len: usize,
+}
+
+impl ReverseIndex {
+    /// Remember addresses of the answer. Expired records are not added.
+    pub fn add(&mut self, dom: &str, ce2: &CacheEntry2, now: Time, opts: &Options) {
+        for x in &ce2.a {
+            let ttl = clamp::clamp(opts.min_ttl, x.ttl, opts.max_ttl);
+            let expires = ce2.t + Time::from(ttl);
+            if expires <= now {
+                continue;
+            }
+            let names = self.map.entry(x.ip.clone()).or_default();
+            let e = names.entry(dom.to_string()).or_insert(expires);
+            *e = (*e).max(expires);
+        }
+        if self.map.len() > self.pruned_len * 2 + 1024 {
+            self.prune(now);
+        }
+    }
+
+    /// Forget the name for those addresses, e.g. when they are replaced by new answer
+    pub fn remove(&mut self, dom: &str, ce2: &CacheEntry2) {
+        for x in &ce2.a {
+            self.forget(&x.ip, dom);
+        }
+    }
+
+    /// Forget one name of the address
+    fn forget(&mut self, ip: &[u8], dom: &str) {
+        let now_empty = match self.map.get_mut(ip) {
+            Some(names) => {
+                names.remove(dom);
+                names.is_empty()
+            }
+            None => false,
+        };
+        if now_empty {
+            self.map.remove(ip);
+        }
+    }
+
+    /// Names of the address with remaining TTLs, sorted by name
+    pub fn lookup(&self, ip: &[u8], now: Time) -> Vec<(String, Ttl)> {
+        let mut v: Vec<_> = match self.map.get(ip) {
+            Some(names) => names
+                .iter()
+                .filter(|&(_, &expires)| expires > now)
+                .map(|(dom, &expires)| (dom.clone(), (expires - now).min(u64::from(Ttl::MAX)) as Ttl))
+                .collect(),
+            None => vec![],
+        };
+        v.sort();
+        v
+    }
+
+    fn prune(&mut self, now: Time) {
+        for names in self.map.values_mut() {
+            names.retain(|_, &mut expires| expires > now);
+        }
+        self.map.retain(|_, names| !names.is_empty());
+        self.pruned_len = self.map.len();
+        debug!("reverse index: {} addresses", self.pruned_len);
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+}
+
+/// Address bytes from `4.3.2.1.in-addr.arpa` or from 32 nibbles followed by `ip6.arpa`.
+/// Shorter names (of whole networks) give `None`.
+pub(crate) fn parse_reverse_name(dom: &str) -> Option<Vec<u8>> {
+    let dom = dom.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(rest) = dom.strip_suffix(".in-addr.arpa") {
+        let mut v = rest.split('.').map(|l| l.parse::<u8>().ok()).collect::<Option<Vec<u8>>>()?;
+        if v.len() != 4 {
+            return None;
+        }
+        v.reverse();
+        return Some(v);
+    }
+    if let Some(rest) = dom.strip_suffix(".ip6.arpa") {
+        let nibbles = rest
+            .split('.')
+            .map(|l| if l.len() == 1 { u8::from_str_radix(l, 16).ok() } else { None })
+            .collect::<Option<Vec<u8>>>()?;
+        if nibbles.len() != 32 {
+            return None;
+        }
+        return Some(nibbles.rchunks(2).map(|c| (c[1] << 4) | c[0]).collect());
+    }
+    None
+}
+
+/// Delete the entry and forget its addresses. Returns whether it was present.
+pub(crate) fn delete_entry<DB: Database>(db: &mut DB, reverse: &mut ReverseIndex, dom: &str) -> BoxResult<bool> {
+    let entry = db.get(dom)?;
+    let present = entry.is_some();
+    if let Some(ce) = entry {
+        for ce2 in ce.a4.iter().chain(ce.a6.iter()) {
+            reverse.remove(dom, ce2);
+        }
+        db.delete(dom)?;
+    }
+    Ok(present)
+}
+
+fn encode_name(dom: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(dom.len() + 2);
+    for l in dom.trim_end_matches('.').split('.') {
+        buf.push(l.len() as u8);
+        buf.extend_from_slice(l.as_bytes());
+    }
+    buf.push(0);
+    buf
+}
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Fill the reverse index from the database, e.g. at startup. Returns number of addresses.
+    pub fn build_reverse_index(&mut self) -> BoxResult<usize> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let opts = &self.opts;
+        let reverse = &mut self.reverse;
+        self.db.scan("", &mut |dom, ce| {
+            for ce2 in ce.a4.iter().chain(ce.a6.iter()) {
+                reverse.add(dom, ce2, now, opts);
+            }
+            true
+        })?;
+        Ok(reverse.len())
+    }
+
+    /// At most [`MAX_PTR_NAMES`] names for single PTR question about a single address, if any are known.
+    /// Names which no longer have the address in the database (e.g. deleted by
+    /// `dnscache delete` or `purge` from another process) are dropped from the index.
+    pub(crate) fn ptr_answer(&mut self, p: &Packet, now: Time) -> BoxResult<Option<Vec<(String, Ttl)>>> {
+        if p.questions.len() != 1 {
+            return Ok(None);
+        }
+        let q = &p.questions[0];
+        if q.qtype != PTR || (q.qclass != IN && q.qclass != QCAny) {
+            return Ok(None);
+        }
+        let ip = match parse_reverse_name(&q.qname.to_string()) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let mut names = Vec::new();
+        for (dom, ttl) in self.reverse.lookup(&ip, now) {
+            if names.len() >= MAX_PTR_NAMES {
+                break;
+            }
+            let ce = get_entry(&mut self.db, &dom);
+            let has_ip = ce.as_ref().is_some_and(|ce| {
+                ce.a4.iter().chain(ce.a6.iter()).any(|ce2| ce2.a.iter().any(|x| x.ip == ip))
+            });
+            if has_ip {
+                names.push((dom, ttl));
+            } else {
+                self.reverse.forget(&ip, &dom);
+            }
+        }
+        if names.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(names))
+        }
+    }
+
+    /// Answer PTR query with names from the reverse index.
+    /// Names not fitting into `max_size` bytes are left out and the reply is marked truncated.
+    pub(crate) fn reply_ptr(
+        &mut self,
+        src: N::ClientId,
+        received: SystemTime,
+        query: &[u8],
+        names: &[(String, Ttl)],
+        max_size: usize,
+    ) -> BoxResult<()> {
+        info!("  from reverse index");
+        Metrics::inc(&self.metrics.cached);
+        let (qs, qend) = raw_questions(query)?;
+        let q = &qs[0];
+        let mut size = qend;
+        let mut truncated = false;
+        let mut answers = Vec::with_capacity(names.len().min(MAX_PTR_NAMES));
+        for &(ref dom, ttl) in names {
+            let rdata = encode_name(dom);
+            size += 12 + rdata.len();
+            if size > max_size {
+                truncated = true;
+                break;
+            }
+            answers.push((q.offset, q.qtype, ttl, rdata));
+        }
+        let mut reply = synthesize_reply(query, 0, &answers)?;
+        if truncated {
+            reply[2] |= 0x02; // tc
+        }
+        self.net.send_to_client(&reply, src)?;
+        tap_reply(&mut self.taps, src, received, &reply, Outcome::Cached);
+        Ok(())
+    }
+}
@@ -0,0 +1,415 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+use super::*;
+
+use dns_parser::{Packet, Question};
+use details::{adjust_ttl, get_entry, raw_questions, send_dns_reply, synthesize_reply};
+use local::LocalName;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tap::{tap_client_response, tap_reply};
+use wire::name_from_str;
+
+/// Policy of a trigger
+#[derive(Debug, Clone)]
+pub(crate) enum RpzAction {
+    /// `CNAME .`
+    NxDomain,
+    /// `CNAME *.`
+    NoData,
+    /// `CNAME rpz-passthru.`: answer normally, skipping other zones and blocklists
+    Passthru,
+    /// `CNAME rpz-drop.`: no reply at all
+    Drop,
+    /// A, AAAA or CNAME records to answer with instead
+    LocalData(LocalName),
+}
+
+/// Response Policy Zone: QNAME and response IP triggers with their actions
+#[derive(Debug, Clone, Default)]
+pub struct Rpz {
+    name: String,
+    qnames: HashMap<String, RpzAction>,
+    /// `*.example` triggers, keyed by `example`
+    wildcards: HashMap<String, RpzAction>,
+    /// Network address and prefix length
+    ips: Vec<(IpAddr, u8, RpzAction)>,
+}
+
+/// `ads.example.rpz.local` -> `ads.example` for origin `rpz.local`
+fn strip_origin<'a>(name: &'a str, origin: &str) -> Option<&'a str> {
+    if origin.is_empty() {
+        Some(name)
+    } else if name == origin {
+        Some("")
+    } else if name.ends_with(origin) && name.as_bytes()[name.len() - origin.len() - 1] == b'.' {
+        Some(&name[..name.len() - origin.len() - 1])
+    } else {
+        None
+    }
+}
+
+/// `24.0.2.0.192` -> 192.0.2.0/24, `48.zz.db8.2001` -> 2001:db8::/48
+fn parse_ip_trigger(s: &str) -> Option<(IpAddr, u8)> {
+    let mut labels: Vec<&str> = s.split('.').collect();
+    let prefix: u8 = labels.remove(0).parse().ok()?;
+    labels.reverse();
+    if labels.len() == 4 && prefix <= 32 {
+        if let Ok(ip) = labels.join(".").parse::<Ipv4Addr>() {
+            return Some((IpAddr::V4(ip), prefix));
+        }
+    }
+    if prefix > 128 {
+        return None;
+    }
+    let groups: Vec<&str> = labels.iter().map(|&l| if l == "zz" { "" } else { l }).collect();
+    let mut text = groups.join(":");
+    if groups.first() == Some(&"") {
+        text.insert(0, ':');
+    }
+    if groups.last() == Some(&"") {
+        text.push(':');
+    }
+    text.parse::<Ipv6Addr>().ok().map(|ip| (IpAddr::V6(ip), prefix))
+}
+
+/// Several records of the same owner make one local-data action; other combinations conflict
+fn add_action<K: Hash + Eq + ::std::fmt::Debug>(map: &mut HashMap<K, RpzAction>, key: K, action: RpzAction) {
+    use std::collections::hash_map::Entry;
+    match map.entry(key) {
+        Entry::Vacant(e) => {
+            e.insert(action);
+        }
+        Entry::Occupied(mut e) => match (e.get_mut(), action) {
+            (&mut RpzAction::LocalData(ref mut old), RpzAction::LocalData(new)) => {
+                old.a4.extend(new.a4);
+                old.a6.extend(new.a6);
+                if new.cname.is_some() {
+                    old.cname = new.cname;
+                }
+            }
+            _ => warn!("RPZ: conflicting actions for {:?}, keeping the first one", e.key()),
+        },
+    }
+}
+
+impl RpzAction {
+    pub(crate) fn is_passthru(&self) -> bool {
+        matches!(*self, RpzAction::Passthru)
+    }
+}
+
+impl Rpz {
+    /// Load zone file text. The origin is the owner of the SOA record.
+    /// QNAME (including `*.` wildcards) and `rpz-ip` triggers are supported; other triggers
+    /// and local data other than A, AAAA and CNAME are skipped with a warning.
+    /// `name` identifies the zone in logs and metrics.
+    pub fn parse(name: &str, text: &str, default_ttl: Ttl) -> BoxResult<Rpz> {
+        let records = parse_zone(text, "")?;
+        let origin = match records.iter().find(|r| match r.data {
+            ZoneData::Other(ref t, _) => t == "SOA",
+            _ => false,
+        }) {
+            Some(r) => r.name.to_ascii_lowercase(),
+            None => Err(format!("RPZ {}: no SOA record", name))?,
+        };
+
+        let mut qnames = HashMap::new();
+        let mut wildcards = HashMap::new();
+        let mut ips = HashMap::new();
+        for r in &records {
+            // Triggers are kept lowercase, as names are compared ignoring letter case
+            let dom = r.name.to_ascii_lowercase();
+            let owner = match strip_origin(&dom, &origin) {
+                Some("") => continue, // SOA, NS
+                Some(x) => x,
+                None => {
+                    warn!("RPZ {}: {} is outside of {}", name, r.name, origin);
+                    continue;
+                }
+            };
+            let ttl = r.ttl.unwrap_or(default_ttl);
+            let mut ld = LocalName::default();
+            let action = match r.data {
+                ZoneData::CNAME(ref target) => {
+                    let t = target.to_ascii_lowercase();
+                    match strip_origin(&t, &origin).unwrap_or(&t) {
+                        "." => RpzAction::NxDomain,
+                        "*" => RpzAction::NoData,
+                        "rpz-passthru" => RpzAction::Passthru,
+                        "rpz-drop" => RpzAction::Drop,
+                        t if t.starts_with("rpz-") || t.starts_with("*.") => {
+                            warn!("RPZ {}: unsupported action {} of {}", name, t, owner);
+                            continue;
+                        }
+                        _ => {
+                            ld.cname = Some((target.clone(), ttl));
+                            RpzAction::LocalData(ld)
+                        }
+                    }
+                }
+                ZoneData::A(ip) => {
+                    ld.a4.push(AddrTtl { ttl, ip: ip.octets().to_vec() });
+                    RpzAction::LocalData(ld)
+                }
+                ZoneData::AAAA(ip) => {
+                    ld.a6.push(AddrTtl { ttl, ip: ip.octets().to_vec() });
+                    RpzAction::LocalData(ld)
+                }
+                ZoneData::Other(ref t, _) => {
+                    warn!("RPZ {}: unsupported local data {} of {}", name, t, owner);
+                    continue;
+                }
+            };
+
+            if let Some(ip) = owner.strip_suffix(".rpz-ip") {
+                match parse_ip_trigger(ip) {
+                    Some(net) => add_action(&mut ips, net, action),
+                    None => warn!("RPZ {}: bad IP trigger {}", name, owner),
+                }
+            } else if owner.ends_with(".rpz-nsdname") || owner.ends_with(".rpz-nsip") || owner.ends_with(".rpz-client-ip") {
+                warn!("RPZ {}: unsupported trigger {}", name, owner);
+            } else if let Some(suffix) = owner.strip_prefix("*.") {
+                add_action(&mut wildcards, suffix.to_string(), action);
+            } else {
+                add_action(&mut qnames, owner.to_string(), action);
+            }
+        }
+
+        let mut ips: Vec<_> = ips.into_iter().map(|((ip, prefix), a)| (ip, prefix, a)).collect();
+        // Longest prefix first, so the first match wins
+        ips.sort_by_key(|x| ::std::cmp::Reverse(x.1));
+        Ok(Rpz {
+            name: name.to_string(),
+            qnames,
+            wildcards,
+            ips,
+        })
+    }
+
+    /// Name given on loading
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Number of triggers
+    pub fn len(&self) -> usize {
+        self.qnames.len() + self.wildcards.len() + self.ips.len()
+    }
+
+    /// There are no triggers
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn qname_action(&self, dom: &str) -> Option<&RpzAction> {
+        let dom = dom.to_ascii_lowercase();
+        if let Some(a) = self.qnames.get(&dom) {
+            return Some(a);
+        }
+        let mut d = dom.as_str();
+        while let Some(i) = d.find('.') {
+            d = &d[i + 1..];
+            if let Some(a) = self.wildcards.get(d) {
+                return Some(a);
+            }
+        }
+        None
+    }
+
+    fn ip_action(&self, ips: &[IpAddr]) -> Option<&RpzAction> {
+        self.ips
+            .iter()
+            .find(|&&(net, prefix, _)| ips.iter().any(|&ip| in_network(ip, net, prefix)))
+            .map(|x| &x.2)
+    }
+}
+
+/// Answer records for local data
+struct LocalDataAnswer {
+    /// Owner, target, TTL
+    cnames: Vec<(String, String, Ttl)>,
+    /// Name the addresses belong to
+    owner: String,
+    a4: Vec<AddrTtl>,
+    a6: Vec<AddrTtl>,
+}
+
+/// A CNAME target gets whatever addresses are cached for it
+fn local_data_records<DB: Database>(
+    db: &mut DB,
+    opts: &Options,
+    now: Time,
+    dom: &str,
+    ld: &LocalName,
+) -> LocalDataAnswer {
+    match ld.cname {
+        None => LocalDataAnswer {
+            cnames: vec![],
+            owner: dom.to_string(),
+            a4: ld.a4.clone(),
+            a6: ld.a6.clone(),
+        },
+        Some((ref target, ttl)) => {
+            let cnames = vec![(dom.to_string(), target.clone(), ttl)];
+            let ce = get_entry(db, target).unwrap_or_default();
+            let adj = |x: Option<CacheEntry2>| {
+                x.map_or(vec![], |x| adjust_ttl(&x.a, now, x.t, opts.max_ttl, opts.min_ttl).1)
+            };
+            LocalDataAnswer {
+                cnames,
+                owner: target.clone(),
+                a4: adj(ce.a4),
+                a6: adj(ce.a6),
+            }
+        }
+    }
+}
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Replace response policy zones, e.g. after files they came from have changed.
+    /// Zones are consulted in order, before blocklists.
+    pub fn set_rpz(&mut self, zones: Vec<Rpz>) {
+        self.rpz = zones;
+    }
+
+    fn count_rpz_hit(&self, zone: usize, trigger: &str) {
+        let name = &self.rpz[zone].name;
+        info!("  {} trigger of RPZ {}", trigger, name);
+        Metrics::inc(&self.metrics.rpz);
+        self.metrics.inc_rpz(name);
+    }
+
+    /// First QNAME trigger matching any of the questions, with the zone index
+    pub(crate) fn rpz_qname_policy(&self, qs: &[SimplifiedQuestion]) -> Option<(usize, RpzAction)> {
+        for (i, z) in self.rpz.iter().enumerate() {
+            for q in qs {
+                if let Some(a) = z.qname_action(&q.dom) {
+                    return Some((i, a.clone()));
+                }
+            }
+        }
+        None
+    }
+
+    /// Reply to the request according to the action, without asking upstream.
+    /// Nothing is sent for `Drop`.
+    fn send_rpz_reply(&mut self, r: &SimplifiedRequest<N::ClientId>, action: &RpzAction, now: Time) -> BoxResult<Option<Vec<u8>>> {
+        let mut cnames = vec![];
+        let mut ans_a4 = vec![];
+        let mut ans_a6 = vec![];
+        let rcode = match *action {
+            RpzAction::NxDomain => 3,
+            RpzAction::NoData | RpzAction::Passthru => 0,
+            RpzAction::Drop => return Ok(None),
+            RpzAction::LocalData(ref ld) => {
+                for q in &r.q {
+                    let lda = local_data_records(&mut self.db, &self.opts, now, &q.dom, ld);
+                    cnames.extend(lda.cnames);
+                    if q.a4 {
+                        ans_a4.push((lda.owner.clone(), lda.a4));
+                    }
+                    if q.a6 {
+                        ans_a6.push((lda.owner, lda.a6));
+                    }
+                }
+                0
+            }
+        };
+        Ok(Some(send_dns_reply(&self.net, r, rcode, &cnames, &ans_a4, &ans_a6, &Default::default())?))
+    }
+
+    /// Answer query according to QNAME trigger, without asking upstream.
+    /// Queries other than A, AAAA or ANY get no addresses, only the CNAME of local data.
+    pub(crate) fn reply_rpz(
+        &mut self,
+        src: N::ClientId,
+        received: SystemTime,
+        query: &[u8],
+        q: Vec<SimplifiedQuestion>,
+        zone: usize,
+        action: &RpzAction,
+    ) -> BoxResult<()> {
+        self.count_rpz_hit(zone, "QNAME");
+        if let RpzAction::Drop = *action {
+            self.tap_dropped(src, received, query);
+            return Ok(());
+        }
+        let qs = raw_questions(query)?.0;
+        if qs.iter().any(|x| x.qtype != 0x0001 && x.qtype != 0x001C && x.qtype != 0x00FF) {
+            let mut answers = Vec::new();
+            if let RpzAction::LocalData(LocalName { cname: Some((ref target, ttl)), .. }) = *action {
+                for q in &qs {
+                    answers.push((q.offset, 0x0005, ttl, name_from_str(target)));
+                }
+            }
+            let rcode = if let RpzAction::NxDomain = *action { 3 } else { 0 };
+            let reply = synthesize_reply(query, rcode, &answers)?;
+            self.net.send_to_client(&reply, src)?;
+            tap_reply(&mut self.taps, src, received, &reply, Outcome::Rpz);
+            return Ok(());
+        }
+        let r = SimplifiedRequest {
+            id: (u16::from(query[0]) << 8) | u16::from(query[1]),
+            clientid: Some(src),
+            q,
+            inhibit_send: false,
+            received,
+            upstream: 0,
+            want_ad: false,
+            dnssec_ok: false,
+            checking_disabled: false,
+            query: vec![],
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        if let Some(reply) = self.send_rpz_reply(&r, action, now)? {
+            tap_client_response(&mut self.taps, &r, &reply, Outcome::Rpz);
+        }
+        Ok(())
+    }
+
+    /// First response IP trigger matching any of the addresses, with the zone index.
+    /// Passthru means no policy. Zones after the one with QNAME passthru trigger for
+    /// the questions are not consulted, nor is that zone itself.
+    pub(crate) fn rpz_ip_policy(&self, questions: &[Question], ips: &[IpAddr]) -> Option<(usize, RpzAction)> {
+        let mut zones = self.rpz.len();
+        for q in questions {
+            let dom = q.qname.to_string();
+            if let Some(i) = self.rpz.iter().position(|z| z.qname_action(&dom).is_some()) {
+                if let Some(&RpzAction::Passthru) = self.rpz[i].qname_action(&dom) {
+                    zones = zones.min(i);
+                }
+            }
+        }
+        for (i, z) in self.rpz[..zones].iter().enumerate() {
+            match z.ip_action(ips) {
+                None => continue,
+                Some(&RpzAction::Passthru) => return None,
+                Some(a) => return Some((i, a.clone())),
+            }
+        }
+        None
+    }
+
+    /// Answer requests waiting for the domains of upstream reply `p` according to
+    /// response IP trigger. The reply itself is not saved.
+    pub(crate) fn reply_rpz_waiting(&mut self, p: &Packet, zone: usize, action: &RpzAction) -> BoxResult<()> {
+        self.count_rpz_hit(zone, "response IP");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        for q in &p.questions {
+            let dom = q.qname.to_string();
+            for r in self.take_subscribed_requests(&dom) {
+                let client = match (r.inhibit_send, r.clientid) {
+                    (false, Some(c)) => c,
+                    _ => continue,
+                };
+                match self.send_rpz_reply(&r, action, now)? {
+                    Some(reply) => tap_client_response(&mut self.taps, &r, &reply, Outcome::Rpz),
+                    None => self.tap_dropped(client, r.received, &r.query),
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,444 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! Database backends of the dnscache binary. All of them store CBOR-encoded values
+//! tagged with format version: `[version, value]`.
+
+use rusty_leveldb::DB as LevelDB;
+use rusty_leveldb::{LdbIterator, WriteBatch};
+use serde::Serialize;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde_cbor::{ObjectKey, Value};
+use serde_cbor::de::from_slice;
+use serde_cbor::ser::to_vec;
+use std::path::Path;
+use std::str::FromStr;
+use dnscache::{Database, CacheEntry, BoxResult, BatchOp, MemoryDatabase};
+
+/// Version of the stored value layout. Values written before versioning was introduced
+/// are plain CBOR maps and are treated as version 0.
+///
+/// * 0: addresses are `{"ttl": ttl, "ip": bytes}` maps
+/// * 1: addresses are `[ttl, bytes]` pairs
+pub const FORMAT_VERSION: u32 = 1;
+
+pub fn encode<T: Serialize>(x: &T) -> BoxResult<Vec<u8>> {
+    Ok(to_vec(&(FORMAT_VERSION, x))?)
+}
+
+pub fn decode<T: DeserializeOwned>(b: &[u8]) -> BoxResult<T> {
+    let (version, value) = match b.first() {
+        Some(&x) if x & 0xE0 == 0xA0 => (0, from_slice(b)?),
+        Some(&x) if x & 0xE0 == 0x80 => {
+            let (version, _): (u32, IgnoredAny) = from_slice(b)?;
+            if version == FORMAT_VERSION {
+                let (_, x): (u32, T) = from_slice(b)?;
+                return Ok(x);
+            }
+            let (_, value): (u32, Value) = from_slice(b)?;
+            (version, value)
+        }
+        _ => Err("unrecognized stored value")?,
+    };
+    Ok(from_slice(&to_vec(&upgrade(version, value)?)?)?)
+}
+
+/// Convert value of older layout to the current one, step by step
+fn upgrade(mut version: u32, mut value: Value) -> BoxResult<Value> {
+    if version > FORMAT_VERSION {
+        Err(format!("stored value has unsupported format version {}", version))?;
+    }
+    while version < FORMAT_VERSION {
+        value = match version {
+            0 => pair_addresses(value)?,
+            _ => Err(format!("no conversion from format version {}", version))?,
+        };
+        version += 1;
+    }
+    Ok(value)
+}
+
+/// Version 0 to 1: replace address maps with pairs in `CacheEntry` (`"a4"` and `"a6"`)
+/// or `CacheEntry2` (SQLite rows) value
+fn pair_addresses(value: Value) -> BoxResult<Value> {
+    let mut m = match value {
+        Value::Object(m) => m,
+        _ => Err("stored value is not a map")?,
+    };
+    for key in &["a4", "a6"] {
+        let key = ObjectKey::String(key.to_string());
+        if let Some(x) = m.remove(&key) {
+            let x = match x {
+                Value::Null => x,
+                x => pair_addresses(x)?,
+            };
+            m.insert(key, x);
+        }
+    }
+    let key = ObjectKey::String("a".to_string());
+    if let Some(a) = m.remove(&key) {
+        let a = match a {
+            Value::Array(a) => a,
+            _ => Err("stored addresses are not a list")?,
+        };
+        let mut pairs = Vec::with_capacity(a.len());
+        for x in a {
+            let mut x = match x {
+                Value::Object(x) => x,
+                _ => Err("stored address is not a map")?,
+            };
+            let ttl = x.remove(&ObjectKey::String("ttl".to_string()));
+            let ip = x.remove(&ObjectKey::String("ip".to_string()));
+            match (ttl, ip) {
+                (Some(ttl), Some(ip)) => pairs.push(Value::Array(vec![ttl, ip])),
+                _ => Err("stored address lacks ttl or ip")?,
+            }
+        }
+        m.insert(key, Value::Array(pairs));
+    }
+    Ok(Value::Object(m))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Backend {
+    LevelDb,
+    #[cfg(feature = "sled")]
+    Sled,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+    Memory,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "leveldb" => Ok(Backend::LevelDb),
+            #[cfg(feature = "sled")]
+            "sled" => Ok(Backend::Sled),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(Backend::Sqlite),
+            "memory" => Ok(Backend::Memory),
+            _ => Err(format!("unknown or not compiled in database backend: {}", s)),
+        }
+    }
+}
+
+pub fn open(backend: Backend, path: &Path) -> BoxResult<Box<dyn Database>> {
+    Ok(match backend {
+        Backend::LevelDb => {
+            let dbopts: ::rusty_leveldb::Options = Default::default();
+            Box::new(LevelDatabase(LevelDB::open(path, dbopts)?))
+        }
+        #[cfg(feature = "sled")]
+        Backend::Sled => Box::new(SledDatabase(::sled::open(path)?)),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Box::new(SqliteDatabase::open(path)?),
+        Backend::Memory => Box::new(MemoryDatabase::new()),
+    })
+}
+
+pub struct LevelDatabase(LevelDB);
+
+impl Database for LevelDatabase {
+    fn get(&mut self, dom: &str) -> BoxResult<Option<CacheEntry>> {
+        if let Some(ceb) = self.0.get(dom.as_bytes()) {
+            Ok(Some(decode(&ceb[..])?))
+        } else {
+            Ok(None)
+        }
+    }
+    fn put(&mut self, dom: &str, entry: &CacheEntry) -> BoxResult<()> {
+        self.0.put(dom.as_bytes(), &encode(entry)?[..])?;
+        Ok(())
+    }
+    fn flush(&mut self) -> BoxResult<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+    fn delete(&mut self, dom: &str) -> BoxResult<()> {
+        self.0.delete(dom.as_bytes())?;
+        Ok(())
+    }
+    fn scan(&mut self, from: &str, f: &mut dyn FnMut(&str, CacheEntry) -> bool) -> BoxResult<()> {
+        let mut unreadable = vec![];
+        {
+            let mut it = self.0.new_iter()?;
+            it.seek(from.as_bytes());
+            let (mut k, mut v) = (vec![], vec![]);
+            while it.current(&mut k, &mut v) {
+                let dom = String::from_utf8_lossy(&k);
+                match decode(&v[..]) {
+                    Ok(ce) => if !f(&dom, ce) {
+                        break;
+                    },
+                    Err(e) => {
+                        warn!("deleting unreadable entry for {}: {}", dom, e);
+                        unreadable.push(k.clone());
+                    }
+                }
+                if !it.advance() {
+                    break;
+                }
+            }
+        }
+        for k in unreadable {
+            self.0.delete(&k)?;
+        }
+        Ok(())
+    }
+    fn write_batch(&mut self, batch: Vec<BatchOp>) -> BoxResult<()> {
+        let mut wb = WriteBatch::new();
+        for op in batch {
+            match op {
+                BatchOp::Put(dom, entry) => wb.put(dom.as_bytes(), &encode(&entry)?[..]),
+                BatchOp::Delete(dom) => wb.delete(dom.as_bytes()),
+            }
+        }
+        self.0.write(wb, false)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sled")]
+pub struct SledDatabase(::sled::Db);
+
+#[cfg(feature = "sled")]
+impl Database for SledDatabase {
+    fn get(&mut self, dom: &str) -> BoxResult<Option<CacheEntry>> {
+        if let Some(ceb) = self.0.get(dom.as_bytes())? {
+            Ok(Some(decode(&ceb[..])?))
+        } else {
+            Ok(None)
+        }
+    }
+    fn put(&mut self, dom: &str, entry: &CacheEntry) -> BoxResult<()> {
+        self.0.insert(dom.as_bytes(), encode(entry)?)?;
+        Ok(())
+    }
+    fn flush(&mut self) -> BoxResult<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+    fn delete(&mut self, dom: &str) -> BoxResult<()> {
+        self.0.remove(dom.as_bytes())?;
+        Ok(())
+    }
+    fn scan(&mut self, from: &str, f: &mut dyn FnMut(&str, CacheEntry) -> bool) -> BoxResult<()> {
+        for kv in self.0.range(from.as_bytes()..) {
+            let (k, v) = kv?;
+            let dom = String::from_utf8_lossy(&k);
+            match decode(&v[..]) {
+                Ok(ce) => if !f(&dom, ce) {
+                    break;
+                },
+                Err(e) => {
+                    warn!("deleting unreadable entry for {}: {}", dom, e);
+                    self.0.remove(&k)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn write_batch(&mut self, batch: Vec<BatchOp>) -> BoxResult<()> {
+        let mut b = ::sled::Batch::default();
+        for op in batch {
+            match op {
+                BatchOp::Put(dom, entry) => b.insert(dom.as_bytes(), encode(&entry)?),
+                BatchOp::Delete(dom) => b.remove(dom.as_bytes()),
+            }
+        }
+        self.0.apply_batch(b)?;
+        Ok(())
+    }
+}
+
+/// One row per domain and record type (1 for A, 28 for AAAA).
+/// Entries with neither are kept as a row of type 0 with empty value, holding only the last use time.
+#[cfg(feature = "sqlite")]
+pub struct SqliteDatabase(::rusqlite::Connection);
+
+#[cfg(feature = "sqlite")]
+impl SqliteDatabase {
+    pub fn open(path: &Path) -> BoxResult<Self> {
+        let c = ::rusqlite::Connection::open(path)?;
+        c.execute_batch(
+            "CREATE TABLE IF NOT EXISTS cache (
+                name TEXT NOT NULL,
+                type INTEGER NOT NULL,
+                used INTEGER,
+                value BLOB NOT NULL,
+                PRIMARY KEY (name, type)
+            );",
+        )?;
+        Ok(SqliteDatabase(c))
+    }
+
+    fn put_rows(c: &::rusqlite::Connection, dom: &str, entry: &CacheEntry) -> BoxResult<()> {
+        c.execute("DELETE FROM cache WHERE name = ?1", ::rusqlite::params![dom])?;
+        let used = entry.u.map(|u| u as i64);
+        if entry.a4.is_none() && entry.a6.is_none() {
+            c.execute(
+                "INSERT INTO cache (name, type, used, value) VALUES (?1, 0, ?2, x'')",
+                ::rusqlite::params![dom, used],
+            )?;
+        }
+        for &(typ, ce2) in &[(1, &entry.a4), (28, &entry.a6)] {
+            if let Some(ref ce2) = *ce2 {
+                c.execute(
+                    "INSERT INTO cache (name, type, used, value) VALUES (?1, ?2, ?3, ?4)",
+                    ::rusqlite::params![dom, typ, used, encode(ce2)?],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_row(entry: &mut CacheEntry, typ: i64, used: Option<i64>, value: &[u8]) -> BoxResult<()> {
+        match typ {
+            0 => (),
+            1 => entry.a4 = Some(decode(value)?),
+            28 => entry.a6 = Some(decode(value)?),
+            _ => Err(format!("unexpected record type {} in database", typ))?,
+        }
+        if let Some(u) = used {
+            entry.u = Some(u as u64);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Database for SqliteDatabase {
+    fn get(&mut self, dom: &str) -> BoxResult<Option<CacheEntry>> {
+        let mut stmt = self.0.prepare_cached("SELECT type, used, value FROM cache WHERE name = ?1")?;
+        let mut rows = stmt.query(::rusqlite::params![dom])?;
+        let mut entry: Option<CacheEntry> = None;
+        while let Some(row) = rows.next()? {
+            let value: Vec<u8> = row.get(2)?;
+            let ce = entry.get_or_insert_with(Default::default);
+            SqliteDatabase::add_row(ce, row.get(0)?, row.get(1)?, &value)?;
+        }
+        Ok(entry)
+    }
+    fn put(&mut self, dom: &str, entry: &CacheEntry) -> BoxResult<()> {
+        let tx = self.0.transaction()?;
+        SqliteDatabase::put_rows(&tx, dom, entry)?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn flush(&mut self) -> BoxResult<()> {
+        Ok(())
+    }
+    fn delete(&mut self, dom: &str) -> BoxResult<()> {
+        self.0.execute("DELETE FROM cache WHERE name = ?1", ::rusqlite::params![dom])?;
+        Ok(())
+    }
+    fn scan(&mut self, from: &str, f: &mut dyn FnMut(&str, CacheEntry) -> bool) -> BoxResult<()> {
+        let mut unreadable: Vec<(String, i64)> = vec![];
+        {
+            let mut stmt = self.0.prepare_cached(
+                "SELECT name, type, used, value FROM cache WHERE name >= ?1 ORDER BY name",
+            )?;
+            let mut rows = stmt.query(::rusqlite::params![from])?;
+            let mut current: Option<(String, CacheEntry)> = None;
+            while let Some(row) = rows.next()? {
+                let name: String = row.get(0)?;
+                let typ: i64 = row.get(1)?;
+                let value: Vec<u8> = row.get(3)?;
+                let same = current.as_ref().is_some_and(|c| c.0 == name);
+                if !same {
+                    if let Some((dom, ce)) = current.take() {
+                        if !f(&dom, ce) {
+                            break;
+                        }
+                    }
+                    current = Some((name.clone(), Default::default()));
+                }
+                let ce = &mut current.as_mut().unwrap().1;
+                if let Err(e) = SqliteDatabase::add_row(ce, typ, row.get(2)?, &value) {
+                    warn!("deleting unreadable entry for {}: {}", name, e);
+                    unreadable.push((name, typ));
+                }
+            }
+            if let Some((dom, ce)) = current {
+                f(&dom, ce);
+            }
+        }
+        for (name, typ) in unreadable {
+            self.0.execute("DELETE FROM cache WHERE name = ?1 AND type = ?2", ::rusqlite::params![name, typ])?;
+        }
+        Ok(())
+    }
+    fn write_batch(&mut self, batch: Vec<BatchOp>) -> BoxResult<()> {
+        let tx = self.0.transaction()?;
+        for op in batch {
+            match op {
+                BatchOp::Put(dom, entry) => SqliteDatabase::put_rows(&tx, &dom, &entry)?,
+                BatchOp::Delete(dom) => {
+                    tx.execute("DELETE FROM cache WHERE name = ?1", ::rusqlite::params![dom])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dnscache::{AddrTtl, CacheEntry2};
+
+    /// Unversioned value from the README, as written by builds before format versions
+    const UNVERSIONED: &[u8] = &[
+        0xa2, 0x62, 0x61, 0x34, 0xa2, 0x61, 0x74, 0x1a, 0x5a, 0x3a, 0xeb, 0xa7, 0x61, 0x61, 0x81, 0xa2,
+        0x63, 0x74, 0x74, 0x6c, 0x19, 0x02, 0x57, 0x62, 0x69, 0x70, 0x44, 0x40, 0x47, 0xa8, 0xd3, 0x62,
+        0x61, 0x36, 0xa2, 0x61, 0x74, 0x1a, 0x5a, 0x3a, 0xeb, 0xa7, 0x61, 0x61, 0x81, 0xa2, 0x63, 0x74,
+        0x74, 0x6c, 0x19, 0x02, 0x57, 0x62, 0x69, 0x70, 0x50, 0x20, 0x01, 0x04, 0x70, 0x00, 0x01, 0x03,
+        0xa8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x11,
+    ];
+
+    fn sample() -> CacheEntry {
+        let ce2 = |ip: &[u8]| CacheEntry2 {
+            t: 1513810855,
+            a: vec![AddrTtl { ttl: 599, ip: ip.to_vec() }],
+            ..Default::default()
+        };
+        CacheEntry {
+            a4: Some(ce2(&[0x40, 0x47, 0xa8, 0xd3])),
+            a6: Some(ce2(&[0x20, 0x01, 0x04, 0x70, 0x00, 0x01, 0x03, 0xa8, 0, 0, 0, 0, 0, 0, 0x02, 0x11])),
+            u: None,
+        }
+    }
+
+    #[test]
+    fn unversioned_entry() {
+        let ce: CacheEntry = decode(UNVERSIONED).unwrap();
+        assert_eq!(ce, sample());
+    }
+
+    #[test]
+    fn unversioned_sqlite_row() {
+        // {"t": 1513810855, "a": []}: negative answer
+        let row = [0xa2, 0x61, 0x74, 0x1a, 0x5a, 0x3a, 0xeb, 0xa7, 0x61, 0x61, 0x80];
+        let ce2: CacheEntry2 = decode(&row).unwrap();
+        assert_eq!(ce2.t, 1513810855);
+        assert!(ce2.is_negative());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let b = encode(&sample()).unwrap();
+        assert_eq!(b[0], 0x82);
+        assert_eq!(b[1], FORMAT_VERSION as u8);
+        assert!(b.len() < UNVERSIONED.len());
+        let ce: CacheEntry = decode(&b).unwrap();
+        assert_eq!(ce, sample());
+    }
+
+    #[test]
+    fn newer_version() {
+        let b = to_vec(&(FORMAT_VERSION + 1, Value::Null)).unwrap();
+        assert!(decode::<CacheEntry>(&b).is_err());
+    }
+}
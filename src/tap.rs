@@ -0,0 +1,179 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+use super::*;
+
+use dns_parser::{Packet, RRData};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+/// How the reply to a client was obtained
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    /// Answered from fresh cache entries
+    Cached,
+    /// Answered from stale cache entries while refreshing them from upstream
+    Stale,
+    /// Answered from fresh negative cache entries
+    Negative,
+    /// Answered after waiting for upstream
+    Queued,
+    /// Upstream reply forwarded as is
+    Forwarded,
+    /// Query was not understood and got no reply
+    Dropped,
+    /// Name is in a blocklist, answered without asking upstream
+    Blocked,
+    /// Answered according to a response policy zone
+    Rpz,
+    /// Over a rate limit: dropped or answered with empty truncated reply
+    RateLimited,
+    /// Client denied by an access control list: refused or dropped
+    Denied,
+    /// Upstream answer failed DNSSEC validation, SERVFAIL sent
+    Bogus,
+}
+
+impl Outcome {
+    /// Short lowercase name, e.g. for logs
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Outcome::Cached => "cached",
+            Outcome::Stale => "stale",
+            Outcome::Negative => "negative",
+            Outcome::Queued => "queued",
+            Outcome::Forwarded => "forwarded",
+            Outcome::Dropped => "dropped",
+            Outcome::Blocked => "blocked",
+            Outcome::Rpz => "rpz",
+            Outcome::RateLimited => "ratelimited",
+            Outcome::Denied => "denied",
+            Outcome::Bogus => "bogus",
+        }
+    }
+}
+
+/// What happened to one client query, for query logs
+#[derive(Debug, Clone)]
+pub struct QuerySummary<C> {
+    /// Who asked
+    pub client: C,
+    /// When the query came
+    pub received: SystemTime,
+    /// Name from the first question. Empty if the query could not be parsed.
+    pub qname: String,
+    /// Type from the first question, like `A` or `MX`. Empty if the query could not be parsed.
+    pub qtype: String,
+    /// How it was answered
+    pub outcome: Outcome,
+    /// A and AAAA addresses in the reply
+    pub answers: Vec<IpAddr>,
+    /// Smallest TTL of answer records, if any
+    pub ttl: Option<Ttl>,
+    /// Time from receiving the query to sending the reply, including waiting for upstream
+    pub latency: Duration,
+}
+
+/// Observer of DNS messages passing through [`DnsCache`], e.g. for dnstap or query logs.
+/// Called from the serving thread, so implementations should not block.
+pub trait Tap<C> {
+    /// Query received from a client
+    fn client_query(&mut self, _client: C, _query: &[u8], _time: SystemTime) {}
+    /// Reply sent to a client. `query_time` is when the query was received.
+    fn client_response(&mut self, _client: C, _response: &[u8], _outcome: Outcome, _query_time: SystemTime) {}
+    /// Query sent to the upstream
+    fn forwarder_query(&mut self, _upstream: UpstreamId, _query: &[u8]) {}
+    /// Reply received from the upstream
+    fn forwarder_response(&mut self, _upstream: UpstreamId, _response: &[u8]) {}
+    /// Client query was answered or dropped
+    fn query_done(&mut self, _summary: &QuerySummary<C>) {}
+}
+
+fn summarize<C>(client: C, received: SystemTime, outcome: Outcome, packet: &[u8]) -> QuerySummary<C> {
+    let mut s = QuerySummary {
+        client,
+        received,
+        qname: String::new(),
+        qtype: String::new(),
+        outcome,
+        answers: vec![],
+        ttl: None,
+        latency: SystemTime::now().duration_since(received).unwrap_or_default(),
+    };
+    if let Ok(p) = Packet::parse(packet) {
+        if let Some(q) = p.questions.first() {
+            s.qname = q.qname.to_string();
+            s.qtype = format!("{:?}", q.qtype);
+        }
+        for ans in &p.answers {
+            s.ttl = Some(s.ttl.map_or(ans.ttl, |t| t.min(ans.ttl)));
+            match ans.data {
+                RRData::A(ip) => s.answers.push(IpAddr::V4(ip)),
+                RRData::AAAA(ip) => s.answers.push(IpAddr::V6(ip)),
+                _ => {}
+            }
+        }
+    }
+    s
+}
+
+pub(crate) fn tap_client_response<C: Copy>(
+    taps: &mut [Box<dyn Tap<C>>],
+    r: &SimplifiedRequest<C>,
+    reply: &[u8],
+    outcome: Outcome,
+) {
+    if let Some(client) = r.clientid {
+        tap_reply(taps, client, r.received, reply, outcome);
+    }
+}
+
+pub(crate) fn tap_reply<C: Copy>(
+    taps: &mut [Box<dyn Tap<C>>],
+    client: C,
+    received: SystemTime,
+    reply: &[u8],
+    outcome: Outcome,
+) {
+    if taps.is_empty() {
+        return;
+    }
+    let summary = summarize(client, received, outcome, reply);
+    for t in taps {
+        t.client_response(client, reply, outcome, received);
+        t.query_done(&summary);
+    }
+}
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Start reporting messages to `tap`, in addition to previously added ones
+    pub fn add_tap(&mut self, tap: Box<dyn Tap<N::ClientId>>) {
+        self.taps.push(tap);
+    }
+
+    /// Report a client query that is not going to be answered
+    pub(crate) fn tap_dropped(&mut self, client: N::ClientId, received: SystemTime, query: &[u8]) {
+        self.tap_unanswered(client, received, query, Outcome::Dropped)
+    }
+
+    /// Report a client query that is not going to be answered for the given reason
+    pub(crate) fn tap_unanswered(&mut self, client: N::ClientId, received: SystemTime, query: &[u8], outcome: Outcome) {
+        if self.taps.is_empty() {
+            return;
+        }
+        let mut summary = summarize(client, received, outcome, query);
+        summary.answers.clear();
+        summary.ttl = None;
+        for t in &mut self.taps {
+            t.query_done(&summary);
+        }
+    }
+
+    /// Send query to upstream, noting it for metrics and taps
+    pub(crate) fn send_upstream(&mut self, id: u16, buf: &[u8], upstream: UpstreamId) -> BoxResult<()> {
+        self.note_upstream_query(id);
+        for t in &mut self.taps {
+            t.forwarder_query(upstream, buf);
+        }
+        self.net.send_to_upstream_n(buf, upstream)
+    }
+}
@@ -0,0 +1,213 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+use super::*;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Record read from a zone file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneRecord {
+    /// Owner name, without trailing dot
+    pub name: String,
+    /// TTL, if specified explicitly or by `$TTL`
+    pub ttl: Option<Ttl>,
+    /// Record type and data
+    pub data: ZoneData,
+}
+
+/// Data of a zone file record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZoneData {
+    /// IPv4 address
+    A(Ipv4Addr),
+    /// IPv6 address
+    AAAA(Ipv6Addr),
+    /// Alias target, without trailing dot (`.` stays as is)
+    CNAME(String),
+    /// Anything else: type name and unparsed data
+    Other(String, String),
+}
+
+/// Parse TTL like `3600` or BIND-style `1h30m`, `30d`, `2w`
+pub fn parse_ttl(s: &str) -> Option<u64> {
+    if s.is_empty() {
+        return None;
+    }
+    if let Ok(x) = s.parse() {
+        return Some(x);
+    }
+    let mut total: u64 = 0;
+    let mut num: Option<u64> = None;
+    for c in s.chars() {
+        if let Some(d) = c.to_digit(10) {
+            num = Some(num.unwrap_or(0).checked_mul(10)?.checked_add(u64::from(d))?);
+            continue;
+        }
+        let mult = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 7 * 86400,
+            _ => return None,
+        };
+        total = total.checked_add(num.take()?.checked_mul(mult)?)?;
+    }
+    if num.is_some() {
+        return None;
+    }
+    Some(total)
+}
+
+/// Parse network like `192.0.2.0/24` or `2001:db8::/32`. Plain address means single host.
+pub fn parse_network(s: &str) -> Option<(IpAddr, u8)> {
+    let mut parts = s.splitn(2, '/');
+    let ip: IpAddr = parts.next()?.parse().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = match parts.next() {
+        Some(x) => x.parse().ok()?,
+        None => max,
+    };
+    if prefix > max {
+        return None;
+    }
+    Some((ip, prefix))
+}
+
+/// Parse `/etc/hosts`-style text into address and name pairs.
+/// Lines that do not start with an IP address are skipped.
+pub fn parse_hosts(text: &str) -> Vec<(IpAddr, String)> {
+    let mut v = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        let mut words = line.split_whitespace();
+        let ip = match words.next() {
+            None => continue,
+            Some(w) => match w.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => {
+                    warn!("hosts line {}: bad address {}", n + 1, w);
+                    continue;
+                }
+            },
+        };
+        for name in words {
+            v.push((ip, name.trim_end_matches('.').to_string()));
+        }
+    }
+    v
+}
+
+fn absolute_name(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_string()
+    } else if name == "." {
+        ".".to_string()
+    } else if let Some(x) = name.strip_suffix('.') {
+        x.to_string()
+    } else if origin.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, origin)
+    }
+}
+
+/// Parse RFC 1035 master file text. Relative names are completed with `origin`
+/// (or `$ORIGIN`). `$INCLUDE` is not supported.
+pub fn parse_zone(text: &str, origin: &str) -> BoxResult<Vec<ZoneRecord>> {
+    let mut records = Vec::new();
+    let mut origin = origin.trim_end_matches('.').to_string();
+    let mut default_ttl: Option<Ttl> = None;
+    let mut last_owner: Option<String> = None;
+
+    // Join parenthesized multi-line records into logical lines
+    let mut logical = Vec::new();
+    let mut pending = String::new();
+    let mut depth = 0;
+    let mut start_line = 0;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap();
+        if depth == 0 {
+            start_line = n + 1;
+        }
+        for c in line.chars() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                _ => pending.push(c),
+            }
+        }
+        if depth == 0 {
+            logical.push((start_line, ::std::mem::take(&mut pending)));
+        } else {
+            pending.push(' ');
+        }
+    }
+    if depth != 0 {
+        Err(format!("zone line {}: unbalanced parentheses", start_line))?;
+    }
+
+    for (n, line) in logical {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let starts_with_owner = !line.starts_with(' ') && !line.starts_with('\t');
+        let mut words: Vec<&str> = line.split_whitespace().collect();
+
+        match words[0] {
+            "$ORIGIN" if words.len() >= 2 => {
+                origin = absolute_name(words[1], &origin);
+                continue;
+            }
+            "$TTL" if words.len() >= 2 => {
+                default_ttl = Some(parse_ttl(words[1])
+                    .ok_or_else(|| format!("zone line {}: bad $TTL", n))? as Ttl);
+                continue;
+            }
+            x if x.starts_with('$') => Err(format!("zone line {}: unsupported {}", n, x))?,
+            _ => {}
+        }
+
+        let owner = if starts_with_owner {
+            let o = absolute_name(words.remove(0), &origin);
+            last_owner = Some(o.clone());
+            o
+        } else {
+            last_owner.clone().ok_or_else(|| format!("zone line {}: no owner name", n))?
+        };
+
+        let mut ttl = default_ttl;
+        let mut i = 0;
+        while i < words.len() {
+            let w = words[i];
+            if let Some(t) = parse_ttl(w) {
+                ttl = Some(t as Ttl);
+            } else if ["IN", "CH", "HS", "CS"].contains(&w.to_ascii_uppercase().as_str()) {
+            } else {
+                break;
+            }
+            i += 1;
+        }
+        if i + 1 >= words.len() {
+            Err(format!("zone line {}: no record data", n))?;
+        }
+        let typ = words[i].to_ascii_uppercase();
+        let rdata = &words[i + 1..];
+        let bad = |_| format!("zone line {}: bad {} data", n, typ);
+        let data = match typ.as_str() {
+            "A" => ZoneData::A(rdata[0].parse().map_err(bad)?),
+            "AAAA" => ZoneData::AAAA(rdata[0].parse().map_err(bad)?),
+            "CNAME" => ZoneData::CNAME(absolute_name(rdata[0], &origin)),
+            _ => ZoneData::Other(typ.clone(), rdata.join(" ")),
+        };
+        if default_ttl.is_none() {
+            default_ttl = ttl;
+        }
+        records.push(ZoneRecord {
+            name: owner,
+            ttl,
+            data,
+        });
+    }
+    Ok(records)
+}
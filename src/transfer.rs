@@ -0,0 +1,191 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! `export` and `import` subcommands
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use dnscache::{Database, CacheEntry, CacheEntry2, AddrTtl, BoxResult, BatchOp, Time, Ttl};
+use dnscache::{ip_octets, parse_hosts, parse_zone, ZoneData};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    JsonLines,
+    Hosts,
+    Zone,
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "jsonl" | "json" => Ok(Format::JsonLines),
+            "hosts" => Ok(Format::Hosts),
+            "zone" => Ok(Format::Zone),
+            _ => Err(format!("unknown format {}, expected jsonl, hosts or zone", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonAddr {
+    ttl: Ttl,
+    ip: IpAddr,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonSet {
+    t: Time,
+    a: Vec<JsonAddr>,
+}
+
+/// Like `CacheEntry`, but with readable addresses
+#[derive(Serialize, Deserialize)]
+struct JsonEntry {
+    name: String,
+    a4: Option<JsonSet>,
+    a6: Option<JsonSet>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    u: Option<Time>,
+}
+
+fn to_json_set(ce2: &CacheEntry2) -> JsonSet {
+    JsonSet {
+        t: ce2.t,
+        a: ce2.a.iter().filter_map(|x| x.ip_addr().map(|ip| JsonAddr { ttl: x.ttl, ip })).collect(),
+    }
+}
+
+/// Not marked secure: nothing in the file was validated
+fn from_json_set(js: JsonSet) -> CacheEntry2 {
+    CacheEntry2 {
+        t: js.t,
+        a: js.a.iter().map(|x| AddrTtl { ttl: x.ttl, ip: ip_octets(x.ip) }).collect(),
+        ..Default::default()
+    }
+}
+
+pub fn export(db: &mut dyn Database, format: Format, out: &mut dyn Write) -> BoxResult<()> {
+    let mut result = Ok(());
+    db.scan("", &mut |dom, ce| {
+        result = export_entry(dom, &ce, format, out);
+        result.is_ok()
+    })?;
+    result
+}
+
+fn export_entry(dom: &str, ce: &CacheEntry, format: Format, out: &mut dyn Write) -> BoxResult<()> {
+    match format {
+        Format::JsonLines => {
+            let je = JsonEntry {
+                name: dom.to_string(),
+                a4: ce.a4.as_ref().map(to_json_set),
+                a6: ce.a6.as_ref().map(to_json_set),
+                u: ce.u,
+            };
+            ::serde_json::to_writer(&mut *out, &je)?;
+            writeln!(out)?;
+        }
+        Format::Hosts => {
+            for ce2 in ce.a4.iter().chain(ce.a6.iter()) {
+                for ip in ce2.a.iter().filter_map(AddrTtl::ip_addr) {
+                    writeln!(out, "{}\t{}", ip, dom)?;
+                }
+            }
+        }
+        Format::Zone => {
+            for ce2 in ce.a4.iter().chain(ce.a6.iter()) {
+                for x in &ce2.a {
+                    match x.ip_addr() {
+                        Some(IpAddr::V4(ip)) => writeln!(out, "{}.\t{}\tIN\tA\t{}", dom, x.ttl, ip)?,
+                        Some(IpAddr::V6(ip)) => writeln!(out, "{}.\t{}\tIN\tAAAA\t{}", dom, x.ttl, ip)?,
+                        None => {}
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Imported data replaces A or AAAA information of mentioned domains; the other family is kept.
+/// Hosts and zone entries get current time as answer time and `default_ttl` if TTL is unknown.
+pub fn import(
+    db: &mut dyn Database,
+    format: Format,
+    input: &mut dyn BufRead,
+    default_ttl: Ttl,
+) -> BoxResult<usize> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut entries: BTreeMap<String, CacheEntry> = BTreeMap::new();
+
+    let add = |entries: &mut BTreeMap<String, CacheEntry>, name: String, ip: IpAddr, ttl: Ttl| {
+        let ce = entries.entry(name).or_default();
+        let ce2 = match ip {
+            IpAddr::V4(_) => &mut ce.a4,
+            IpAddr::V6(_) => &mut ce.a6,
+        };
+        ce2.get_or_insert_with(|| CacheEntry2 { t: now, ..Default::default() }).a.push(AddrTtl {
+            ttl,
+            ip: ip_octets(ip),
+        });
+    };
+
+    match format {
+        Format::JsonLines => {
+            for line in input.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let je: JsonEntry = ::serde_json::from_str(&line)?;
+                entries.insert(je.name, CacheEntry {
+                    a4: je.a4.map(from_json_set),
+                    a6: je.a6.map(from_json_set),
+                    u: je.u,
+                });
+            }
+        }
+        Format::Hosts => {
+            let mut text = String::new();
+            input.read_to_string(&mut text)?;
+            for (ip, name) in parse_hosts(&text) {
+                add(&mut entries, name, ip, default_ttl);
+            }
+        }
+        Format::Zone => {
+            let mut text = String::new();
+            input.read_to_string(&mut text)?;
+            for r in parse_zone(&text, "")? {
+                let ttl = r.ttl.unwrap_or(default_ttl);
+                match r.data {
+                    ZoneData::A(ip) => add(&mut entries, r.name, IpAddr::V4(ip), ttl),
+                    ZoneData::AAAA(ip) => add(&mut entries, r.name, IpAddr::V6(ip), ttl),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    let mut batch = Vec::with_capacity(entries.len());
+    for (dom, mut ce) in entries {
+        if let Ok(Some(old)) = db.get(&dom) {
+            if ce.a4.is_none() {
+                ce.a4 = old.a4;
+            }
+            if ce.a6.is_none() {
+                ce.a6 = old.a6;
+            }
+            if ce.u.is_none() {
+                ce.u = old.u;
+            }
+        }
+        batch.push(BatchOp::Put(dom, ce));
+    }
+    let n = batch.len();
+    db.write_batch(batch)?;
+    db.flush()?;
+    Ok(n)
+}
@@ -0,0 +1,182 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! DNSSEC validation of upstream replies: fetching DS and DNSKEY records,
+//! holding replies until their keys arrive, rejecting bogus ones
+
+use super::*;
+
+use details::{send_dns_reply, send_raw_reply, ReplyDnssec};
+use dnssec::{Lookup, Validator, Verdict};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::time::{Duration, UNIX_EPOCH};
+use tap::tap_client_response;
+use wire::*;
+
+/// Forget replies waiting for keys and key queries not answered for this long
+const KEY_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Unpredictable ID for own DS and DNSKEY queries: a spoofed answer to them would
+/// make the whole zone look insecure or bogus
+fn random_id() -> BoxResult<u16> {
+    let mut b = [0u8; 2];
+    SystemRandom::new().fill(&mut b).map_err(|_| "random number generator failed")?;
+    Ok(u16::from_be_bytes(b))
+}
+
+#[derive(Default)]
+pub(crate) struct DnssecState {
+    validator: Validator,
+    /// Own DS and DNSKEY queries by ID: lowercase name, type, upstream, when sent
+    fetches: HashMap<u16, (Name, u16, UpstreamId, Instant)>,
+    /// Upstream replies waiting for keys: reply, upstream, when received
+    waiting: Vec<(Vec<u8>, UpstreamId, Instant)>,
+}
+
+impl<DB: Database, N: Network> DnsCache<DB, N> {
+    /// Handle upstream reply when DNSSEC validation is on
+    pub(crate) fn validate_upstream_reply(&mut self, buf: &[u8], upstream: UpstreamId) -> BoxResult<()> {
+        let m = Message::parse(buf)?;
+        let received = Instant::now();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.dnssec.validator.prune(now);
+        self.dnssec.fetches.retain(|_, x| received.duration_since(x.3) < KEY_WAIT_TIMEOUT);
+        let waiting_before = self.dnssec.waiting.len();
+        self.dnssec.waiting.retain(|x| received.duration_since(x.2) < KEY_WAIT_TIMEOUT);
+        if self.dnssec.waiting.len() < waiting_before {
+            warn!("  DNSSEC: gave up waiting for keys of {} replies", waiting_before - self.dnssec.waiting.len());
+        }
+
+        if !self.is_key_reply(&m, upstream) {
+            return self.validate_reply(buf, m, upstream, received, now);
+        }
+
+        let (name, qtype, _, _) = self.dnssec.fetches.remove(&m.id).unwrap();
+        info!("  DNSSEC: got {} of {}.", if qtype == TYPE_DS { "DS" } else { "DNSKEY" }, name_to_string(&name));
+        self.note_upstream_reply(m.id);
+        self.dnssec.validator.add_fetched(&name, qtype, m, now);
+        let waiting = ::std::mem::take(&mut self.dnssec.waiting);
+        for (buf, upstream, since) in waiting {
+            let m = Message::parse(&buf)?;
+            if let Err(e) = self.validate_reply(&buf, m, upstream, since, now) {
+                error!("{}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether somebody waits for upstream reply `m`
+    fn reply_awaited(&self, m: &Message, upstream: UpstreamId) -> bool {
+        !m.questions.is_empty()
+            && m.questions.iter().all(|q| {
+                let dom = name_to_string(&q.name);
+                self.dom_update_subscriptions.get_vec(&dom).is_some_and(|v| {
+                    v.iter().any(|id| self.unreplied_requests.get(*id).is_some_and(|r| r.id == m.id && r.upstream == upstream))
+                })
+            })
+    }
+
+    fn validate_reply(&mut self, buf: &[u8], m: Message, upstream: UpstreamId, received: Instant, now: Time) -> BoxResult<()> {
+        let forwarded = m.questions.iter().any(|q| self.upstream_for(&name_to_string(&q.name)) != 0);
+        if forwarded {
+            // Forwarded zones are often internal ones unknown to the public DNS, they are not validated.
+            // Replies from the wrong upstream get rejected there as usual.
+            return self.process_unvalidated_reply(buf, upstream);
+        }
+        if m.questions.is_empty() || !self.reply_awaited(&m, upstream) {
+            for q in &m.questions {
+                self.check_dom(&name_to_string(&q.name), m.id, upstream);
+            }
+            return Ok(());
+        }
+
+        let verdict = {
+            let anchors = &self.opts.dnssec.as_ref().ok_or("DNSSEC is off")?.trust_anchors;
+            let q = &m.questions[0];
+            self.dnssec.validator.validate(&m, &q.name, q.qtype, anchors, now)
+        };
+        match verdict {
+            Lookup::Need(keys) => {
+                info!("  DNSSEC: waiting for {} more records", keys.len());
+                self.dnssec.waiting.push((buf.to_vec(), upstream, received));
+                for (name, qtype) in keys {
+                    if !self.dnssec.fetches.values().any(|x| x.0 == name && x.1 == qtype) {
+                        self.send_key_query(name, qtype)?;
+                    }
+                }
+                Ok(())
+            }
+            Lookup::Done(Verdict::Bogus(reason)) => {
+                warn!("  DNSSEC validation failed for {}: {}", name_to_string(&m.questions[0].name), reason);
+                Metrics::inc(&self.metrics.dnssec_bogus);
+                self.note_upstream_reply(m.id);
+                self.reply_bogus_waiting(buf, &m)
+            }
+            Lookup::Done(v) => {
+                let secure = v == Verdict::Secure;
+                info!("  DNSSEC: {}", if secure { "secure" } else { "insecure" });
+                Metrics::inc(if secure { &self.metrics.dnssec_secure } else { &self.metrics.dnssec_insecure });
+                let dnssec = ReplyDnssec {
+                    secure,
+                    dnssec_ok: m.dnssec_ok(),
+                    upstream_ad: false,
+                    raw: buf.to_vec(),
+                    sigs: vec![],
+                };
+                self.process_split_reply(m, upstream, dnssec)
+            }
+        }
+    }
+
+    /// Whether the upstream reply answers one of own DS or DNSKEY queries
+    pub(crate) fn is_key_reply(&self, m: &Message, upstream: UpstreamId) -> bool {
+        match self.dnssec.fetches.get(&m.id) {
+            Some(&(ref name, qtype, u, _)) => {
+                u == upstream && m.questions.len() == 1 && m.questions[0].qtype == qtype && lowercase(&m.questions[0].name) == *name
+            }
+            None => false,
+        }
+    }
+
+    /// Ask the default upstream for DS or DNSKEY records needed for validation
+    fn send_key_query(&mut self, name: Name, qtype: u16) -> BoxResult<()> {
+        let upstream = 0;
+        let mut id = random_id()?;
+        // Not the ID of another key query or of a direct forward, so the reply can't be taken for them
+        while self.dnssec.fetches.contains_key(&id) || self.r2a.contains_key(&id) {
+            id = random_id()?;
+        }
+        let m = Message {
+            id,
+            flags: 0x0100, // recursion desired
+            questions: vec![Question {
+                name: name.clone(),
+                qtype,
+                qclass: 1,
+            }],
+            ..Default::default()
+        };
+        let query = with_dnssec_ok(&m.to_vec(), FLAG_CD)?;
+        self.dnssec.fetches.insert(id, (name, qtype, upstream, Instant::now()));
+        self.send_upstream(id, &query, upstream)
+    }
+
+    /// Reply SERVFAIL to requests waiting for the domains of bogus upstream reply,
+    /// except for ones with Checking Disabled bit, which get the reply as is.
+    /// The reply itself is not saved.
+    fn reply_bogus_waiting(&mut self, buf: &[u8], m: &Message) -> BoxResult<()> {
+        for q in &m.questions {
+            for r in self.take_subscribed_requests(&name_to_string(&q.name)) {
+                if r.inhibit_send || r.clientid.is_none() {
+                    continue;
+                }
+                let reply = if r.checking_disabled {
+                    send_raw_reply(&self.net, &r, buf, false)?
+                } else {
+                    send_dns_reply(&self.net, &r, 2, &[], &[], &[], &Default::default())?
+                };
+                tap_client_response(&mut self.taps, &r, &reply, Outcome::Bogus);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! Noticing changes of configuration files by polling their modification times.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Don't look at the files more often than this
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub struct WatchedFiles {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    last_check: Instant,
+}
+
+impl WatchedFiles {
+    /// Remember current modification times, so only later changes are reported
+    pub fn new<'a, I: IntoIterator<Item = &'a PathBuf>>(paths: I) -> Self {
+        WatchedFiles {
+            files: paths.into_iter().map(|p| (p.clone(), mtime(p))).collect(),
+            last_check: Instant::now(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Whether any of the files was modified, created or removed since the previous call.
+    /// Cheap to call often: the files are looked at only every [`CHECK_INTERVAL`].
+    pub fn changed(&mut self) -> bool {
+        if self.files.is_empty() || self.last_check.elapsed() < CHECK_INTERVAL {
+            return false;
+        }
+        self.last_check = Instant::now();
+        let mut changed = false;
+        for &mut (ref path, ref mut last) in &mut self.files {
+            let t = mtime(path);
+            if t != *last {
+                *last = t;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
@@ -0,0 +1,368 @@
+// Implemented by Vitaly "_Vi" Shukela in 2017; Licence = MIT or Apache 2.0
+
+//! Minimal DNS message parser and writer keeping raw record data,
+//! for record types dns-parser does not know (RRSIG, DNSKEY, DS, NSEC, NSEC3)
+#![cfg_attr(not(feature = "dnssec"), allow(dead_code))]
+
+use super::*;
+
+use bytes::{BufMut, BigEndian as BE};
+use std::cmp::Ordering;
+
+pub(crate) const TYPE_CNAME: u16 = 5;
+pub(crate) const TYPE_SOA: u16 = 6;
+pub(crate) const TYPE_OPT: u16 = 41;
+pub(crate) const TYPE_DS: u16 = 43;
+pub(crate) const TYPE_RRSIG: u16 = 46;
+pub(crate) const TYPE_NSEC: u16 = 47;
+pub(crate) const TYPE_DNSKEY: u16 = 48;
+pub(crate) const TYPE_NSEC3: u16 = 50;
+pub(crate) const TYPE_NSEC3PARAM: u16 = 51;
+
+/// Header flag: authentic data
+pub(crate) const FLAG_AD: u16 = 0x0020;
+
+/// Header flag: checking disabled
+pub(crate) const FLAG_CD: u16 = 0x0010;
+
+/// DNSSEC OK bit in the TTL field of OPT record
+pub(crate) const EDNS_DO: u32 = 0x8000;
+
+/// Domain name in uncompressed wire format, as it was received (not lowercased)
+pub(crate) type Name = Vec<u8>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Question {
+    pub name: Name,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// Resource record with names inside RDATA decompressed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Record {
+    pub name: Name,
+    pub typ: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authority: Vec<Record>,
+    pub additional: Vec<Record>,
+}
+
+fn read_u16(buf: &[u8], off: usize) -> BoxResult<u16> {
+    if off + 2 > buf.len() {
+        Err("truncated message")?;
+    }
+    Ok((u16::from(buf[off]) << 8) | u16::from(buf[off + 1]))
+}
+
+fn read_u32(buf: &[u8], off: usize) -> BoxResult<u32> {
+    Ok((u32::from(read_u16(buf, off)?) << 16) | u32::from(read_u16(buf, off + 2)?))
+}
+
+/// Read possibly compressed name at `off`. Returns the name and offset after it.
+pub(crate) fn read_name(buf: &[u8], mut off: usize) -> BoxResult<(Name, usize)> {
+    let mut name = Vec::with_capacity(32);
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *buf.get(off).ok_or("truncated name")? as usize;
+        match len & 0xC0 {
+            0x00 => {
+                name.push(len as u8);
+                if len == 0 {
+                    break;
+                }
+                let label = buf.get(off + 1..off + 1 + len).ok_or("truncated label")?;
+                name.extend_from_slice(label);
+                if name.len() > 255 {
+                    Err("name too long")?;
+                }
+                off += 1 + len;
+            }
+            0xC0 => {
+                let ptr = (read_u16(buf, off)? & 0x3FFF) as usize;
+                if end.is_none() {
+                    end = Some(off + 2);
+                }
+                jumps += 1;
+                if jumps > 64 {
+                    Err("compression loop")?;
+                }
+                off = ptr;
+            }
+            _ => Err("unsupported label type")?,
+        }
+    }
+    Ok((name, end.unwrap_or(off + 1)))
+}
+
+/// Offsets of embedded names within RDATA of given type, as (fixed bytes before, number of names)
+/// chunks. Only types that may use compression or carry names signed in canonical form are listed.
+fn rdata_layout(typ: u16) -> &'static [(usize, usize)] {
+    match typ {
+        2 | 5 | 12 | 39 => &[(0, 1)],   // NS, CNAME, PTR, DNAME
+        15 => &[(2, 1)],                // MX
+        33 => &[(6, 1)],                // SRV
+        6 => &[(0, 2)],                 // SOA
+        TYPE_RRSIG => &[(18, 1)],
+        TYPE_NSEC => &[(0, 1)],
+        _ => &[],
+    }
+}
+
+/// Copy RDATA, decompressing embedded names
+fn read_rdata(buf: &[u8], off: usize, len: usize, typ: u16) -> BoxResult<Vec<u8>> {
+    let end = off + len;
+    if end > buf.len() {
+        Err("truncated rdata")?;
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut pos = off;
+    for &(fixed, names) in rdata_layout(typ) {
+        if pos + fixed > end {
+            Err("truncated rdata")?;
+        }
+        out.extend_from_slice(&buf[pos..pos + fixed]);
+        pos += fixed;
+        for _ in 0..names {
+            let (name, next) = read_name(buf, pos)?;
+            if next > end {
+                Err("name outside of rdata")?;
+            }
+            out.extend_from_slice(&name);
+            pos = next;
+        }
+    }
+    out.extend_from_slice(&buf[pos..end]);
+    Ok(out)
+}
+
+/// RDATA with embedded names lowercased, as required for signing (RFC 4034 6.2, RFC 6840 5.1)
+pub(crate) fn canonical_rdata(typ: u16, rdata: &[u8]) -> Vec<u8> {
+    let mut out = rdata.to_vec();
+    if typ == TYPE_NSEC {
+        return out;
+    }
+    let mut pos = 0;
+    for &(fixed, names) in rdata_layout(typ) {
+        pos += fixed;
+        for _ in 0..names {
+            match name_len(&out[pos.min(out.len())..]) {
+                Some(l) => {
+                    out[pos..pos + l].make_ascii_lowercase();
+                    pos += l;
+                }
+                None => return out,
+            }
+        }
+    }
+    out
+}
+
+/// Length of uncompressed wire name at the start of `b`
+pub(crate) fn name_len(b: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+    loop {
+        let l = *b.get(pos)? as usize;
+        if l & 0xC0 != 0 {
+            return None;
+        }
+        pos += 1 + l;
+        if l == 0 {
+            return if pos <= b.len() { Some(pos) } else { None };
+        }
+    }
+}
+
+fn read_record(buf: &[u8], off: usize) -> BoxResult<(Record, usize)> {
+    let (name, off) = read_name(buf, off)?;
+    let typ = read_u16(buf, off)?;
+    let class = read_u16(buf, off + 2)?;
+    let ttl = read_u32(buf, off + 4)?;
+    let len = read_u16(buf, off + 8)? as usize;
+    let rdata = read_rdata(buf, off + 10, len, typ)?;
+    Ok((Record { name, typ, class, ttl, rdata }, off + 10 + len))
+}
+
+impl Message {
+    pub fn parse(buf: &[u8]) -> BoxResult<Message> {
+        let mut m = Message {
+            id: read_u16(buf, 0)?,
+            flags: read_u16(buf, 2)?,
+            ..Default::default()
+        };
+        let qd = read_u16(buf, 4)?;
+        let counts = [read_u16(buf, 6)?, read_u16(buf, 8)?, read_u16(buf, 10)?];
+        let mut off = 12;
+        for _ in 0..qd {
+            let (name, next) = read_name(buf, off)?;
+            m.questions.push(Question {
+                name,
+                qtype: read_u16(buf, next)?,
+                qclass: read_u16(buf, next + 2)?,
+            });
+            off = next + 4;
+        }
+        for (i, &n) in counts.iter().enumerate() {
+            for _ in 0..n {
+                let (rr, next) = read_record(buf, off)?;
+                off = next;
+                match i {
+                    0 => m.answers.push(rr),
+                    1 => m.authority.push(rr),
+                    _ => m.additional.push(rr),
+                }
+            }
+        }
+        Ok(m)
+    }
+
+    /// Wire format without name compression
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(512);
+        b.put_u16::<BE>(self.id);
+        b.put_u16::<BE>(self.flags);
+        b.put_u16::<BE>(self.questions.len() as u16);
+        b.put_u16::<BE>(self.answers.len() as u16);
+        b.put_u16::<BE>(self.authority.len() as u16);
+        b.put_u16::<BE>(self.additional.len() as u16);
+        for q in &self.questions {
+            b.put(&q.name[..]);
+            b.put_u16::<BE>(q.qtype);
+            b.put_u16::<BE>(q.qclass);
+        }
+        for rr in self.answers.iter().chain(&self.authority).chain(&self.additional) {
+            b.put(&rr.name[..]);
+            b.put_u16::<BE>(rr.typ);
+            b.put_u16::<BE>(rr.class);
+            b.put_u32::<BE>(rr.ttl);
+            b.put_u16::<BE>(rr.rdata.len() as u16);
+            b.put(&rr.rdata[..]);
+        }
+        b
+    }
+
+    /// OPT record, if any
+    pub fn opt(&self) -> Option<&Record> {
+        self.additional.iter().find(|rr| rr.typ == TYPE_OPT)
+    }
+
+    /// Whether the DNSSEC OK bit is set
+    pub fn dnssec_ok(&self) -> bool {
+        self.opt().is_some_and(|rr| rr.ttl & EDNS_DO != 0)
+    }
+}
+
+/// Reply without DNSSEC records, which dns-parser does not understand, and RRSIGs of its answer section
+pub(crate) fn split_dnssec_records(mut m: Message) -> (Vec<u8>, Vec<Record>) {
+    let dnssec_type = |t: u16| [TYPE_RRSIG, TYPE_NSEC, TYPE_NSEC3, TYPE_DS, TYPE_DNSKEY, TYPE_NSEC3PARAM].contains(&t);
+    let sigs = m.answers.iter().filter(|rr| rr.typ == TYPE_RRSIG).cloned().collect();
+    m.answers.retain(|rr| !dnssec_type(rr.typ));
+    m.authority.retain(|rr| !dnssec_type(rr.typ));
+    m.additional.retain(|rr| !dnssec_type(rr.typ));
+    (m.to_vec(), sigs)
+}
+
+/// Type covered by RRSIG record data
+pub(crate) fn rrsig_type_covered(rdata: &[u8]) -> Option<u16> {
+    match rdata {
+        &[a, b, ..] => Some((u16::from(a) << 8) | u16::from(b)),
+        _ => None,
+    }
+}
+
+/// Copy of the query with EDNS DO bit set (adding OPT record if needed) and given header flags
+/// (like CD) added
+pub(crate) fn with_dnssec_ok(query: &[u8], extra_flags: u16) -> BoxResult<Vec<u8>> {
+    let mut m = Message::parse(query)?;
+    m.flags |= extra_flags;
+    match m.additional.iter_mut().find(|rr| rr.typ == TYPE_OPT) {
+        Some(opt) => opt.ttl |= EDNS_DO,
+        None => m.additional.push(Record {
+            name: vec![0],
+            typ: TYPE_OPT,
+            class: 1232, // UDP payload size
+            ttl: EDNS_DO,
+            rdata: vec![],
+        }),
+    }
+    Ok(m.to_vec())
+}
+
+/// Wire format of dotted name like `www.example.com`. Empty string or `.` is the root.
+pub(crate) fn name_from_str(dom: &str) -> Name {
+    let mut b = Vec::with_capacity(dom.len() + 2);
+    for l in dom.trim_end_matches('.').split('.').filter(|l| !l.is_empty()) {
+        b.push(l.len() as u8);
+        b.extend_from_slice(l.as_bytes());
+    }
+    b.push(0);
+    b
+}
+
+/// Dotted form without trailing dot, like dns-parser gives. Root is empty string.
+pub(crate) fn name_to_string(name: &[u8]) -> String {
+    labels(name).map(|l| String::from_utf8_lossy(l).into_owned()).collect::<Vec<_>>().join(".")
+}
+
+/// Labels from the leftmost one, without the root
+pub(crate) fn labels(name: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut pos = 0;
+    ::std::iter::from_fn(move || {
+        let l = *name.get(pos)? as usize;
+        if l == 0 || pos + 1 + l > name.len() {
+            return None;
+        }
+        let label = &name[pos + 1..pos + 1 + l];
+        pos += 1 + l;
+        Some(label)
+    })
+}
+
+pub(crate) fn label_count(name: &[u8]) -> usize {
+    labels(name).count()
+}
+
+/// Name without its leftmost label. None for the root.
+pub(crate) fn parent(name: &[u8]) -> Option<&[u8]> {
+    match name.first() {
+        Some(&l) if l > 0 => name.get(1 + l as usize..),
+        _ => None,
+    }
+}
+
+pub(crate) fn lowercase(name: &[u8]) -> Name {
+    name.to_ascii_lowercase()
+}
+
+/// The name itself, then its ancestors up to the root
+pub(crate) fn ancestors(name: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut cur = Some(name);
+    ::std::iter::from_fn(move || {
+        let x = cur?;
+        cur = parent(x);
+        Some(x)
+    })
+}
+
+/// Whether `name` is `zone` or under it. Case-insensitive.
+pub(crate) fn is_under(name: &[u8], zone: &[u8]) -> bool {
+    ancestors(name).any(|a| a.eq_ignore_ascii_case(zone))
+}
+
+/// Canonical DNS name order (RFC 4034 6.1)
+pub(crate) fn canonical_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let la: Vec<Vec<u8>> = labels(a).map(|l| l.to_ascii_lowercase()).collect();
+    let lb: Vec<Vec<u8>> = labels(b).map(|l| l.to_ascii_lowercase()).collect();
+    la.iter().rev().cmp(lb.iter().rev())
+}